@@ -0,0 +1,430 @@
+use std::{collections::HashMap, error, fs, path::PathBuf};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use gtfs_heatmap_lib::{
+    coords::{BoundingBox, Coordinates},
+    geotiff::Crs,
+    gtfs_graph::{
+        dijkstras::StopWithDuration,
+        matrix::{parse_places_csv, MatrixOptions},
+        parser::Feed,
+        GtfsGraph,
+    },
+    mbtiles::MbTiles,
+    timezone::Departure,
+    Gtfs,
+};
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, Duration};
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Parser)]
+#[command(
+    name = "gtfs-heatmap",
+    about = "Offline tools for GTFS travel time analysis"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    ///Builds the graph from a feed and prints its size and the issues found in the feed
+    Build(BuildArgs),
+    ///Searches travel times to every reachable stop
+    Search(SearchArgs),
+    ///Renders the heatmap of an area as a single PNG, or a GeoTIFF of travel times in seconds
+    Render(RenderArgs),
+    ///Computes travel times between every origin and destination
+    Matrix(MatrixArgs),
+    ///Renders heatmap tiles of an area into an MBTiles archive
+    Prerender(PrerenderArgs),
+}
+
+#[derive(Args)]
+struct GraphArgs {
+    ///GTFS zip file or a directory with the unpacked feed.
+    ///Repeat to merge feeds, ids are then prefixed with the file name like "hsl:1020453".
+    #[arg(long, default_value = "../data/")]
+    gtfs: Vec<PathBuf>,
+    ///Stops of different feeds closer than this in meters get walking transfers
+    #[arg(long, default_value_t = 200.0)]
+    transfer_distance: f64,
+}
+
+///Where a search starts, either a stop or coordinates to walk to the nearest stop from
+#[derive(Args)]
+struct OriginArgs {
+    #[arg(
+        long,
+        required_unless_present = "latitude",
+        conflicts_with = "latitude"
+    )]
+    stop: Option<String>,
+    #[arg(long, requires = "longitude", allow_negative_numbers = true)]
+    latitude: Option<f64>,
+    #[arg(long, requires = "latitude", allow_negative_numbers = true)]
+    longitude: Option<f64>,
+    ///Departure time in RFC 3339, e.g. 2024-10-18T08:00:00+03:00,
+    ///or local time in the time zone of the feeds, e.g. 2024-10-18T08:00
+    #[arg(long)]
+    departure: Departure,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Csv,
+    Json,
+}
+
+#[derive(Args)]
+struct SearchArgs {
+    #[command(flatten)]
+    graph: GraphArgs,
+    #[command(flatten)]
+    origin: OriginArgs,
+    #[arg(long, value_enum, default_value_t = Format::Csv)]
+    format: Format,
+    ///File to write, prints to stdout when missing
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct RenderArgs {
+    #[command(flatten)]
+    graph: GraphArgs,
+    #[command(flatten)]
+    origin: OriginArgs,
+    ///Area to render as min_lon,min_lat,max_lon,max_lat
+    #[arg(long, value_parser = parse_bounding_box)]
+    bbox: BoundingBox,
+    ///Pixels are the same size as in map tiles of this zoom level
+    #[arg(long, default_value_t = 13)]
+    zoom: u32,
+    ///EPSG code of GeoTIFF output, 3857 or 4326
+    #[arg(long, value_parser = parse_crs, default_value = "3857")]
+    crs: Crs,
+    ///PNG file to create, or a GeoTIFF when the extension is .tif or .tiff
+    #[arg(long, short)]
+    output: PathBuf,
+}
+
+#[derive(Args)]
+struct BuildArgs {
+    #[command(flatten)]
+    graph: GraphArgs,
+    ///Json file to write every issue found in the feed to
+    #[arg(long)]
+    report: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct MatrixArgs {
+    #[command(flatten)]
+    graph: GraphArgs,
+    ///Csv with an id column and either stop_id or lat and lon columns
+    #[arg(long)]
+    origins: PathBuf,
+    ///Same format as origins, defaults to the origins
+    #[arg(long)]
+    destinations: Option<PathBuf>,
+    ///Departure time in RFC 3339, e.g. 2024-10-18T08:00:00+03:00,
+    ///or local time in the time zone of the feeds, e.g. 2024-10-18T08:00
+    #[arg(long)]
+    departure: Departure,
+    ///Repeat the search for this many minutes after departure and report the median
+    #[arg(long, default_value_t = 0)]
+    window_minutes: i64,
+    #[arg(long, default_value_t = 5)]
+    step_minutes: i64,
+    ///Defaults to the number of available cores
+    #[arg(long)]
+    threads: Option<usize>,
+    ///Csv file to write, prints to stdout when missing
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct PrerenderArgs {
+    #[command(flatten)]
+    graph: GraphArgs,
+    #[command(flatten)]
+    origin: OriginArgs,
+    ///Area to render as min_lon,min_lat,max_lon,max_lat
+    #[arg(long, value_parser = parse_bounding_box)]
+    bbox: BoundingBox,
+    #[arg(long, default_value_t = 9)]
+    min_zoom: u32,
+    #[arg(long, default_value_t = 14)]
+    max_zoom: u32,
+    ///MBTiles file to create
+    #[arg(long, short)]
+    output: PathBuf,
+}
+
+fn parse_bounding_box(value: &str) -> Result<BoundingBox, String> {
+    let numbers = value
+        .split(',')
+        .map(|number| number.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|err| err.to_string())?;
+
+    let [min_longitude, min_latitude, max_longitude, max_latitude] = numbers[..] else {
+        return Err("expected min_lon,min_lat,max_lon,max_lat".to_string());
+    };
+
+    if min_longitude > max_longitude || min_latitude > max_latitude {
+        return Err("min corner must be south west of max corner".to_string());
+    }
+
+    Ok(BoundingBox {
+        min: Coordinates {
+            latitude: min_latitude,
+            longitude: min_longitude,
+        },
+        max: Coordinates {
+            latitude: max_latitude,
+            longitude: max_longitude,
+        },
+    })
+}
+
+fn parse_crs(value: &str) -> Result<Crs, String> {
+    value
+        .trim_start_matches("EPSG:")
+        .parse()
+        .ok()
+        .and_then(Crs::from_epsg)
+        .ok_or("expected 3857 or 4326".to_string())
+}
+
+fn load_graph(args: &GraphArgs) -> Result<GtfsGraph, Box<dyn error::Error>> {
+    let feeds = args
+        .gtfs
+        .iter()
+        .map(|path| {
+            eprintln!("Loading {}", path.display());
+
+            Ok(Feed {
+                namespace: (args.gtfs.len() > 1)
+                    .then(|| path.file_stem())
+                    .flatten()
+                    .map(|stem| stem.to_string_lossy().into_owned()),
+                gtfs: Gtfs::from_path(path)?,
+            })
+        })
+        .collect::<Result<Vec<Feed>, Box<dyn error::Error>>>()?;
+
+    Ok(GtfsGraph::from_feeds(feeds, args.transfer_distance)?)
+}
+
+fn search_from(
+    graph: &GtfsGraph,
+    origin: &OriginArgs,
+) -> Result<HashMap<String, StopWithDuration>, Box<dyn error::Error>> {
+    let departure = origin.departure.resolve(graph.time_zone());
+
+    Ok(match (&origin.stop, origin.latitude, origin.longitude) {
+        (Some(stop), _, _) => graph.dijkstras(stop, departure)?,
+        (None, Some(latitude), Some(longitude)) => graph.dijkstras_from_coordinates(
+            &Coordinates {
+                latitude,
+                longitude,
+            },
+            departure,
+        )?,
+        _ => return Err("either --stop or --latitude and --longitude is required".into()),
+    })
+}
+
+fn origin_name(origin: &OriginArgs) -> String {
+    match (&origin.stop, origin.latitude, origin.longitude) {
+        (Some(stop), _, _) => stop.clone(),
+        (_, latitude, longitude) => format!(
+            "{}, {}",
+            latitude.unwrap_or_default(),
+            longitude.unwrap_or_default()
+        ),
+    }
+}
+
+fn write_output(output: &Option<PathBuf>, contents: &str) -> Result<(), Box<dyn error::Error>> {
+    match output {
+        Some(path) => fs::write(path, contents)?,
+        None => print!("{}", contents),
+    }
+
+    Ok(())
+}
+
+fn build(args: BuildArgs) -> Result<(), Box<dyn error::Error>> {
+    let graph = load_graph(&args.graph)?;
+    let report = graph.validation_report();
+
+    println!("{} stops, {} edges", graph.stop_count(), graph.edge_count());
+
+    for (kind, count) in report.summary() {
+        println!("{}: {}", kind, count);
+    }
+
+    if let Some(path) = &args.report {
+        fs::write(path, serde_json::to_string_pretty(report)?)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct TravelTime<'a> {
+    stop_id: &'a str,
+    latitude: f64,
+    longitude: f64,
+    travel_time_seconds: i64,
+}
+
+fn search(args: SearchArgs) -> Result<(), Box<dyn error::Error>> {
+    let graph = load_graph(&args.graph)?;
+    let stop_times = search_from(&graph, &args.origin)?;
+
+    let mut travel_times: Vec<TravelTime> = stop_times
+        .values()
+        .map(|stop_time| TravelTime {
+            stop_id: &stop_time.stop().id,
+            latitude: stop_time.stop().coordinates.latitude,
+            longitude: stop_time.stop().coordinates.longitude,
+            travel_time_seconds: stop_time.duration().whole_seconds(),
+        })
+        .collect();
+    travel_times.sort_by(|a, b| {
+        a.travel_time_seconds
+            .cmp(&b.travel_time_seconds)
+            .then(a.stop_id.cmp(b.stop_id))
+    });
+
+    let contents = match args.format {
+        Format::Json => serde_json::to_string_pretty(&travel_times)? + "\n",
+        Format::Csv => travel_times.iter().fold(
+            String::from("stop_id,latitude,longitude,travel_time_seconds\n"),
+            |csv, time| {
+                csv + &format!(
+                    "{},{},{},{}\n",
+                    time.stop_id, time.latitude, time.longitude, time.travel_time_seconds
+                )
+            },
+        ),
+    };
+
+    write_output(&args.output, &contents)
+}
+
+fn render(args: RenderArgs) -> Result<(), Box<dyn error::Error>> {
+    let graph = load_graph(&args.graph)?;
+    let stop_times = search_from(&graph, &args.origin)?;
+
+    let is_geotiff = args
+        .output
+        .extension()
+        .is_some_and(|extension| extension == "tif" || extension == "tiff");
+
+    if is_geotiff {
+        let raster = graph.travel_time_raster(&args.bbox, args.crs, args.zoom, &stop_times);
+        fs::write(&args.output, raster.to_geotiff()?)?;
+
+        eprintln!(
+            "Wrote {}x{} raster to {}",
+            raster.width,
+            raster.height,
+            args.output.display()
+        );
+        return Ok(());
+    }
+
+    let image = graph
+        .generate_heatmap_area(&args.bbox, args.zoom, &stop_times)
+        .ok_or("the search reached no stops")?;
+    image.save(&args.output)?;
+
+    eprintln!(
+        "Wrote {}x{} image to {}",
+        image.width(),
+        image.height(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+fn matrix(args: MatrixArgs) -> Result<(), Box<dyn error::Error>> {
+    let origins = parse_places_csv(&fs::read_to_string(&args.origins)?)?;
+    let destinations = match &args.destinations {
+        Some(path) => parse_places_csv(&fs::read_to_string(path)?)?,
+        None => origins.clone(),
+    };
+
+    let graph = load_graph(&args.graph)?;
+
+    let options = MatrixOptions {
+        start_time: args.departure.resolve(graph.time_zone()),
+        window: Duration::minutes(args.window_minutes),
+        step: Duration::minutes(args.step_minutes),
+        threads: args.threads.unwrap_or(0),
+    };
+
+    let matrix = graph.travel_time_matrix(&origins, &destinations, &options)?;
+
+    write_output(&args.output, &matrix.to_csv())
+}
+
+fn prerender(args: PrerenderArgs) -> Result<(), Box<dyn error::Error>> {
+    if args.min_zoom > args.max_zoom {
+        return Err("min_zoom must be at most max_zoom".into());
+    }
+
+    let graph = load_graph(&args.graph)?;
+    let stop_times = search_from(&graph, &args.origin)?;
+
+    let archive = MbTiles::create(
+        &args.output,
+        &format!("Travel times from {}", origin_name(&args.origin)),
+        &format!(
+            "Departure {}",
+            args.origin
+                .departure
+                .resolve(graph.time_zone())
+                .format(&Rfc3339)?
+        ),
+        &args.bbox,
+        args.min_zoom,
+        args.max_zoom,
+    )?;
+
+    let count = archive.in_transaction(|archive| {
+        graph.render_tile_pyramid(
+            &args.bbox,
+            args.min_zoom..=args.max_zoom,
+            &stop_times,
+            |tile, data| archive.insert_tile(tile, data),
+        )
+    })?;
+
+    eprintln!("Wrote {} tiles to {}", count, args.output.display());
+    Ok(())
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn error::Error>> {
+    match cli.command {
+        Command::Build(args) => build(args),
+        Command::Search(args) => search(args),
+        Command::Render(args) => render(args),
+        Command::Matrix(args) => matrix(args),
+        Command::Prerender(args) => prerender(args),
+    }
+}
+
+fn main() -> Result<(), Box<dyn error::Error>> {
+    run(Cli::parse())
+}
@@ -0,0 +1,128 @@
+use std::path::Path;
+
+use super::*;
+
+///Feed with stops A and B and trip t1 from A at 08:00 to B at 08:10 on weekdays of 2024
+fn write_test_feed(dir: &Path) {
+    fs::create_dir_all(dir).unwrap();
+
+    for (file, contents) in [
+        (
+            "agency.txt",
+            "agency_id,agency_name,agency_url,agency_timezone\nhsl,HSL,https://hsl.fi,Europe/Helsinki\n",
+        ),
+        (
+            "stops.txt",
+            "stop_id,stop_name,stop_lat,stop_lon\nA,Alpha,60.0,25.0\nB,Beta,60.1,25.0\n",
+        ),
+        (
+            "routes.txt",
+            "route_id,agency_id,route_short_name,route_type\nr1,hsl,1,3\n",
+        ),
+        ("trips.txt", "route_id,service_id,trip_id\nr1,weekdays,t1\n"),
+        (
+            "stop_times.txt",
+            "trip_id,arrival_time,departure_time,stop_id,stop_sequence\nt1,08:00:00,08:00:00,A,1\nt1,08:10:00,08:10:00,B,2\n",
+        ),
+        (
+            "calendar.txt",
+            "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\nweekdays,1,1,1,1,1,0,0,20240101,20241231\n",
+        ),
+    ] {
+        fs::write(dir.join(file), contents).unwrap();
+    }
+}
+
+fn run_args(args: &[&str]) -> Result<(), Box<dyn error::Error>> {
+    run(Cli::try_parse_from(
+        ["gtfs-heatmap"].iter().chain(args.iter()),
+    )?)
+}
+
+#[test]
+fn build_search_and_render_a_feed() {
+    let dir = std::env::temp_dir().join(format!("gtfs-heatmap-cli-test-{}", std::process::id()));
+    let feed = dir.join("feed");
+    write_test_feed(&feed);
+    let feed = feed.to_str().unwrap();
+    let output = |file: &str| dir.join(file).to_str().unwrap().to_string();
+
+    run_args(&["build", "--gtfs", feed, "--report", &output("report.json")]).unwrap();
+    let report: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(output("report.json")).unwrap()).unwrap();
+    assert!(report.is_object());
+
+    //07:55 local time on a Friday, the trip reaches B 15 minutes later
+    let origin = [
+        "--gtfs",
+        feed,
+        "--stop",
+        "A",
+        "--departure",
+        "2024-10-18T07:55",
+    ];
+    run_args(&[&["search"], &origin[..], &["-o", &output("times.csv")]].concat()).unwrap();
+    assert_eq!(
+        fs::read_to_string(output("times.csv")).unwrap(),
+        "stop_id,latitude,longitude,travel_time_seconds\nA,60,25,0\nB,60.1,25,900\n"
+    );
+
+    run_args(
+        &[
+            &["render"],
+            &origin[..],
+            &["--bbox", "24.99,59.99,25.01,60.11", "--zoom", "10"],
+            &["-o", &output("heatmap.png")],
+        ]
+        .concat(),
+    )
+    .unwrap();
+    assert!(fs::read(output("heatmap.png"))
+        .unwrap()
+        .starts_with(b"\x89PNG"));
+
+    assert!(run_args(&[
+        "search",
+        "--gtfs",
+        feed,
+        "--stop",
+        "C",
+        "--departure",
+        "2024-10-18T07:55"
+    ])
+    .is_err());
+    assert!(run_args(
+        &[
+            &["render"],
+            &origin[..],
+            &["--bbox", "25.01,59.99,24.99,60.11", "-o", &output("x.png")]
+        ]
+        .concat()
+    )
+    .is_err());
+
+    let prerender = |min_zoom, max_zoom| {
+        run_args(
+            &[
+                &["prerender"],
+                &origin[..],
+                &["--bbox", "24.99,59.99,25.01,60.11"],
+                &["--min-zoom", min_zoom, "--max-zoom", max_zoom],
+                &["-o", &output("tiles.mbtiles")],
+            ]
+            .concat(),
+        )
+    };
+    assert!(prerender("10", "9").is_err());
+    assert!(!dir.join("tiles.mbtiles").exists());
+    prerender("9", "10").unwrap();
+    assert_eq!(
+        MbTiles::open(output("tiles.mbtiles"))
+            .unwrap()
+            .get_metadata("maxzoom")
+            .unwrap(),
+        Some("10".to_string())
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
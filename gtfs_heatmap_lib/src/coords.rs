@@ -5,7 +5,7 @@ use serde::Serialize;
 ///Earth radius in meters
 const EARTH_RADIUS: f64 = 6_378_000.0;
 
-#[derive(Debug, Default, Serialize)]
+#[derive(Debug, Default, Clone, Copy, Serialize)]
 pub struct Coordinates {
     pub latitude: f64,
     pub longitude: f64,
@@ -18,10 +18,21 @@ impl Coordinates {
         TileNumbers {
             zoom,
             x: (n * ((self.longitude + 180.0) / 360.0)) as u32,
-            y: (n * (1.0 - (f64::asin(f64::tan(self.latitude.to_radians())) / PI) / 2.0)) as u32,
+            y: (n * (1.0 - f64::asinh(f64::tan(self.latitude.to_radians())) / PI) / 2.0) as u32,
         }
     }
 
+    ///Position in pixels from the north west corner of the world on the zoom level,
+    ///with tiles being 256 pixels wide
+    pub fn as_global_pixel(&self, zoom: u32) -> (u32, u32) {
+        let size: f64 = 2_u32.pow(zoom) as f64 * 256.0;
+
+        (
+            (size * ((self.longitude + 180.0) / 360.0)) as u32,
+            (size * (1.0 - f64::asinh(f64::tan(self.latitude.to_radians())) / PI) / 2.0) as u32,
+        )
+    }
+
     ///Calculates distance between two points on earth using the pythagoran theorem
     pub fn distance(&self, other: &Coordinates) -> f64 {
         let d_lat: f64 = (other.latitude * 2.0 - self.latitude * 2.0).to_radians();
@@ -47,6 +58,45 @@ impl Coordinates {
     }
 }
 
+///Area between two corners, min being the south west corner and max the north east corner
+#[derive(Debug, Clone, Copy)]
+pub struct BoundingBox {
+    pub min: Coordinates,
+    pub max: Coordinates,
+}
+
+impl BoundingBox {
+    pub fn contains(&self, coordinates: &Coordinates) -> bool {
+        (self.min.latitude..=self.max.latitude).contains(&coordinates.latitude)
+            && (self.min.longitude..=self.max.longitude).contains(&coordinates.longitude)
+    }
+
+    ///All tiles on the zoom level which overlap the box, row by row from the north west corner
+    pub fn tiles(&self, zoom: u32) -> impl Iterator<Item = TileNumbers> {
+        let north_west = Coordinates {
+            latitude: self.max.latitude,
+            longitude: self.min.longitude,
+        }
+        .as_tile(zoom);
+        let south_east = Coordinates {
+            latitude: self.min.latitude,
+            longitude: self.max.longitude,
+        }
+        .as_tile(zoom);
+
+        (north_west.y..=south_east.y).flat_map(move |y| {
+            (north_west.x..=south_east.x).map(move |x| TileNumbers { zoom, x, y })
+        })
+    }
+
+    pub fn center(&self) -> Coordinates {
+        Coordinates {
+            latitude: (self.min.latitude + self.max.latitude) / 2.0,
+            longitude: (self.min.longitude + self.max.longitude) / 2.0,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TileNumbers {
     pub zoom: u32,
@@ -74,4 +124,27 @@ impl TileNumbers {
             .to_degrees(),
         }
     }
+
+    ///Coordinates of a point given as fractions of the tile from its north west corner.
+    ///Fractions outside 0..1 are points in neighbouring tiles.
+    pub fn get_relative_coordinates(&self, x: f64, y: f64) -> Coordinates {
+        let n: f64 = 2_u32.pow(self.zoom) as f64;
+
+        Coordinates {
+            longitude: (self.x as f64 + x) / n * 360.0 - 180.0,
+            latitude: f64::atan(f64::sinh(PI * (1.0 - 2.0 * ((self.y as f64 + y) / n))))
+                .to_degrees(),
+        }
+    }
+
+    ///Inverse of get_relative_coordinates
+    pub fn relative_position(&self, coordinates: &Coordinates) -> (f64, f64) {
+        let n: f64 = 2_u32.pow(self.zoom) as f64;
+
+        (
+            n * ((coordinates.longitude + 180.0) / 360.0) - self.x as f64,
+            n * (1.0 - f64::asinh(f64::tan(coordinates.latitude.to_radians())) / PI) / 2.0
+                - self.y as f64,
+        )
+    }
 }
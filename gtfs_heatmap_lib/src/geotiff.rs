@@ -0,0 +1,167 @@
+use std::io::Cursor;
+
+use serde::Deserialize;
+use tiff::{
+    encoder::{colortype::Gray32Float, TiffEncoder},
+    tags::Tag,
+};
+
+use crate::{coords::Coordinates, Error};
+
+///Half of the circumference of the earth in EPSG:3857 meters
+const WEB_MERCATOR_EXTENT: f64 = 20_037_508.342_789_244;
+
+//GeoTIFF 1.0 key ids and values
+const GT_MODEL_TYPE: u16 = 1024;
+const GT_RASTER_TYPE: u16 = 1025;
+const GEOGRAPHIC_TYPE: u16 = 2048;
+const PROJECTED_CS_TYPE: u16 = 3072;
+const MODEL_TYPE_PROJECTED: u16 = 1;
+const MODEL_TYPE_GEOGRAPHIC: u16 = 2;
+const RASTER_PIXEL_IS_AREA: u16 = 1;
+
+///Coordinate reference systems a raster can be georeferenced in
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Crs {
+    ///EPSG:3857, pixels line up with map tiles
+    WebMercator,
+    ///EPSG:4326, pixels are a regular grid of degrees
+    Wgs84,
+}
+
+impl Crs {
+    pub fn epsg(&self) -> u16 {
+        match self {
+            Crs::WebMercator => 3857,
+            Crs::Wgs84 => 4326,
+        }
+    }
+
+    pub fn from_epsg(code: u16) -> Option<Self> {
+        match code {
+            3857 => Some(Crs::WebMercator),
+            4326 => Some(Crs::Wgs84),
+            _ => None,
+        }
+    }
+
+    ///Projects coordinates to x and y in the units of the crs
+    pub fn project(&self, coordinates: &Coordinates) -> (f64, f64) {
+        match self {
+            Crs::WebMercator => (
+                coordinates.longitude / 180.0 * WEB_MERCATOR_EXTENT,
+                f64::asinh(f64::tan(coordinates.latitude.to_radians())) / std::f64::consts::PI
+                    * WEB_MERCATOR_EXTENT,
+            ),
+            Crs::Wgs84 => (coordinates.longitude, coordinates.latitude),
+        }
+    }
+
+    pub fn unproject(&self, x: f64, y: f64) -> Coordinates {
+        match self {
+            Crs::WebMercator => Coordinates {
+                latitude: f64::atan(f64::sinh(y / WEB_MERCATOR_EXTENT * std::f64::consts::PI))
+                    .to_degrees(),
+                longitude: x / WEB_MERCATOR_EXTENT * 180.0,
+            },
+            Crs::Wgs84 => Coordinates {
+                latitude: y,
+                longitude: x,
+            },
+        }
+    }
+
+    ///Size of a pixel in crs units when tiles of the zoom level are 256 pixels wide
+    pub fn pixel_size(&self, zoom: u32) -> f64 {
+        let pixels = 2_u32.pow(zoom) as f64 * 256.0;
+
+        match self {
+            Crs::WebMercator => 2.0 * WEB_MERCATOR_EXTENT / pixels,
+            Crs::Wgs84 => 360.0 / pixels,
+        }
+    }
+}
+
+///Single band grid of values in row major order, starting from the north west corner.
+///Missing values are NaN.
+pub struct Raster {
+    pub crs: Crs,
+    pub width: u32,
+    pub height: u32,
+    ///North west corner of the raster in crs units
+    pub origin: (f64, f64),
+    pub pixel_size: f64,
+    pub values: Vec<f32>,
+}
+
+impl Raster {
+    ///Coordinates of the center of a pixel
+    pub fn pixel_center(&self, column: u32, row: u32) -> Coordinates {
+        self.crs.unproject(
+            self.origin.0 + (column as f64 + 0.5) * self.pixel_size,
+            self.origin.1 - (row as f64 + 0.5) * self.pixel_size,
+        )
+    }
+
+    ///Encodes the raster as a float32 GeoTIFF readable by GDAL and QGIS
+    pub fn to_geotiff(&self) -> Result<Vec<u8>, Error> {
+        let mut buffer = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut buffer)?;
+        let mut image = encoder.new_image::<Gray32Float>(self.width, self.height)?;
+
+        let geo_keys: [u16; 16] = match self.crs {
+            Crs::WebMercator => [
+                1,
+                1,
+                0,
+                3,
+                GT_MODEL_TYPE,
+                0,
+                1,
+                MODEL_TYPE_PROJECTED,
+                GT_RASTER_TYPE,
+                0,
+                1,
+                RASTER_PIXEL_IS_AREA,
+                PROJECTED_CS_TYPE,
+                0,
+                1,
+                self.crs.epsg(),
+            ],
+            Crs::Wgs84 => [
+                1,
+                1,
+                0,
+                3,
+                GT_MODEL_TYPE,
+                0,
+                1,
+                MODEL_TYPE_GEOGRAPHIC,
+                GT_RASTER_TYPE,
+                0,
+                1,
+                RASTER_PIXEL_IS_AREA,
+                GEOGRAPHIC_TYPE,
+                0,
+                1,
+                self.crs.epsg(),
+            ],
+        };
+
+        let directory = image.encoder();
+        directory.write_tag(
+            Tag::ModelPixelScaleTag,
+            &[self.pixel_size, self.pixel_size, 0.0][..],
+        )?;
+        directory.write_tag(
+            Tag::ModelTiepointTag,
+            &[0.0, 0.0, 0.0, self.origin.0, self.origin.1, 0.0][..],
+        )?;
+        directory.write_tag(Tag::GeoKeyDirectoryTag, &geo_keys[..])?;
+        directory.write_tag(Tag::GdalNodata, "nan")?;
+
+        image.write_data(&self.values)?;
+
+        Ok(buffer.into_inner())
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+
+use image::GrayImage;
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::Value;
+use time::{Duration, OffsetDateTime};
+
+use crate::coords::{BoundingBox, Coordinates};
+
+use super::{
+    csv::{field, Csv},
+    heatmap::{walking_time, MAX_WALKING_TIME},
+    matrix::transit_time,
+    Error, GtfsGraph,
+};
+
+///Meters in one degree of latitude
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+///A point with something worth reaching, like jobs, services or residents
+#[derive(Debug, Clone)]
+pub struct Opportunity {
+    pub coordinates: Coordinates,
+    pub weight: f64,
+}
+
+///How much an opportunity counts depending on how long it takes to reach it.
+///Opportunities further than the cutoff never count.
+#[derive(Debug, Clone, Copy)]
+pub enum DecayFunction {
+    ///Every opportunity within the cutoff counts fully, giving cumulative opportunities
+    Step,
+    ///Falls linearly from 1 at the origin to 0 at the cutoff, with a zero cutoff only the origin counts
+    Linear,
+    ///Halves every half_life
+    Exponential { half_life: Duration },
+    ///Gaussian curve with standard deviation sigma
+    Gaussian { sigma: Duration },
+}
+
+impl DecayFunction {
+    pub fn factor(&self, time: Duration, cutoff: Duration) -> f64 {
+        if time > cutoff {
+            return 0.0;
+        }
+
+        let time = time.as_seconds_f64();
+
+        match self {
+            DecayFunction::Step => 1.0,
+            DecayFunction::Linear if cutoff.is_zero() => 1.0,
+            DecayFunction::Linear => 1.0 - time / cutoff.as_seconds_f64(),
+            DecayFunction::Exponential { half_life } => {
+                0.5_f64.powf(time / half_life.as_seconds_f64())
+            }
+            DecayFunction::Gaussian { sigma } => {
+                (-(time * time) / (2.0 * sigma.as_seconds_f64().powi(2))).exp()
+            }
+        }
+    }
+}
+
+pub struct AccessibilityOptions {
+    pub bounding_box: BoundingBox,
+    ///Width and height of a cell in meters
+    pub cell_size: f64,
+    pub start_time: OffsetDateTime,
+    pub cutoff: Duration,
+    pub decay: DecayFunction,
+    ///Grids with more cells are rejected, as every cell is computed at once
+    pub max_cells: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AccessibilityCell {
+    pub row: u32,
+    pub column: u32,
+    ///Center of the cell
+    #[serde(flatten)]
+    pub coordinates: Coordinates,
+    pub value: f64,
+}
+
+///Cells are stored row by row, starting from the north west corner
+#[derive(Serialize, Debug)]
+pub struct AccessibilityGrid {
+    pub columns: u32,
+    pub rows: u32,
+    pub cells: Vec<AccessibilityCell>,
+}
+
+impl AccessibilityGrid {
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("row,column,latitude,longitude,value\n");
+
+        for cell in self.cells.iter() {
+            csv.push_str(&format!(
+                "{},{},{},{},{}\n",
+                cell.row,
+                cell.column,
+                cell.coordinates.latitude,
+                cell.coordinates.longitude,
+                cell.value
+            ));
+        }
+
+        csv
+    }
+
+    ///One pixel per cell, brightness relative to the best cell
+    pub fn to_image(&self) -> GrayImage {
+        let max_value = self
+            .cells
+            .iter()
+            .fold(0.0_f64, |acc, cell| acc.max(cell.value));
+
+        let mut buf = GrayImage::new(self.columns, self.rows);
+
+        for cell in self.cells.iter() {
+            let brightness = match max_value > 0.0 {
+                true => (cell.value / max_value * u8::MAX as f64) as u8,
+                false => 0,
+            };
+
+            buf.put_pixel(cell.column, cell.row, [brightness].into());
+        }
+
+        buf
+    }
+}
+
+///Parses opportunities from csv with a header row.
+///Coordinates are read from lat/latitude and lon/lng/longitude columns
+///and the weight from weight_column, missing weight column counts every row as 1.
+pub fn parse_opportunities_csv(data: &str, weight_column: &str) -> Result<Vec<Opportunity>, Error> {
+    let csv = Csv::parse(data).ok_or(Error::InvalidOpportunities("csv is empty".to_string()))?;
+
+    let latitude_column = csv
+        .column(&["lat", "latitude"])
+        .ok_or(Error::InvalidOpportunities(
+            "csv has no latitude column".to_string(),
+        ))?;
+    let longitude_column =
+        csv.column(&["lon", "lng", "longitude"])
+            .ok_or(Error::InvalidOpportunities(
+                "csv has no longitude column".to_string(),
+            ))?;
+    let weight_column = csv.column(&[weight_column]);
+
+    csv.rows
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let parse = |column: usize| {
+                field(row, Some(column))
+                    .and_then(|field| field.parse::<f64>().ok())
+                    .ok_or(Error::InvalidOpportunities(format!(
+                        "invalid number on row {}",
+                        i + 1
+                    )))
+            };
+
+            Ok(Opportunity {
+                coordinates: Coordinates {
+                    latitude: parse(latitude_column)?,
+                    longitude: parse(longitude_column)?,
+                },
+                weight: match weight_column {
+                    Some(column) => parse(column)?,
+                    None => 1.0,
+                },
+            })
+        })
+        .collect()
+}
+
+///Parses opportunities from the Point features of a GeoJSON FeatureCollection.
+///The weight is read from the weight_property of each feature and defaults to 1.
+pub fn parse_opportunities_geojson(
+    data: &str,
+    weight_property: &str,
+) -> Result<Vec<Opportunity>, Error> {
+    let json: Value =
+        serde_json::from_str(data).map_err(|err| Error::InvalidOpportunities(err.to_string()))?;
+
+    let features = json["features"]
+        .as_array()
+        .ok_or(Error::InvalidOpportunities(
+            "GeoJSON is not a FeatureCollection".to_string(),
+        ))?;
+
+    Ok(features
+        .iter()
+        .filter(|feature| feature["geometry"]["type"] == "Point")
+        .filter_map(|feature| {
+            let coordinates = feature["geometry"]["coordinates"].as_array()?;
+
+            Some(Opportunity {
+                coordinates: Coordinates {
+                    longitude: coordinates.first()?.as_f64()?,
+                    latitude: coordinates.get(1)?.as_f64()?,
+                },
+                weight: feature["properties"][weight_property]
+                    .as_f64()
+                    .unwrap_or(1.0),
+            })
+        })
+        .collect())
+}
+
+///Parses GeoJSON if the data looks like a json object and csv otherwise
+pub fn parse_opportunities(data: &str, weight_field: &str) -> Result<Vec<Opportunity>, Error> {
+    match data.trim_start().starts_with('{') {
+        true => parse_opportunities_geojson(data, weight_field),
+        false => parse_opportunities_csv(data, weight_field),
+    }
+}
+
+impl GtfsGraph {
+    ///Sums the decayed weights of opportunities reachable from every cell of the grid.
+    ///
+    ///Each cell starts from its nearest stop within walking distance,
+    ///cells sharing a nearest stop share one search.
+    ///Opportunities are reached by walking from the stops near them, or directly from the cell.
+    pub fn accessibility(
+        &self,
+        opportunities: &[Opportunity],
+        options: &AccessibilityOptions,
+    ) -> Result<AccessibilityGrid, Error> {
+        let bbox = &options.bounding_box;
+        let latitude_step = options.cell_size / METERS_PER_DEGREE;
+        let longitude_step =
+            options.cell_size / (METERS_PER_DEGREE * bbox.center().latitude.to_radians().cos());
+
+        let rows = ((bbox.max.latitude - bbox.min.latitude) / latitude_step).ceil() as u32;
+        let columns = ((bbox.max.longitude - bbox.min.longitude) / longitude_step).ceil() as u32;
+        let cell_count = (rows as usize)
+            .checked_mul(columns as usize)
+            .filter(|cell_count| *cell_count <= options.max_cells)
+            .ok_or(Error::InvalidGrid(format!(
+                "{} by {} cells is more than the maximum of {} cells",
+                rows, columns, options.max_cells
+            )))?;
+
+        let egress: Vec<Vec<(String, Duration)>> = opportunities
+            .iter()
+            .map(|opportunity| self.stops_within_walking(&opportunity.coordinates))
+            .collect();
+
+        let mut cells = Vec::with_capacity(cell_count);
+        //Cells by the id of their nearest stop, with the time it takes to walk to it
+        let mut origins: HashMap<String, Vec<(usize, Duration)>> = HashMap::new();
+
+        for row in 0..rows {
+            for column in 0..columns {
+                let coordinates = Coordinates {
+                    latitude: bbox.max.latitude - (row as f64 + 0.5) * latitude_step,
+                    longitude: bbox.min.longitude + (column as f64 + 0.5) * longitude_step,
+                };
+
+                if let Some((stop, distance)) = self.nearest_stop(&coordinates) {
+                    let access_time = walking_time(distance);
+
+                    if access_time.whole_seconds() <= MAX_WALKING_TIME {
+                        origins
+                            .entry(stop.id.clone())
+                            .or_default()
+                            .push((cells.len(), access_time));
+                    }
+                }
+
+                cells.push(AccessibilityCell {
+                    row,
+                    column,
+                    value: 0.0,
+                    coordinates,
+                });
+            }
+        }
+
+        let values = origins
+            .par_iter()
+            .map(|(stop_id, origin_cells)| {
+                let stop_times = self.dijkstras(stop_id, options.start_time)?;
+
+                Ok(origin_cells
+                    .iter()
+                    .map(|(cell_index, access_time)| {
+                        let value = cell_value(
+                            &cells[*cell_index].coordinates,
+                            opportunities,
+                            options,
+                            |i| {
+                                transit_time(&stop_times, &egress[i])
+                                    .map(|time| time + *access_time)
+                            },
+                        );
+
+                        (*cell_index, value)
+                    })
+                    .collect::<Vec<_>>())
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut has_origin = vec![false; cells.len()];
+
+        for (cell_index, value) in values.into_iter().flatten() {
+            cells[cell_index].value = value;
+            has_origin[cell_index] = true;
+        }
+
+        //Cells too far from any stop can only walk
+        cells
+            .par_iter_mut()
+            .zip(has_origin)
+            .filter(|(_, has_origin)| !has_origin)
+            .for_each(|(cell, _)| {
+                cell.value = cell_value(&cell.coordinates, opportunities, options, |_| None);
+            });
+
+        Ok(AccessibilityGrid {
+            columns,
+            rows,
+            cells,
+        })
+    }
+
+    pub(super) fn stops_within_walking(
+        &self,
+        coordinates: &Coordinates,
+    ) -> Vec<(String, Duration)> {
+        self.stops
+            .iter()
+            .filter_map(|stop| {
+                let time = walking_time(stop.coordinates.haversine_distance(coordinates));
+
+                (time.whole_seconds() <= MAX_WALKING_TIME).then(|| (stop.id.clone(), time))
+            })
+            .collect()
+    }
+}
+
+///transit_time gives the time to an opportunity by its index using transit, if it is reachable
+fn cell_value(
+    coordinates: &Coordinates,
+    opportunities: &[Opportunity],
+    options: &AccessibilityOptions,
+    transit_time: impl Fn(usize) -> Option<Duration>,
+) -> f64 {
+    opportunities
+        .iter()
+        .enumerate()
+        .map(|(i, opportunity)| {
+            let walk = walking_time(coordinates.haversine_distance(&opportunity.coordinates));
+            let time = transit_time(i).map_or(walk, |time| time.min(walk));
+
+            opportunity.weight * options.decay.factor(time, options.cutoff)
+        })
+        .sum()
+}
@@ -0,0 +1,64 @@
+///Csv with a header row, as used for opportunity and place inputs.
+///Fields may be quoted with double quotes, a doubled quote being a literal one.
+///Quoted fields can not span lines.
+pub(super) struct Csv {
+    header: Vec<String>,
+    pub(super) rows: Vec<Vec<String>>,
+}
+
+impl Csv {
+    ///None if the data has no header row, blank lines are skipped
+    pub(super) fn parse(data: &str) -> Option<Csv> {
+        let mut lines = data
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(split_fields);
+
+        Some(Csv {
+            header: lines.next()?,
+            rows: lines.collect(),
+        })
+    }
+
+    ///Index of the first column named any of names, which are matched case insensitively
+    pub(super) fn column(&self, names: &[&str]) -> Option<usize> {
+        self.header
+            .iter()
+            .position(|column| names.iter().any(|name| column.eq_ignore_ascii_case(name)))
+    }
+}
+
+///Field of a row, None if the column is missing or the field is empty
+pub(super) fn field(row: &[String], column: Option<usize>) -> Option<&str> {
+    column
+        .and_then(|column| row.get(column))
+        .map(|field| field.as_str())
+        .filter(|field| !field.is_empty())
+}
+
+///Splits a line into trimmed fields, removing the quotes around quoted fields
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            (',', false) => fields.push(std::mem::take(&mut field).trim().to_string()),
+            (c, _) => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+
+    fields
+}
@@ -0,0 +1,199 @@
+use std::{
+    collections::{HashMap, HashSet},
+    iter,
+    sync::Arc,
+};
+
+use chrono::{Datelike, NaiveDate};
+use gtfs_structures::{Exception, Gtfs};
+use serde::Serialize;
+use time::{Date, Duration, Month, OffsetDateTime};
+
+use super::{Edge, Error, GtfsGraph, SECONDS_IN_DAY};
+
+///How far ahead departures are looked for
+const DEPARTURE_HORIZON: Duration = Duration::days(7);
+
+///Dates a service runs on, from calendar.txt and calendar_dates.txt
+#[derive(Debug, Clone, Default)]
+pub(super) struct Service {
+    ///Weekdays and the first and last date from calendar.txt, first index being monday.
+    ///None when the service only has dates added in calendar_dates.txt.
+    pub(super) calendar: Option<([bool; 7], Date, Date)>,
+    pub(super) added: HashSet<Date>,
+    pub(super) removed: HashSet<Date>,
+}
+
+impl Service {
+    pub(super) fn from_gtfs(gtfs: &Gtfs, service_id: &str) -> Self {
+        let mut service = Service {
+            calendar: gtfs.calendar.get(service_id).and_then(|calendar| {
+                Some((
+                    [
+                        calendar.monday,
+                        calendar.tuesday,
+                        calendar.wednesday,
+                        calendar.thursday,
+                        calendar.friday,
+                        calendar.saturday,
+                        calendar.sunday,
+                    ],
+                    to_date(calendar.start_date)?,
+                    to_date(calendar.end_date)?,
+                ))
+            }),
+            ..Default::default()
+        };
+
+        for calendar_date in gtfs.calendar_dates.get(service_id).into_iter().flatten() {
+            let Some(date) = to_date(calendar_date.date) else {
+                continue;
+            };
+
+            match calendar_date.exception_type {
+                Exception::Added => service.added.insert(date),
+                Exception::Deleted => service.removed.insert(date),
+            };
+        }
+
+        service
+    }
+
+    pub(super) fn runs_on(&self, date: Date) -> bool {
+        if self.removed.contains(&date) {
+            return false;
+        }
+
+        self.added.contains(&date)
+            || self.calendar.is_some_and(|(weekdays, start, end)| {
+                (start..=end).contains(&date)
+                    && weekdays[date.weekday().number_days_from_monday() as usize]
+            })
+    }
+}
+
+fn to_date(date: NaiveDate) -> Option<Date> {
+    Date::from_calendar_date(
+        date.year(),
+        Month::try_from(date.month() as u8).ok()?,
+        date.day() as u8,
+    )
+    .ok()
+}
+
+///Departure of a trip from a stop
+#[derive(Serialize, Debug, Clone)]
+pub struct StopDeparture {
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    pub route_id: String,
+    pub route_short_name: Option<String>,
+    pub trip_id: String,
+    pub headsign: Option<String>,
+    ///stop_id of the last stop of the trip
+    pub destination: String,
+    pub stop_sequence: u32,
+}
+
+impl GtfsGraph {
+    ///Next departures from a stop at or after from, within a week.
+    ///Unlike searches, which only look at weekdays, departures are only listed
+    ///on dates their service runs according to calendar.txt and calendar_dates.txt.
+    pub fn departures(
+        &self,
+        stop_id: &str,
+        from: OffsetDateTime,
+        count: usize,
+    ) -> Result<Vec<StopDeparture>, Error> {
+        let stop = self
+            .get_stop(stop_id)
+            .ok_or(Error::MissingStop(stop_id.to_string()))?;
+        let until = from + DEPARTURE_HORIZON;
+
+        let mut departures: Vec<(OffsetDateTime, &Arc<Edge>)> = stop
+            .edges
+            .iter()
+            .flat_map(|edge| {
+                self.edge_departures(edge, from, until)
+                    .map(move |time| (time, edge))
+            })
+            .collect();
+
+        departures
+            .sort_by(|(a, a_edge), (b, b_edge)| (a, &a_edge.trip_id).cmp(&(b, &b_edge.trip_id)));
+        departures.truncate(count);
+
+        let mut destinations: HashMap<&Arc<str>, String> = HashMap::new();
+
+        Ok(departures
+            .into_iter()
+            .map(|(time, edge)| {
+                let trip = self.trips.get(&edge.trip_id);
+
+                StopDeparture {
+                    time,
+                    route_id: edge.route_id.to_string(),
+                    route_short_name: self
+                        .routes
+                        .get(&edge.route_id)
+                        .and_then(|route| route.short_name.clone()),
+                    trip_id: edge.trip_id.to_string(),
+                    headsign: trip.and_then(|trip| trip.headsign.clone()),
+                    destination: destinations
+                        .entry(&edge.trip_id)
+                        .or_insert_with(|| self.trip_destination(edge))
+                        .clone(),
+                    stop_sequence: edge.stop_sequence,
+                }
+            })
+            .collect())
+    }
+
+    ///Times the edge departs from start until end, on the service dates its trip runs on
+    pub(super) fn edge_departures<'a>(
+        &'a self,
+        edge: &'a Edge,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+    ) -> impl Iterator<Item = OffsetDateTime> + 'a {
+        let days_spanned = Duration::days((edge.departure_time / SECONDS_IN_DAY) as i64);
+        let first = self.time_zone.to_local(start).date() - days_spanned;
+        let last = self.time_zone.to_local(end).date();
+
+        iter::successors(Some(first), |date| date.next_day())
+            .take_while(move |date| *date <= last)
+            .filter(move |date| self.runs_on(edge, *date))
+            .map(move |date| Edge::to_datetime(&edge.departure_time, date, &self.time_zone))
+            .filter(move |time| (start..end).contains(time))
+    }
+
+    ///Whether the trip of the edge runs on the service date.
+    ///Edges of trips without a known service fall back to their weekdays.
+    fn runs_on(&self, edge: &Edge, service_date: Date) -> bool {
+        match self
+            .trips
+            .get(&edge.trip_id)
+            .and_then(|trip| self.services.get(&trip.service_id))
+        {
+            Some(service) => service.runs_on(service_date),
+            None => edge.weekdays.is_valid(service_date.weekday()),
+        }
+    }
+
+    ///stop_id of the last stop of the trip, following its edges from the edge
+    fn trip_destination(&self, edge: &Edge) -> String {
+        let mut current = edge;
+
+        while let Some(next) = self.stops[current.connected_stop]
+            .edges
+            .iter()
+            .find(|next| {
+                next.trip_id == current.trip_id && next.stop_sequence > current.stop_sequence
+            })
+        {
+            current = next;
+        }
+
+        self.stops[current.connected_stop].id.clone()
+    }
+}
@@ -2,20 +2,48 @@ use std::{
     any::Any,
     cmp::Ordering,
     collections::{BinaryHeap, HashMap},
-    sync::{Arc, RwLock},
+    sync::Arc,
 };
 
 use serde::Serialize;
 use time::{Duration, OffsetDateTime};
 
-use super::{Error, GtfsGraph, Stop};
+use crate::coords::Coordinates;
+
+use super::{
+    heatmap::{walking_time, MAX_WALKING_TIME},
+    scenario::ScenarioOverlay,
+    Edge, Error, GtfsGraph, Stop,
+};
 
 #[derive(Clone, Serialize)]
 #[serde(transparent)]
 pub struct StopWithDuration {
     #[serde(skip)]
-    pub(crate) stop: Arc<RwLock<Stop>>,
+    pub(crate) stop: Arc<Stop>,
     pub(crate) duration: Duration,
+    ///How the stop was reached, None for the stop the search started from
+    #[serde(skip)]
+    pub(super) arrival: Option<Arrival>,
+}
+
+///Last step taken to reach a stop, followed backwards to build itineraries
+#[derive(Clone)]
+pub(super) enum Arrival {
+    Ride {
+        ///Index of the stop the edge departs from
+        from: usize,
+        edge: Arc<Edge>,
+        departure: OffsetDateTime,
+    },
+    Walk {
+        ///Index of the stop the transfer starts from
+        from: usize,
+        duration: Duration,
+    },
+    ///Duration combined from several journeys, like the lowest generalised cost of
+    ///a pareto search, so there is no single way the stop was reached
+    Combined,
 }
 
 //Ord implemented as reverse, so we get a min heap from rust BinaryHeap
@@ -43,11 +71,70 @@ impl PartialEq for StopWithDuration {
 
 impl Eq for StopWithDuration {}
 
+impl StopWithDuration {
+    pub fn stop(&self) -> &Stop {
+        &self.stop
+    }
+
+    ///Time from the start of the search until the stop is reached
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+}
+
 impl GtfsGraph {
     pub fn dijkstras(
         &self,
         start_id: &str,
         start_time: OffsetDateTime,
+    ) -> Result<HashMap<String, StopWithDuration>, Error> {
+        self.dijkstras_with_scenario(start_id, start_time, None)
+    }
+
+    ///Searches from the stop nearest to coordinates, walking there first.
+    ///The walk is included in the durations.
+    pub fn dijkstras_from_coordinates(
+        &self,
+        coordinates: &Coordinates,
+        start_time: OffsetDateTime,
+    ) -> Result<HashMap<String, StopWithDuration>, Error> {
+        let (stop, access_time) = self
+            .nearest_stop(coordinates)
+            .map(|(stop, distance)| (stop, walking_time(distance)))
+            .filter(|(_, access_time)| access_time.whole_seconds() <= MAX_WALKING_TIME)
+            .ok_or(Error::NoStopNearby(
+                coordinates.latitude,
+                coordinates.longitude,
+            ))?;
+
+        let mut times = self.dijkstras(&stop.id, start_time + access_time)?;
+
+        for stop_with_duration in times.values_mut() {
+            stop_with_duration.duration += access_time;
+        }
+
+        Ok(times)
+    }
+
+    ///Same as dijkstras, but edges removed by the scenario are skipped
+    ///and edges added by it are followed as if they were part of the graph.
+    pub fn dijkstras_with_scenario(
+        &self,
+        start_id: &str,
+        start_time: OffsetDateTime,
+        scenario: Option<&ScenarioOverlay>,
+    ) -> Result<HashMap<String, StopWithDuration>, Error> {
+        self.dijkstras_filtered(start_id, start_time, scenario, |_, _| true)
+    }
+
+    ///Same as dijkstras_with_scenario, but only edges for which allowed returns true
+    ///when departing at the given time are followed
+    pub(super) fn dijkstras_filtered(
+        &self,
+        start_id: &str,
+        start_time: OffsetDateTime,
+        scenario: Option<&ScenarioOverlay>,
+        allowed: impl Fn(&Edge, OffsetDateTime) -> bool,
     ) -> Result<HashMap<String, StopWithDuration>, Error> {
         let mut queue: BinaryHeap<StopWithDuration> = BinaryHeap::new();
         let mut times: HashMap<String, StopWithDuration> = HashMap::new();
@@ -59,48 +146,97 @@ impl GtfsGraph {
                 .get_stop(start_id)
                 .ok_or(Error::MissingStop(start_id.to_string()))?,
             duration: Duration::ZERO,
+            arrival: None,
         });
 
         while let Some(stop_with_duration) = queue.pop() {
-            let stop = stop_with_duration.stop.read()?;
+            let stop = &stop_with_duration.stop;
 
             if times.contains_key(&stop.id) {
                 continue;
             }
 
+            let added_edges = scenario
+                .map(|scenario| scenario.added_edges(&stop.id))
+                .unwrap_or_default();
+
             let unvisited_edges = stop
                 .edges
                 .iter()
-                .filter(|edge| !times.contains_key(&edge.connected_stop.read().unwrap().id));
+                .chain(added_edges)
+                .filter(|edge| !scenario.is_some_and(|scenario| scenario.is_removed(edge)))
+                .filter(|edge| !times.contains_key(&self.stops[edge.connected_stop].id));
+
+            let from = self
+                .stop_index(&stop.id)
+                .expect("Stops in the queue are in the graph");
+            let now = start_time + stop_with_duration.duration;
 
-            let mut times_until_stops: HashMap<String, OffsetDateTime> = HashMap::new();
+            let mut times_until_stops: HashMap<usize, (OffsetDateTime, Arrival)> = HashMap::new();
 
             for edge in unvisited_edges {
-                let id = &edge.connected_stop.read()?.id;
+                let id = &edge.connected_stop;
 
-                let mut time = edge.departure_datetime((start_time + stop_with_duration.duration));
+                let Some(departure) = edge.departure_datetime_on(now, &self.time_zone, |date| {
+                    scenario.is_none_or(|scenario| scenario.runs_on(edge, date))
+                }) else {
+                    continue;
+                };
 
-                if time < start_time + stop_with_duration.duration {
+                if !allowed(edge, departure) {
                     continue;
                 }
 
-                if times_until_stops.contains_key(id) {
-                    let val = times_until_stops
-                        .get_mut(id)
-                        .expect("We just checked if id exsist in map");
+                let time = edge.arrival_datetime(departure);
+
+                if times_until_stops
+                    .get(id)
+                    .is_none_or(|(current, _)| time < *current)
+                {
+                    times_until_stops.insert(
+                        *id,
+                        (
+                            time,
+                            Arrival::Ride {
+                                from,
+                                edge: edge.clone(),
+                                departure,
+                            },
+                        ),
+                    );
+                }
+            }
 
-                    if &mut time < val {
-                        times_until_stops.insert(id.clone(), time);
-                    }
-                } else {
-                    times_until_stops.insert(id.clone(), time);
+            let unvisited_transfers = stop
+                .transfers
+                .iter()
+                .filter(|transfer| !times.contains_key(&self.stops[transfer.stop].id));
+
+            for transfer in unvisited_transfers {
+                let time = now + transfer.duration;
+
+                if times_until_stops
+                    .get(&transfer.stop)
+                    .is_none_or(|(current, _)| time < *current)
+                {
+                    times_until_stops.insert(
+                        transfer.stop,
+                        (
+                            time,
+                            Arrival::Walk {
+                                from,
+                                duration: transfer.duration,
+                            },
+                        ),
+                    );
                 }
             }
 
-            for (id, mut time_h) in times_until_stops.drain() {
+            for (id, (time, arrival)) in times_until_stops.drain() {
                 queue.push(StopWithDuration {
-                    stop: self.get_stop(&id).expect("Stop id should be valid"),
-                    duration: time_h - (start_time + stop_with_duration.duration),
+                    stop: self.stops[id].clone(),
+                    duration: time - start_time,
+                    arrival: Some(arrival),
                 });
             }
 
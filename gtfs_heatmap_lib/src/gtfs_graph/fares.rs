@@ -0,0 +1,407 @@
+use std::collections::{HashMap, HashSet};
+
+use gtfs_structures::{Gtfs, Transfers};
+use image::GrayImage;
+use rayon::prelude::*;
+use serde::Serialize;
+use time::{Duration, OffsetDateTime};
+
+use crate::coords::TileNumbers;
+
+use super::{
+    dijkstras::{Arrival, StopWithDuration},
+    heatmap::{reachable_time, TILE_RESOLUTION},
+    validation::Issue,
+    Edge, Error, GtfsGraph,
+};
+
+///Fare product from fare_attributes.txt
+#[derive(Serialize, Debug, Clone)]
+pub struct FareAttribute {
+    pub id: String,
+    pub price: f64,
+    pub currency: String,
+    ///Transfers allowed on the fare, None when unlimited
+    pub transfers: Option<u32>,
+    ///How long after the first boarding the fare can be used, None when it doesn't expire
+    #[serde(skip)]
+    pub transfer_duration: Option<Duration>,
+}
+
+impl FareAttribute {
+    fn allows(&self, rides: &[Ride]) -> bool {
+        let transfers = rides.len() as u32 - 1;
+
+        self.transfers.is_none_or(|allowed| transfers <= allowed)
+            && self.transfer_duration.is_none_or(|validity| {
+                rides[rides.len() - 1].departure - rides[0].departure <= validity
+            })
+    }
+}
+
+///fare_rules.txt rows of a fare. Rides match the fare when they match
+///every kind of rule the fare has, like in GTFS Fares v1.
+#[derive(Debug, Clone, Default)]
+struct FareRules {
+    ///Fares only apply to routes of the feed they are from
+    namespace: Option<String>,
+    routes: HashSet<String>,
+    ///Zone of the first and last stop, None matches any zone
+    origin_destinations: Vec<(Option<String>, Option<String>)>,
+    ///Zones which have to be passed, no more and no less
+    contains: HashSet<String>,
+}
+
+impl FareRules {
+    fn matches(&self, rides: &[Ride]) -> bool {
+        let origin = rides[0].zones.first().cloned().flatten();
+        let destination = rides[rides.len() - 1].zones.last().cloned().flatten();
+
+        rides.iter().all(|ride| self.allows_route(&ride.route_id))
+            && (self.origin_destinations.is_empty()
+                || self.origin_destinations.iter().any(|(from, to)| {
+                    from.as_ref()
+                        .is_none_or(|from| Some(from) == origin.as_ref())
+                        && to
+                            .as_ref()
+                            .is_none_or(|to| Some(to) == destination.as_ref())
+                }))
+            && (self.contains.is_empty()
+                || rides
+                    .iter()
+                    .flat_map(|ride| ride.zones.iter())
+                    .map(|zone| zone.clone().unwrap_or_default())
+                    .collect::<HashSet<String>>()
+                    == self.contains)
+    }
+
+    fn allows_route(&self, route_id: &str) -> bool {
+        self.namespace
+            .as_ref()
+            .is_none_or(|namespace| route_id.starts_with(&format!("{}:", namespace)))
+            && (self.routes.is_empty() || self.routes.contains(route_id))
+    }
+
+    ///Zones the fare can be used in, None if it isn't limited to zones
+    fn zones(&self) -> Option<HashSet<&str>> {
+        let zones: HashSet<&str> = self
+            .origin_destinations
+            .iter()
+            .flat_map(|(from, to)| [from, to])
+            .flatten()
+            .chain(self.contains.iter())
+            .map(String::as_str)
+            .collect();
+
+        (!zones.is_empty()).then_some(zones)
+    }
+}
+
+///Fares of all feeds in the graph
+#[derive(Debug, Clone, Default)]
+pub(super) struct Fares {
+    fares: Vec<(FareAttribute, FareRules)>,
+}
+
+impl Fares {
+    ///Adds the fares of a feed, returning issues with fares which were skipped
+    pub(super) fn add_feed(&mut self, gtfs: &Gtfs, namespace: Option<&str>) -> Vec<Issue> {
+        let id = |id: &str| match namespace {
+            Some(namespace) => format!("{}:{}", namespace, id),
+            None => id.to_string(),
+        };
+
+        let mut issues = Vec::new();
+
+        //Sorted so fares are in the same order on every load
+        let mut attributes: Vec<_> = gtfs.fare_attributes.iter().collect();
+        attributes.sort_by_key(|(fare_id, _)| *fare_id);
+
+        for (fare_id, attribute) in attributes {
+            let Ok(price) = attribute.price.parse::<f64>() else {
+                issues.push(Issue::InvalidFare {
+                    fare_id: id(fare_id),
+                });
+                continue;
+            };
+
+            let mut rules = FareRules {
+                namespace: namespace.map(str::to_string),
+                ..Default::default()
+            };
+
+            for rule in gtfs.fare_rules.get(fare_id).into_iter().flatten() {
+                if let Some(route_id) = &rule.route_id {
+                    rules.routes.insert(id(route_id));
+                }
+
+                if rule.origin_id.is_some() || rule.destination_id.is_some() {
+                    rules.origin_destinations.push((
+                        rule.origin_id.as_deref().map(id),
+                        rule.destination_id.as_deref().map(id),
+                    ));
+                }
+
+                if let Some(contains_id) = &rule.contains_id {
+                    rules.contains.insert(id(contains_id));
+                }
+            }
+
+            self.fares.push((
+                FareAttribute {
+                    id: id(fare_id),
+                    price,
+                    currency: attribute.currency.clone(),
+                    transfers: match attribute.transfers {
+                        Transfers::Unlimited => None,
+                        Transfers::NoTransfer => Some(0),
+                        Transfers::UniqueTransfer => Some(1),
+                        Transfers::TwoTransfers => Some(2),
+                        Transfers::Other(transfers) => Some(transfers.max(0) as u32),
+                    },
+                    transfer_duration: attribute
+                        .transfer_duration
+                        .map(|seconds| Duration::seconds(seconds as i64)),
+                },
+                rules,
+            ));
+        }
+
+        for fare_id in gtfs.fare_rules.keys() {
+            if !gtfs.fare_attributes.contains_key(fare_id) {
+                issues.push(Issue::MissingFare {
+                    fare_id: id(fare_id),
+                });
+            }
+        }
+
+        issues
+    }
+
+    ///Cheapest combination of fares covering the rides, each fare covering consecutive rides.
+    ///Only fares in the same currency are combined, when fares in several currencies
+    ///cover the rides the currency of the fare added first is used.
+    pub(super) fn cheapest(&self, rides: &[Ride]) -> Option<Fare> {
+        let mut currencies: Vec<&str> = Vec::new();
+        for (attribute, _) in self.fares.iter() {
+            if !currencies.contains(&attribute.currency.as_str()) {
+                currencies.push(&attribute.currency);
+            }
+        }
+
+        currencies
+            .into_iter()
+            .find_map(|currency| self.cheapest_in(rides, currency))
+    }
+
+    fn cheapest_in(&self, rides: &[Ride], currency: &str) -> Option<Fare> {
+        if rides.is_empty() {
+            return None;
+        }
+
+        //Cheapest price for the first i rides and the fares used for it
+        let mut best: Vec<Option<(f64, Vec<usize>)>> = vec![None; rides.len() + 1];
+        best[0] = Some((0.0, Vec::new()));
+
+        for end in 1..=rides.len() {
+            for start in 0..end {
+                let (Some((price, used)), Some(fare)) = (
+                    &best[start],
+                    self.cheapest_single(&rides[start..end], currency),
+                ) else {
+                    continue;
+                };
+
+                let price = price + self.fares[fare].0.price;
+
+                if best[end].as_ref().is_none_or(|(best, _)| price < *best) {
+                    let mut used = used.clone();
+                    used.push(fare);
+                    best[end] = Some((price, used));
+                }
+            }
+        }
+
+        let (price, used) = best[rides.len()].take()?;
+
+        Some(Fare {
+            price,
+            currency: currency.to_string(),
+            fare_ids: used.iter().map(|&i| self.fares[i].0.id.clone()).collect(),
+        })
+    }
+
+    fn cheapest_single(&self, rides: &[Ride], currency: &str) -> Option<usize> {
+        self.fares
+            .iter()
+            .enumerate()
+            .filter(|(_, (attribute, rules))| {
+                attribute.currency == currency && attribute.allows(rides) && rules.matches(rides)
+            })
+            .min_by(|(_, (a, _)), (_, (b, _))| a.price.total_cmp(&b.price))
+            .map(|(i, _)| i)
+    }
+}
+
+///Trip ridden as part of an itinerary, with what is needed to match fare rules
+pub(super) struct Ride {
+    pub(super) route_id: String,
+    pub(super) departure: OffsetDateTime,
+    ///Zones of the stops passed in order, from boarding to alighting
+    pub(super) zones: Vec<Option<String>>,
+}
+
+///Tickets covering every ride of an itinerary
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Fare {
+    pub price: f64,
+    pub currency: String,
+    ///fare_ids of the tickets needed, in the order they are used
+    pub fare_ids: Vec<String>,
+}
+
+///Stops reachable using a single fare
+#[derive(Serialize)]
+pub struct FareReach {
+    pub fare: FareAttribute,
+    pub stop_times: HashMap<String, StopWithDuration>,
+}
+
+impl GtfsGraph {
+    pub fn fare_attributes(&self) -> impl Iterator<Item = &FareAttribute> {
+        self.fares.fares.iter().map(|(attribute, _)| attribute)
+    }
+
+    ///Searches once per fare, only riding routes and zones the fare covers
+    ///while it is valid. Results are ordered from the cheapest fare.
+    ///Fares are not combined, so journeys needing two tickets are not found.
+    pub fn fare_searches(
+        &self,
+        start_id: &str,
+        start_time: OffsetDateTime,
+    ) -> Result<Vec<FareReach>, Error> {
+        let start = self
+            .get_stop(start_id)
+            .ok_or(Error::MissingStop(start_id.to_string()))?;
+
+        let mut reaches = self
+            .fares
+            .fares
+            .par_iter()
+            .map(|(attribute, rules)| {
+                let zones = rules.zones();
+                let in_zone = |zone: &Option<String>| {
+                    zones.as_ref().is_none_or(|zones| {
+                        zone.as_deref().is_some_and(|zone| zones.contains(zone))
+                    })
+                };
+                let starts_in_zone = in_zone(&start.zone_id);
+
+                let allowed = |edge: &Edge, departure: OffsetDateTime| {
+                    starts_in_zone
+                        && rules.allows_route(&edge.route_id)
+                        && in_zone(&self.stops[edge.connected_stop].zone_id)
+                        && attribute
+                            .transfer_duration
+                            .is_none_or(|validity| departure - start_time <= validity)
+                };
+
+                Ok(FareReach {
+                    fare: attribute.clone(),
+                    stop_times: self.dijkstras_filtered(start_id, start_time, None, allowed)?,
+                })
+            })
+            .collect::<Result<Vec<FareReach>, Error>>()?;
+
+        reaches.sort_by(|a, b| a.fare.price.total_cmp(&b.fare.price));
+
+        Ok(reaches)
+    }
+
+    ///Renders the price of the cheapest single fare reaching each pixel within cutoff,
+    ///from black for the cheapest to white for the most expensive or unreachable pixels
+    pub fn generate_fare_tile(
+        &self,
+        zoom: u32,
+        tile_x: u32,
+        tile_y: u32,
+        reaches: &[FareReach],
+        cutoff: Duration,
+    ) -> GrayImage {
+        let mut buf = GrayImage::new(TILE_RESOLUTION, TILE_RESOLUTION);
+        let tile = TileNumbers {
+            zoom,
+            x: tile_x,
+            y: tile_y,
+        };
+
+        let max_price = reaches
+            .iter()
+            .map(|reach| reach.fare.price)
+            .fold(0.0, f64::max);
+
+        //Stops reached after the cutoff can't reach any pixel within it
+        let reaches: Vec<(f64, HashMap<String, StopWithDuration>)> = reaches
+            .iter()
+            .map(|reach| {
+                (
+                    reach.fare.price,
+                    reach
+                        .stop_times
+                        .iter()
+                        .filter(|(_, stop_time)| stop_time.duration <= cutoff)
+                        .map(|(id, stop_time)| (id.clone(), stop_time.clone()))
+                        .collect(),
+                )
+            })
+            .collect();
+
+        buf.par_enumerate_pixels_mut()
+            .for_each(|(pixel_x, pixel_y, pixel)| {
+                let coordinates = tile.get_pixel_coordinates(pixel_x, pixel_y);
+
+                let price = reaches.iter().find_map(|(price, stop_times)| {
+                    reachable_time(&coordinates, stop_times)
+                        .is_some_and(|time| time <= cutoff)
+                        .then_some(*price)
+                });
+
+                pixel.0 = [match price {
+                    Some(_) if max_price <= 0.0 => 0,
+                    Some(price) => (price / max_price * u8::MAX as f64) as u8,
+                    None => u8::MAX,
+                }];
+            });
+
+        buf
+    }
+}
+
+///Fastest time to each stop using any single fare costing at most budget.
+///Stops may be reached fastest with different fares than the stops before them,
+///so the result has no itineraries besides the one to the origin.
+pub fn reachable_within_budget(
+    reaches: &[FareReach],
+    budget: f64,
+) -> HashMap<String, StopWithDuration> {
+    let mut stop_times: HashMap<String, StopWithDuration> = HashMap::new();
+
+    for reach in reaches.iter().filter(|reach| reach.fare.price <= budget) {
+        for (id, stop_time) in reach.stop_times.iter() {
+            if stop_times
+                .get(id)
+                .is_none_or(|fastest| stop_time.duration < fastest.duration)
+            {
+                stop_times.insert(
+                    id.clone(),
+                    StopWithDuration {
+                        arrival: stop_time.arrival.as_ref().map(|_| Arrival::Combined),
+                        ..stop_time.clone()
+                    },
+                );
+            }
+        }
+    }
+
+    stop_times
+}
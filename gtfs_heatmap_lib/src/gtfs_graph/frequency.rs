@@ -0,0 +1,157 @@
+use std::{collections::HashMap, sync::Arc};
+
+use gtfs_structures::RouteType;
+use image::GrayImage;
+use rayon::prelude::*;
+use time::{Duration, OffsetDateTime};
+
+use crate::coords::{Coordinates, TileNumbers};
+
+use super::{heatmap::TILE_RESOLUTION, GtfsGraph};
+
+///Which departures count towards the service level of a place
+pub struct FrequencyOptions {
+    pub start: OffsetDateTime,
+    ///Departures from start until start + window are counted
+    pub window: Duration,
+    ///Stops further than this many meters don't serve a place
+    pub walking_distance: f64,
+    ///Multiplier for departures of each route type, missing route types count once
+    pub route_type_weights: HashMap<RouteType, f64>,
+    ///Departures per hour drawn black in tiles, anything less frequent is lighter
+    pub max_frequency: f64,
+}
+
+impl FrequencyOptions {
+    ///Unweighted departures during an hour from start, within a five minute walk
+    pub fn new(start: OffsetDateTime) -> Self {
+        Self {
+            start,
+            window: Duration::hours(1),
+            walking_distance: 400.0,
+            route_type_weights: HashMap::new(),
+            max_frequency: 60.0,
+        }
+    }
+}
+
+///Weights by typical vehicle capacity, a bus departure counting once
+pub fn capacity_weights() -> HashMap<RouteType, f64> {
+    HashMap::from([
+        (RouteType::Tramway, 2.0),
+        (RouteType::Subway, 4.0),
+        (RouteType::Rail, 4.0),
+        (RouteType::Ferry, 2.0),
+    ])
+}
+
+///Weighted departures per hour of each route from a stop
+type RouteFrequencies<'a> = HashMap<&'a Arc<str>, f64>;
+
+impl GtfsGraph {
+    ///Weighted departures per hour at the stops within walking distance of the coordinates
+    pub fn frequency_at(&self, coordinates: &Coordinates, options: &FrequencyOptions) -> f64 {
+        let stops: Vec<(Coordinates, RouteFrequencies)> = self
+            .stops
+            .iter()
+            .enumerate()
+            .filter(|(_, stop)| {
+                stop.coordinates.haversine_distance(coordinates) <= options.walking_distance
+            })
+            .map(|(index, stop)| (stop.coordinates, self.route_frequencies(index, options)))
+            .collect();
+
+        combined_frequency(coordinates, &stops, options.walking_distance)
+    }
+
+    ///Renders the service level of every pixel independent of any origin,
+    ///from white for no departures to black for options.max_frequency or more
+    pub fn generate_frequency_tile(
+        &self,
+        zoom: u32,
+        tile_x: u32,
+        tile_y: u32,
+        options: &FrequencyOptions,
+    ) -> GrayImage {
+        let mut buf = GrayImage::new(TILE_RESOLUTION, TILE_RESOLUTION);
+        let tile = TileNumbers {
+            zoom,
+            x: tile_x,
+            y: tile_y,
+        };
+
+        //Only stops within walking distance of the tile can serve its pixels
+        let center = tile.get_pixel_coordinates(TILE_RESOLUTION / 2, TILE_RESOLUTION / 2);
+        let radius =
+            center.haversine_distance(&tile.get_pixel_coordinates(0, 0)) + options.walking_distance;
+
+        let stops: Vec<(Coordinates, RouteFrequencies)> = self
+            .stops
+            .par_iter()
+            .enumerate()
+            .filter(|(_, stop)| stop.coordinates.haversine_distance(&center) <= radius)
+            .map(|(index, stop)| (stop.coordinates, self.route_frequencies(index, options)))
+            .filter(|(_, frequencies)| !frequencies.is_empty())
+            .collect();
+
+        buf.par_enumerate_pixels_mut()
+            .for_each(|(pixel_x, pixel_y, pixel)| {
+                let coordinates = tile.get_pixel_coordinates(pixel_x, pixel_y);
+                let frequency = combined_frequency(&coordinates, &stops, options.walking_distance);
+                let darkness = (frequency / options.max_frequency).min(1.0) * u8::MAX as f64;
+
+                pixel.0 = [u8::MAX - darkness as u8];
+            });
+
+        buf
+    }
+
+    fn route_frequencies(&self, stop: usize, options: &FrequencyOptions) -> RouteFrequencies<'_> {
+        let end = options.start + options.window;
+        let hours = options.window.as_seconds_f64() / 3600.0;
+
+        let mut frequencies = RouteFrequencies::new();
+
+        for edge in self.stops[stop].edges.iter() {
+            let departures = self.edge_departures(edge, options.start, end).count();
+
+            if departures == 0 || hours <= 0.0 {
+                continue;
+            }
+
+            let weight = self
+                .routes
+                .get(&edge.route_id)
+                .and_then(|route| options.route_type_weights.get(&route.route_type))
+                .copied()
+                .unwrap_or(1.0);
+
+            *frequencies.entry(&edge.route_id).or_default() += departures as f64 * weight / hours;
+        }
+
+        frequencies
+    }
+}
+
+///Sums the departures of every route at the stops within walking distance.
+///A route is counted once, from the stop it departs most often from,
+///so a line passing several nearby stops isn't counted again at each of them.
+fn combined_frequency(
+    coordinates: &Coordinates,
+    stops: &[(Coordinates, RouteFrequencies)],
+    walking_distance: f64,
+) -> f64 {
+    let mut routes = RouteFrequencies::new();
+
+    for (_, frequencies) in stops
+        .iter()
+        .filter(|(stop, _)| stop.haversine_distance(coordinates) <= walking_distance)
+    {
+        for (route_id, frequency) in frequencies {
+            let best = routes.entry(*route_id).or_default();
+            *best = best.max(*frequency);
+        }
+    }
+
+    routes.values().sum()
+}
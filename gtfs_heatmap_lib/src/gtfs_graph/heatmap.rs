@@ -1,16 +1,24 @@
 use core::f64;
-use std::{collections::HashMap, i64, time::Instant};
+use std::{collections::HashMap, i64, io::Cursor, ops::RangeInclusive, time::Instant};
 use time::Duration;
 
-use image::{ColorType, GrayImage, RgbImage};
+use image::{ColorType, DynamicImage, GrayImage, ImageError, ImageFormat, RgbImage};
+use rayon::prelude::*;
 
-use crate::coords::TileNumbers;
+use crate::{
+    coords::{BoundingBox, Coordinates, TileNumbers},
+    geotiff::{Crs, Raster},
+};
 
 use super::{dijkstras::StopWithDuration, GtfsGraph};
 
-const TILE_RESOLUTION: u32 = 256;
-const WALKING_SPEED: f64 = 1.0;
-const MAX_WALKING_TIME: i64 = Duration::minutes(45).whole_seconds();
+pub(super) const TILE_RESOLUTION: u32 = 256;
+pub(crate) const WALKING_SPEED: f64 = 1.0;
+pub(crate) const MAX_WALKING_TIME: i64 = Duration::minutes(45).whole_seconds();
+///Difference at which difference tiles reach full colour
+const MAX_DIFFERENCE: i64 = Duration::minutes(30).whole_seconds();
+///Tiles rendered at once by render_tile_pyramid before handing them to the writer
+const PYRAMID_BATCH_SIZE: usize = 256;
 
 enum Rgb {
     R,
@@ -19,13 +27,14 @@ enum Rgb {
 }
 
 impl GtfsGraph {
+    ///None if stop_times is empty, as there are no travel times to scale the tile by
     pub fn generate_heatmap_tile(
         &self,
         zoom: u32,
         tile_x: u32,
         tile_y: u32,
         stop_times: &HashMap<String, StopWithDuration>,
-    ) -> (GrayImage, String) {
+    ) -> Option<(GrayImage, String)> {
         let mut buf = GrayImage::new(TILE_RESOLUTION, TILE_RESOLUTION);
         let tile = TileNumbers {
             zoom,
@@ -35,13 +44,11 @@ impl GtfsGraph {
 
         let start = Instant::now();
 
-        let max_time = stop_times.values().fold(i64::MIN, |acc, stop_duration| {
-            stop_duration.duration.whole_seconds().max(acc)
-        }) + MAX_WALKING_TIME;
+        let max_time = max_time(stop_times)?;
 
         let max_time_time = start.elapsed();
 
-        buf.enumerate_pixels_mut()
+        buf.par_enumerate_pixels_mut()
             .for_each(|(pixel_x, pixel_y, mut pixel)| {
                 pixel.0 = [calculate_pixel_brightness(
                     pixel_x, pixel_y, &tile, stop_times, max_time,
@@ -50,13 +57,225 @@ impl GtfsGraph {
 
         let img_gen_time = start.elapsed() - max_time_time;
 
-        (
+        Some((
             buf,
             format!(
                 "Time used to generate max time : {:?}\nTime used to draw image: {:?}\nMax time: {:?}",
                 max_time_time, img_gen_time, max_time
             ),
-        )
+        ))
+    }
+
+    ///Renders the bounding box as a single image, with pixels the same size as in tiles of the zoom level.
+    ///None if stop_times is empty.
+    pub fn generate_heatmap_area(
+        &self,
+        bounding_box: &BoundingBox,
+        zoom: u32,
+        stop_times: &HashMap<String, StopWithDuration>,
+    ) -> Option<GrayImage> {
+        let (min_x, min_y) = Coordinates {
+            latitude: bounding_box.max.latitude,
+            longitude: bounding_box.min.longitude,
+        }
+        .as_global_pixel(zoom);
+        let (max_x, max_y) = Coordinates {
+            latitude: bounding_box.min.latitude,
+            longitude: bounding_box.max.longitude,
+        }
+        .as_global_pixel(zoom);
+
+        let mut buf = GrayImage::new(max_x - min_x, max_y - min_y);
+        let max_time = max_time(stop_times)?;
+
+        buf.par_enumerate_pixels_mut()
+            .for_each(|(pixel_x, pixel_y, pixel)| {
+                let (global_x, global_y) = (min_x + pixel_x, min_y + pixel_y);
+                let tile = TileNumbers {
+                    zoom,
+                    x: global_x / TILE_RESOLUTION,
+                    y: global_y / TILE_RESOLUTION,
+                };
+
+                pixel.0 = [calculate_pixel_brightness(
+                    global_x % TILE_RESOLUTION,
+                    global_y % TILE_RESOLUTION,
+                    &tile,
+                    stop_times,
+                    max_time,
+                )]
+            });
+
+        Some(buf)
+    }
+
+    ///Travel times in seconds over the bounding box, snapped to the pixel grid of the crs at zoom.
+    ///Pixels further than MAX_WALKING_TIME from every reached stop are NaN.
+    pub fn travel_time_raster(
+        &self,
+        bounding_box: &BoundingBox,
+        crs: Crs,
+        zoom: u32,
+        stop_times: &HashMap<String, StopWithDuration>,
+    ) -> Raster {
+        let pixel_size = crs.pixel_size(zoom);
+        let (min_x, min_y) = crs.project(&bounding_box.min);
+        let (max_x, max_y) = crs.project(&bounding_box.max);
+
+        let origin = (
+            (min_x / pixel_size).floor() * pixel_size,
+            (max_y / pixel_size).ceil() * pixel_size,
+        );
+        let width = ((max_x - origin.0) / pixel_size).ceil().max(1.0) as u32;
+        let height = ((origin.1 - min_y) / pixel_size).ceil().max(1.0) as u32;
+
+        let raster = Raster {
+            crs,
+            width,
+            height,
+            origin,
+            pixel_size,
+            values: Vec::new(),
+        };
+
+        let values = (0..width * height)
+            .into_par_iter()
+            .map(|i| {
+                reachable_time(&raster.pixel_center(i % width, i / width), stop_times)
+                    .map_or(f32::NAN, |time| time.as_seconds_f32())
+            })
+            .collect();
+
+        Raster { values, ..raster }
+    }
+
+    ///Renders every tile overlapping the bounding box on each zoom level as webp.
+    ///Tiles are rendered in parallel batches and passed to write one at a time.
+    ///Returns the number of tiles written, or Error::EmptySearch if stop_times is empty.
+    pub fn render_tile_pyramid(
+        &self,
+        bounding_box: &BoundingBox,
+        zoom_levels: RangeInclusive<u32>,
+        stop_times: &HashMap<String, StopWithDuration>,
+        mut write: impl FnMut(&TileNumbers, &[u8]) -> Result<(), crate::Error>,
+    ) -> Result<usize, crate::Error> {
+        let tiles: Vec<TileNumbers> = zoom_levels
+            .flat_map(|zoom| bounding_box.tiles(zoom))
+            .collect();
+
+        for batch in tiles.chunks(PYRAMID_BATCH_SIZE) {
+            let encoded = batch
+                .par_iter()
+                .map(|tile| {
+                    let (image, _) = self
+                        .generate_heatmap_tile(tile.zoom, tile.x, tile.y, stop_times)
+                        .ok_or(crate::Error::EmptySearch)?;
+                    Ok(encode_webp(image)?)
+                })
+                .collect::<Result<Vec<_>, crate::Error>>()?;
+
+            for (tile, data) in batch.iter().zip(encoded) {
+                write(tile, &data)?;
+            }
+        }
+
+        Ok(tiles.len())
+    }
+
+    ///Draws a tile showing the change in travel time from before to after.
+    ///Blue pixels got faster, red pixels got slower and white pixels stayed the same.
+    ///None if either search is empty.
+    pub fn generate_difference_tile(
+        &self,
+        zoom: u32,
+        tile_x: u32,
+        tile_y: u32,
+        before: &HashMap<String, StopWithDuration>,
+        after: &HashMap<String, StopWithDuration>,
+    ) -> Option<RgbImage> {
+        if before.is_empty() || after.is_empty() {
+            return None;
+        }
+
+        let mut buf = RgbImage::new(TILE_RESOLUTION, TILE_RESOLUTION);
+        let tile = TileNumbers {
+            zoom,
+            x: tile_x,
+            y: tile_y,
+        };
+
+        buf.par_enumerate_pixels_mut()
+            .for_each(|(pixel_x, pixel_y, pixel)| {
+                let pixel_coords = tile.get_pixel_coordinates(pixel_x, pixel_y);
+
+                if let (Some(after), Some(before)) = (
+                    calculate_pixel_time(&pixel_coords, after),
+                    calculate_pixel_time(&pixel_coords, before),
+                ) {
+                    pixel.0 = difference_to_rgb((after - before).whole_seconds());
+                }
+            });
+
+        Some(buf)
+    }
+}
+
+///Longest travel time in seconds, plus the time walked from the furthest stop.
+///None if no stops were reached.
+fn max_time(stop_times: &HashMap<String, StopWithDuration>) -> Option<i64> {
+    stop_times
+        .values()
+        .map(|stop_duration| stop_duration.duration.whole_seconds())
+        .max()
+        .map(|max| max + MAX_WALKING_TIME)
+}
+
+///Fastest time to the pixel through any stop, None if no stops were reached
+fn calculate_pixel_time(
+    pixel_coords: &Coordinates,
+    stops: &HashMap<String, StopWithDuration>,
+) -> Option<Duration> {
+    stops
+        .values()
+        .map(|stop| {
+            stop.duration + walking_time(stop.stop.coordinates.haversine_distance(pixel_coords))
+        })
+        .min()
+}
+
+///Fastest time to the coordinates, walking at most MAX_WALKING_TIME from a reached stop
+pub(super) fn reachable_time(
+    coordinates: &Coordinates,
+    stops: &HashMap<String, StopWithDuration>,
+) -> Option<Duration> {
+    stops
+        .values()
+        .filter_map(|stop| {
+            let walk = walking_time(stop.stop.coordinates.haversine_distance(coordinates));
+            (walk.whole_seconds() <= MAX_WALKING_TIME).then(|| stop.duration + walk)
+        })
+        .min()
+}
+
+pub fn encode_webp(image: impl Into<DynamicImage>) -> Result<Vec<u8>, ImageError> {
+    let mut writer = Cursor::new(Vec::new());
+    image.into().write_to(&mut writer, ImageFormat::WebP)?;
+    Ok(writer.into_inner())
+}
+
+///Time it takes to walk distance meters
+pub(crate) fn walking_time(distance: f64) -> Duration {
+    Duration::seconds_f64(distance * WALKING_SPEED)
+}
+
+///Maps a difference in seconds to a diverging blue-white-red colour
+fn difference_to_rgb(difference_sec: i64) -> [u8; 3] {
+    let shade = 255 - (difference_sec.abs().min(MAX_DIFFERENCE) * 255 / MAX_DIFFERENCE) as u8;
+
+    if difference_sec < 0 {
+        [shade, shade, u8::MAX]
+    } else {
+        [u8::MAX, shade, shade]
     }
 }
 
@@ -69,18 +288,9 @@ fn calculate_pixel_brightness(
 ) -> u8 {
     let pixel_coords = tile.get_pixel_coordinates(pixel_x, pixel_y);
 
-    let time: Duration = stops.values().fold(Duration::MAX, |acc, stop| {
-        (stop.duration
-            + Duration::seconds_f64(
-                stop.stop
-                    .read()
-                    .unwrap()
-                    .coordinates
-                    .haversine_distance(&pixel_coords)
-                    * WALKING_SPEED,
-            ))
-        .min(acc)
-    });
+    let Some(time) = calculate_pixel_time(&pixel_coords, stops) else {
+        return u8::MAX;
+    };
 
     let brightness = (time.whole_seconds() * 255) / (max_time_sec);
     if brightness > u8::MAX as i64 {
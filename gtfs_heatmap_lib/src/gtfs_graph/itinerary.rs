@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use super::{
+    dijkstras::{Arrival, StopWithDuration},
+    fares::{Fare, Ride},
+    Error, GtfsGraph,
+};
+
+///Part of an itinerary, either riding a single trip or walking between two stops
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Leg {
+    Transit {
+        route_id: String,
+        trip_id: String,
+        from_stop: String,
+        to_stop: String,
+        #[serde(with = "time::serde::rfc3339")]
+        departure: OffsetDateTime,
+        #[serde(with = "time::serde::rfc3339")]
+        arrival: OffsetDateTime,
+    },
+    Walk {
+        from_stop: String,
+        to_stop: String,
+        ///Walking time in seconds
+        duration: i64,
+    },
+}
+
+///How a stop was reached in a search, from the stop the search started from
+#[derive(Serialize, Debug, Clone)]
+pub struct Itinerary {
+    ///Travel time in seconds
+    pub duration: i64,
+    pub legs: Vec<Leg>,
+    ///Cheapest tickets for the transit legs, None without transit legs or a fare covering them
+    pub fare: Option<Fare>,
+}
+
+impl GtfsGraph {
+    ///Reconstructs the itinerary to a destination stop from the results of a search.
+    ///Consecutive edges of the same trip are merged into a single transit leg.
+    ///The fare is computed from the fares of the feeds, using the zones of every stop passed.
+    ///Results combining several journeys, like generalized_cost, have no itineraries.
+    pub fn itinerary(
+        &self,
+        stop_times: &HashMap<String, StopWithDuration>,
+        destination: &str,
+    ) -> Result<Itinerary, Error> {
+        if self.stop_index(destination).is_none() {
+            return Err(Error::MissingStop(destination.to_string()));
+        }
+
+        let reached = stop_times
+            .get(destination)
+            .ok_or(Error::NotReached(destination.to_string()))?;
+
+        let zone = |stop: usize| self.stops[stop].zone_id.clone();
+
+        let mut legs: Vec<Leg> = Vec::new();
+        let mut rides: Vec<Ride> = Vec::new();
+        let mut current = reached;
+
+        while let Some(arrival) = &current.arrival {
+            let to = self
+                .stop_index(&current.stop.id)
+                .expect("Reached stops are in the graph");
+            let to_stop = current.stop.id.clone();
+
+            let (from, leg) = match arrival {
+                Arrival::Ride {
+                    from,
+                    edge,
+                    departure,
+                } => (
+                    *from,
+                    Leg::Transit {
+                        route_id: edge.route_id.to_string(),
+                        trip_id: edge.trip_id.to_string(),
+                        from_stop: self.stops[*from].id.clone(),
+                        to_stop,
+                        departure: *departure,
+                        arrival: edge.arrival_datetime(*departure),
+                    },
+                ),
+                Arrival::Walk { from, duration } => (
+                    *from,
+                    Leg::Walk {
+                        from_stop: self.stops[*from].id.clone(),
+                        to_stop,
+                        duration: duration.whole_seconds(),
+                    },
+                ),
+                Arrival::Combined => return Err(Error::NoItinerary(to_stop)),
+            };
+
+            match (legs.last_mut(), leg) {
+                (
+                    Some(Leg::Transit {
+                        trip_id: next_trip,
+                        from_stop: next_from,
+                        departure: next_departure,
+                        ..
+                    }),
+                    Leg::Transit {
+                        trip_id,
+                        from_stop,
+                        departure,
+                        ..
+                    },
+                ) if *next_trip == trip_id => {
+                    *next_from = from_stop;
+                    *next_departure = departure;
+
+                    if let Some(ride) = rides.last_mut() {
+                        ride.departure = departure;
+                        ride.zones.push(zone(from));
+                    }
+                }
+                (_, leg) => {
+                    if let Leg::Transit {
+                        route_id,
+                        departure,
+                        ..
+                    } = &leg
+                    {
+                        rides.push(Ride {
+                            route_id: route_id.clone(),
+                            departure: *departure,
+                            zones: vec![zone(to), zone(from)],
+                        });
+                    }
+
+                    legs.push(leg);
+                }
+            }
+
+            current = stop_times
+                .get(&self.stops[from].id)
+                .ok_or(Error::NotReached(self.stops[from].id.clone()))?;
+        }
+
+        legs.reverse();
+        rides.reverse();
+        for ride in rides.iter_mut() {
+            ride.zones.reverse();
+        }
+
+        Ok(Itinerary {
+            duration: reached.duration.whole_seconds(),
+            legs,
+            fare: self.fares.cheapest(&rides),
+        })
+    }
+}
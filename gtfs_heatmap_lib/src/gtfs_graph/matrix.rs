@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use rayon::{prelude::*, ThreadPoolBuilder};
+use time::{Duration, OffsetDateTime};
+
+use crate::coords::Coordinates;
+
+use super::{
+    csv::{field, Csv},
+    dijkstras::StopWithDuration,
+    heatmap::{walking_time, MAX_WALKING_TIME},
+    Error, GtfsGraph,
+};
+
+#[derive(Debug, Clone)]
+pub enum Location {
+    Stop(String),
+    Coordinates(Coordinates),
+}
+
+///An origin or a destination of a matrix, id is only used to label the output
+#[derive(Debug, Clone)]
+pub struct Place {
+    pub id: String,
+    pub location: Location,
+}
+
+pub struct MatrixOptions {
+    pub start_time: OffsetDateTime,
+    ///Searches are repeated every step within the window after start_time
+    ///and the median travel time is reported. Zero window searches only at start_time.
+    pub window: Duration,
+    pub step: Duration,
+    ///Zero uses one thread per core
+    pub threads: usize,
+}
+
+///Travel times indexed by origin and then destination, None if unreachable
+pub struct TravelTimeMatrix {
+    pub origins: Vec<Place>,
+    pub destinations: Vec<Place>,
+    pub times: Vec<Vec<Option<Duration>>>,
+}
+
+impl TravelTimeMatrix {
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("origin,destination,travel_time_seconds\n");
+
+        for (origin, times) in self.origins.iter().zip(self.times.iter()) {
+            for (destination, time) in self.destinations.iter().zip(times.iter()) {
+                csv.push_str(&format!(
+                    "{},{},{}\n",
+                    origin.id,
+                    destination.id,
+                    time.map(|time| time.whole_seconds().to_string())
+                        .unwrap_or_default()
+                ));
+            }
+        }
+
+        csv
+    }
+}
+
+///Parses places from csv with a header row.
+///Every row needs an id and either a stop_id or lat and lon columns.
+pub fn parse_places_csv(data: &str) -> Result<Vec<Place>, Error> {
+    let invalid = |message: String| Error::InvalidPlaces(message);
+    let csv = Csv::parse(data).ok_or(invalid("csv is empty".to_string()))?;
+
+    let id_column = csv
+        .column(&["id"])
+        .ok_or(invalid("csv has no id column".to_string()))?;
+    let stop_column = csv.column(&["stop_id"]);
+    let latitude_column = csv.column(&["lat", "latitude"]);
+    let longitude_column = csv.column(&["lon", "lng", "longitude"]);
+
+    csv.rows
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let location = match (
+                field(row, stop_column),
+                field(row, latitude_column).and_then(|field| field.parse::<f64>().ok()),
+                field(row, longitude_column).and_then(|field| field.parse::<f64>().ok()),
+            ) {
+                (Some(stop_id), _, _) => Location::Stop(stop_id.to_string()),
+                (None, Some(latitude), Some(longitude)) => Location::Coordinates(Coordinates {
+                    latitude,
+                    longitude,
+                }),
+                _ => {
+                    return Err(invalid(format!(
+                        "row {} has no stop_id or coordinates",
+                        i + 1
+                    )))
+                }
+            };
+
+            Ok(Place {
+                id: field(row, Some(id_column))
+                    .ok_or(invalid(format!("row {} has no id", i + 1)))?
+                    .to_string(),
+                location,
+            })
+        })
+        .collect()
+}
+
+///Stop to start searching from and the time it takes to get there
+struct Origin {
+    stop_id: String,
+    access_time: Duration,
+}
+
+///Ways to reach a destination, stops near it with the time from the stop
+///and the coordinates for walking there directly
+struct Destination {
+    egress: Vec<(String, Duration)>,
+    coordinates: Coordinates,
+}
+
+impl GtfsGraph {
+    ///Computes travel times from every origin to every destination.
+    ///Origins are searched in parallel on a pool of options.threads threads.
+    pub fn travel_time_matrix(
+        &self,
+        origins: &[Place],
+        destinations: &[Place],
+        options: &MatrixOptions,
+    ) -> Result<TravelTimeMatrix, Error> {
+        let resolved_destinations = destinations
+            .iter()
+            .map(|place| self.resolve_destination(&place.location))
+            .collect::<Result<Vec<Destination>, Error>>()?;
+
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(options.threads)
+            .build()
+            .map_err(|err| Error::ThreadPool(err.to_string()))?;
+
+        let times = pool.install(|| {
+            origins
+                .par_iter()
+                .map(|origin| self.origin_travel_times(origin, &resolved_destinations, options))
+                .collect::<Result<Vec<_>, Error>>()
+        })?;
+
+        Ok(TravelTimeMatrix {
+            origins: origins.to_vec(),
+            destinations: destinations.to_vec(),
+            times,
+        })
+    }
+
+    fn origin_travel_times(
+        &self,
+        origin: &Place,
+        destinations: &[Destination],
+        options: &MatrixOptions,
+    ) -> Result<Vec<Option<Duration>>, Error> {
+        let origin_coordinates = self.location_coordinates(&origin.location)?;
+        let start = self.resolve_origin(&origin.location)?;
+
+        let mut samples: Vec<Vec<Option<Duration>>> = vec![Vec::new(); destinations.len()];
+        let mut departure_offset = Duration::ZERO;
+
+        loop {
+            let stop_times = match &start {
+                Some(start) => self.dijkstras(
+                    &start.stop_id,
+                    options.start_time + departure_offset + start.access_time,
+                )?,
+                None => HashMap::new(),
+            };
+
+            for (destination, samples) in destinations.iter().zip(samples.iter_mut()) {
+                let transit = start.as_ref().and_then(|start| {
+                    Some(transit_time(&stop_times, &destination.egress)? + start.access_time)
+                });
+                let walk =
+                    walking_time(origin_coordinates.haversine_distance(&destination.coordinates));
+                let walk = (walk.whole_seconds() <= MAX_WALKING_TIME).then_some(walk);
+
+                samples.push(match (transit, walk) {
+                    (Some(transit), Some(walk)) => Some(transit.min(walk)),
+                    (transit, walk) => transit.or(walk),
+                });
+            }
+
+            departure_offset += options.step;
+
+            if options.step <= Duration::ZERO || departure_offset > options.window {
+                break;
+            }
+        }
+
+        Ok(samples.into_iter().map(median).collect())
+    }
+
+    fn location_coordinates(&self, location: &Location) -> Result<Coordinates, Error> {
+        match location {
+            Location::Stop(id) => Ok(self
+                .get_stop(id)
+                .ok_or(Error::MissingStop(id.clone()))?
+                .coordinates),
+            Location::Coordinates(coordinates) => Ok(*coordinates),
+        }
+    }
+
+    fn resolve_origin(&self, location: &Location) -> Result<Option<Origin>, Error> {
+        match location {
+            Location::Stop(id) => {
+                self.get_stop(id).ok_or(Error::MissingStop(id.clone()))?;
+
+                Ok(Some(Origin {
+                    stop_id: id.clone(),
+                    access_time: Duration::ZERO,
+                }))
+            }
+            Location::Coordinates(coordinates) => {
+                let Some((stop, distance)) = self.nearest_stop(coordinates) else {
+                    return Ok(None);
+                };
+                let access_time = walking_time(distance);
+
+                Ok(
+                    (access_time.whole_seconds() <= MAX_WALKING_TIME).then(|| Origin {
+                        stop_id: stop.id.clone(),
+                        access_time,
+                    }),
+                )
+            }
+        }
+    }
+
+    fn resolve_destination(&self, location: &Location) -> Result<Destination, Error> {
+        let coordinates = self.location_coordinates(location)?;
+
+        Ok(Destination {
+            egress: match location {
+                Location::Stop(id) => vec![(id.clone(), Duration::ZERO)],
+                Location::Coordinates(coordinates) => self.stops_within_walking(coordinates),
+            },
+            coordinates,
+        })
+    }
+}
+
+///Fastest time to a destination through the stops near it
+pub(super) fn transit_time(
+    stop_times: &HashMap<String, StopWithDuration>,
+    egress: &[(String, Duration)],
+) -> Option<Duration> {
+    egress
+        .iter()
+        .filter_map(|(stop_id, walk)| Some(stop_times.get(stop_id)?.duration + *walk))
+        .min()
+}
+
+///Median where unreachable samples count as slower than any reachable one
+fn median(mut samples: Vec<Option<Duration>>) -> Option<Duration> {
+    samples.sort_by_key(|sample| sample.unwrap_or(Duration::MAX));
+    *samples.get(samples.len() / 2)?
+}
@@ -1,7 +1,22 @@
 #![allow(unused)]
+pub mod accessibility;
+mod csv;
+pub mod departures;
 pub mod dijkstras;
+pub mod fares;
+pub mod frequency;
 pub mod heatmap;
+pub mod itinerary;
+pub mod matrix;
+pub mod pareto;
 pub mod parser;
+pub mod realtime;
+pub mod routes;
+pub mod scenario;
+pub mod stop_search;
+pub mod typical_week;
+pub mod validation;
+pub mod vector_tile;
 
 #[cfg(test)]
 mod tests;
@@ -10,18 +25,29 @@ use core::error;
 use std::{
     collections::HashMap,
     mem,
+    ops::Range,
     sync::{Arc, PoisonError, RwLock},
 };
 
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use gtfs_structures::LocationType;
 use thiserror::Error;
 use time::{macros::*, Date, Duration, OffsetDateTime, Weekday};
 
-use crate::{coords::Coordinates, gtfs_types::Day};
+use crate::{coords::Coordinates, gtfs_types::Day, timezone::TimeZone};
+
+use departures::Service;
+use fares::Fares;
+use routes::{Route, Trip};
+use stop_search::Station;
+use validation::ValidationReport;
 
 const SECONDS_IN_DAY: u32 = 86_400;
+///Departures on the service date after the search date are only boarded within this time,
+///so searches near midnight catch early trips without every search waiting until tomorrow
+const NEXT_DAY_HORIZON: Duration = Duration::hours(4);
 
 impl<T> From<PoisonError<T>> for Error {
     fn from(_err: PoisonError<T>) -> Self {
@@ -73,15 +99,59 @@ pub enum Error {
     LocationTypeNotStop,
     #[error("Internal RwLock is poisoned")]
     Poison,
+    #[error("Invalid scenario: {0}")]
+    InvalidScenario(String),
+    #[error("Invalid opportunity data: {0}")]
+    InvalidOpportunities(String),
+    #[error("Invalid accessibility grid: {0}")]
+    InvalidGrid(String),
+    #[error("Invalid places: {0}")]
+    InvalidPlaces(String),
+    #[error("Failed to start thread pool: {0}")]
+    ThreadPool(String),
+    #[error("No stop within walking distance of {0}, {1}")]
+    NoStopNearby(f64, f64),
+    #[error("Invalid GTFS-Realtime feed: {0}")]
+    InvalidRealtime(String),
+    #[error("Stop {0} has no coordinates")]
+    MissingCoordinates(String),
+    #[error("Stop {0} was not reached by the search")]
+    NotReached(String),
+    #[error("Stop {0} has no itinerary, its travel time combines several journeys")]
+    NoItinerary(String),
+    #[error("Couldn't find route with id: {0}")]
+    MissingRoute(String),
+    #[error("Couldn't find trip with id: {0}")]
+    MissingTrip(String),
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct Stop {
     pub id: String,
     #[serde(flatten)]
     pub coordinates: Coordinates,
     #[serde(skip)]
     edges: Vec<Arc<Edge>>,
+    #[serde(skip)]
+    transfers: Vec<Transfer>,
+    ///Fare zone from stops.txt
+    #[serde(skip)]
+    zone_id: Option<String>,
+    #[serde(skip)]
+    name: Option<String>,
+    #[serde(skip)]
+    code: Option<String>,
+    ///stop_id of the station the stop belongs to
+    #[serde(skip)]
+    parent_station: Option<String>,
+}
+
+///Walk from a stop to another stop, which can be taken at any time
+#[derive(Clone)]
+struct Transfer {
+    ///Index of the stop walked to in GtfsGraph::stops
+    stop: usize,
+    duration: Duration,
 }
 
 ///Stops type must be Stop so it can be represented by this
@@ -93,17 +163,22 @@ impl TryFrom<gtfs_structures::Stop> for Stop {
             return Err(Error::LocationTypeNotStop);
         }
 
+        let (Some(latitude), Some(longitude)) = (stop.latitude, stop.longitude) else {
+            return Err(Error::MissingCoordinates(stop.id));
+        };
+
         Ok(Self {
             id: stop.id,
             coordinates: Coordinates {
-                latitude: stop
-                    .latitude
-                    .expect("stop with location type StopPoint always has a latitude"),
-                longitude: stop
-                    .longitude
-                    .expect("stop with location type StopPoint always has a longitude"),
+                latitude,
+                longitude,
             },
             edges: Vec::new(),
+            transfers: Vec::new(),
+            zone_id: stop.zone_id,
+            name: stop.name,
+            code: stop.code,
+            parent_station: stop.parent_station,
         })
     }
 }
@@ -114,9 +189,23 @@ struct Edge {
     //departure time is from former stop_time and arrival time from latter stop_time
     //used in conjunction with the services date from calendar.
     departure_time: u32,
+    arrival_time: u32,
+    ///Index of the arrival stop in GtfsGraph::stops
     #[serde(skip)]
-    connected_stop: Arc<RwLock<Stop>>,
+    connected_stop: usize,
     weekdays: ValidDays,
+    trip_id: Arc<str>,
+    route_id: Arc<str>,
+    ///stop_sequence of the departure stop within the trip
+    stop_sequence: u32,
+}
+
+///Trip an edge is part of, kept on the edge so scenarios and realtime updates can target it
+pub struct TripStop<'a> {
+    pub trip_id: &'a Arc<str>,
+    pub route_id: &'a Arc<str>,
+    ///stop_sequence of the departure stop within the trip
+    pub stop_sequence: u32,
 }
 
 impl From<[bool; 7]> for ValidDays {
@@ -136,19 +225,49 @@ impl From<ValidDays> for [bool; 7] {
 }
 
 impl Edge {
-    pub fn departure_datetime(&self, current_date_time: OffsetDateTime) -> OffsetDateTime {
-        Self::to_datetime(
-            &self.departure_time,
-            match self.departure_time > SECONDS_IN_DAY
-                && self
-                    .weekdays
-                    .is_valid(current_date_time.date().previous_day().unwrap().weekday())
-            {
-                true => current_date_time.date().previous_day().unwrap(),
-                false => current_date_time.date(),
-            },
-        )
+    ///Next departure at or after current_date_time, None if the edge doesn't run then.
+    ///Service days are dates in the time zone of the feed. Times past 24:00 belong to
+    ///the service day the trip started on, so a departure at 25:30 on Friday's service
+    ///runs early on Saturday. Service days are looked up as far back as the time spans,
+    ///and departures of the next service day are used when they are within NEXT_DAY_HORIZON.
+    pub fn departure_datetime(
+        &self,
+        current_date_time: OffsetDateTime,
+        time_zone: &TimeZone,
+    ) -> Option<OffsetDateTime> {
+        self.departure_datetime_on(current_date_time, time_zone, |_| true)
     }
+
+    ///Same as departure_datetime, but only on service dates for which runs_on returns true
+    pub(super) fn departure_datetime_on(
+        &self,
+        current_date_time: OffsetDateTime,
+        time_zone: &TimeZone,
+        runs_on: impl Fn(Date) -> bool,
+    ) -> Option<OffsetDateTime> {
+        let date = time_zone.to_local(current_date_time).date();
+        let days_spanned = (self.departure_time / SECONDS_IN_DAY) as i64;
+
+        (-days_spanned..=1)
+            .filter_map(|days| Some((days, date.checked_add(Duration::days(days))?)))
+            .filter(|(_, service_date)| self.weekdays.is_valid(service_date.weekday()))
+            .filter(|(_, service_date)| runs_on(*service_date))
+            .map(|(days, service_date)| {
+                let departure = Self::to_datetime(&self.departure_time, service_date, time_zone);
+                (days, departure)
+            })
+            .find(|(days, departure)| {
+                *departure >= current_date_time
+                    && (*days < 1 || *departure - current_date_time <= NEXT_DAY_HORIZON)
+            })
+            .map(|(_, departure)| departure)
+    }
+
+    ///Arrival at the connected stop when leaving at departure
+    pub fn arrival_datetime(&self, departure: OffsetDateTime) -> OffsetDateTime {
+        departure + Duration::seconds(self.arrival_time.saturating_sub(self.departure_time) as i64)
+    }
+
     pub fn set_day_validity(&mut self, day: Weekday, is_valid: bool) {
         match day {
             Weekday::Monday => self.weekdays.monday = is_valid,
@@ -161,71 +280,99 @@ impl Edge {
         }
     }
 
-    fn get_next_valid_date(&self, date: Date) -> Date {
-        let current_day = date.weekday();
-
-        let mut newdays: [bool; 7] = self.weekdays.into();
-
-        newdays.rotate_right(current_day.number_days_from_monday() as usize);
-        date + Duration::days(
-            newdays
-                .iter()
-                .position(|x| *x)
-                .expect("Edge should have a valid day.") as i64,
-        )
-    }
-
-    ///Sums a date and a possibly overflowing time.
-    ///Time is time of day as seconds, which can be over 24:00
-    fn to_datetime(time: &u32, date: Date) -> OffsetDateTime {
-        match time / SECONDS_IN_DAY {
-            0 => OffsetDateTime::new_utc(date, time!(00:00:00)) + Duration::seconds(*time as i64),
-            day_overflow => {
-                OffsetDateTime::new_utc(date + Duration::days(day_overflow as i64), time!(00:00:00))
-                    + Duration::seconds((time - (SECONDS_IN_DAY * day_overflow)) as i64)
-            }
-        }
+    ///Sums a service date and a possibly overflowing time.
+    ///Time is seconds from noon minus 12 hours on the service date, which can be over 24:00.
+    ///On days when clocks change the seconds still run from noon minus 12 hours.
+    fn to_datetime(time: &u32, date: Date, time_zone: &TimeZone) -> OffsetDateTime {
+        time_zone.service_day_start(date) + Duration::seconds(*time as i64)
     }
 }
 
-/// Stops are stored as HashMap with stop_id as Key.
+/// Stops are stored in a Vec and looked up by stop_id through stop_indices.
+/// Edges refer to stops by index, so the graph is immutable once built
+/// and can be shared between threads without locking.
 #[derive(Serialize)]
 pub struct GtfsGraph {
-    stops: HashMap<String, Arc<RwLock<Stop>>>,
+    stops: Vec<Arc<Stop>>,
+    #[serde(skip)]
+    stop_indices: HashMap<String, usize>,
     edges: Vec<Arc<Edge>>,
+    ///Range of stop indices added by each feed
+    #[serde(skip)]
+    feeds: Vec<Range<usize>>,
+    #[serde(skip)]
+    report: ValidationReport,
+    #[serde(skip)]
+    fares: Fares,
+    ///Routes by route_id
+    #[serde(skip)]
+    routes: HashMap<Arc<str>, Route>,
+    ///Trips by trip_id, their stop times are in the edges
+    #[serde(skip)]
+    trips: HashMap<Arc<str>, Trip>,
+    ///Dates each service of the trips runs on by service_id
+    #[serde(skip)]
+    services: HashMap<String, Service>,
+    ///Points of every shape in shapes.txt by shape_id
+    #[serde(skip)]
+    shapes: HashMap<String, Vec<Coordinates>>,
+    ///Parent stations of the stops by stop_id, stations aren't stops of the graph
+    #[serde(skip)]
+    stations: HashMap<String, Station>,
+    ///Time zone of the agencies, which stop times are in
+    #[serde(skip)]
+    time_zone: TimeZone,
 }
 
 impl GtfsGraph {
     pub fn new() -> Self {
         Self {
-            stops: HashMap::new(),
+            stops: Vec::new(),
+            stop_indices: HashMap::new(),
             edges: Vec::new(),
+            feeds: Vec::new(),
+            report: ValidationReport::default(),
+            fares: Fares::default(),
+            routes: HashMap::new(),
+            trips: HashMap::new(),
+            services: HashMap::new(),
+            shapes: HashMap::new(),
+            stations: HashMap::new(),
+            time_zone: TimeZone::utc(),
         }
     }
 
     pub fn insert_stop(&mut self, stop: gtfs_structures::Stop) -> Result<(), Error> {
-        if self.stops.contains_key(&stop.id) {
+        if self.stop_indices.contains_key(&stop.id) {
             return Err(Error::DuplicateStop(stop.id));
         }
 
-        self.stops.insert(
-            stop.id.clone(),
-            Arc::new(RwLock::new(Stop {
-                id: stop.id,
-                coordinates: Coordinates {
-                    latitude: stop
-                        .latitude
-                        .expect("GTFS DATA CONTAINS STOP WITHOUT LATITUDE, should fix lol"),
-                    longitude: stop
-                        .longitude
-                        .expect("GTFS DATA CONTAINS STOP WITHOUT LONGITUDE, should fix lol"),
-                },
-                edges: Vec::new(),
-            })),
-        );
+        let (Some(latitude), Some(longitude)) = (stop.latitude, stop.longitude) else {
+            return Err(Error::MissingCoordinates(stop.id));
+        };
+
+        self.push_stop(Stop {
+            id: stop.id,
+            coordinates: Coordinates {
+                latitude,
+                longitude,
+            },
+            edges: Vec::new(),
+            transfers: Vec::new(),
+            zone_id: stop.zone_id,
+            name: stop.name,
+            code: stop.code,
+            parent_station: stop.parent_station,
+        });
 
         Ok(())
     }
+
+    fn push_stop(&mut self, stop: Stop) {
+        self.stop_indices.insert(stop.id.clone(), self.stops.len());
+        self.stops.push(Arc::new(stop));
+    }
+
     ///Connects two stops(nodes)
     ///Valid days is an array of days for which the edge is available. First index is monday.
     pub fn connect_stops(
@@ -233,38 +380,78 @@ impl GtfsGraph {
         departure_stop_id: &str,
         departure_time: u32,
         arrival_stop_id: &str,
+        arrival_time: u32,
         weekdays: [bool; 7],
+        trip: TripStop,
     ) -> Result<(), Error> {
         let departure_stop = self
-            .stops
-            .get_mut(departure_stop_id)
-            .ok_or(Error::MissingDepartureStop(departure_stop_id.to_string()))?
-            .clone();
+            .stop_index(departure_stop_id)
+            .ok_or(Error::MissingDepartureStop(departure_stop_id.to_string()))?;
         let arrival_stop = self
-            .stops
-            .get(arrival_stop_id)
-            .ok_or(Error::MissingArrivalStop(arrival_stop_id.to_string()))?
-            .clone();
+            .stop_index(arrival_stop_id)
+            .ok_or(Error::MissingArrivalStop(arrival_stop_id.to_string()))?;
 
         let edge = Arc::new(Edge {
             departure_time,
+            arrival_time,
             connected_stop: arrival_stop,
             weekdays: weekdays.into(),
+            trip_id: trip.trip_id.clone(),
+            route_id: trip.route_id.clone(),
+            stop_sequence: trip.stop_sequence,
         });
 
-        departure_stop.write()?.edges.push(edge.clone());
+        //Only clones the stop if someone is still holding on to it from get_stop
+        Arc::make_mut(&mut self.stops[departure_stop])
+            .edges
+            .push(edge.clone());
 
         self.edges.push(edge);
 
         Ok(())
     }
 
+    pub(crate) fn stop_index(&self, id: &str) -> Option<usize> {
+        self.stop_indices.get(id).copied()
+    }
+
     ///Gets stop by its stop_id
-    pub fn get_stop(&self, id: &str) -> Option<Arc<RwLock<Stop>>> {
-        Some(self.stops.get(id)?.clone())
+    pub fn get_stop(&self, id: &str) -> Option<Arc<Stop>> {
+        Some(self.stops[self.stop_index(id)?].clone())
+    }
+
+    pub fn get_stops(&self) -> Vec<Arc<Stop>> {
+        self.stops.clone()
+    }
+
+    ///Time zone stop times are in, from agency_timezone of the first feed
+    pub fn time_zone(&self) -> &TimeZone {
+        &self.time_zone
+    }
+
+    ///Issues found in the feeds while building the graph
+    pub fn validation_report(&self) -> &ValidationReport {
+        &self.report
+    }
+
+    pub fn stop_count(&self) -> usize {
+        self.stops.len()
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.edges.len()
     }
 
-    pub fn get_stops(&self) -> Vec<Arc<RwLock<Stop>>> {
-        self.stops.values().map(|stop| stop.clone()).collect()
+    ///Finds the stop closest to the given coordinates and its distance in meters
+    pub fn nearest_stop(&self, coordinates: &Coordinates) -> Option<(Arc<Stop>, f64)> {
+        self.stops
+            .par_iter()
+            .map(|stop| {
+                (
+                    stop.clone(),
+                    stop.coordinates.haversine_distance(coordinates),
+                )
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
     }
 }
@@ -0,0 +1,298 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::Arc,
+};
+
+use serde::{Serialize, Serializer};
+use time::{Duration, OffsetDateTime};
+
+use super::{
+    dijkstras::{Arrival, StopWithDuration},
+    heatmap::WALKING_SPEED,
+    Edge, Error, GtfsGraph,
+};
+
+///Criteria of a journey to a stop. A stop can have several labels,
+///none of which is better than another in every criterion.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Label {
+    ///Travel time in seconds
+    #[serde(serialize_with = "as_seconds")]
+    pub duration: Duration,
+    pub transfers: u32,
+    ///Meters walked between stops. The graph only has walking transfers between stops of
+    ///different feeds, see connect_feeds, so this is zero within a single feed.
+    pub walking_distance: f64,
+}
+
+impl Label {
+    fn dominates(&self, other: &Label) -> bool {
+        self.duration <= other.duration
+            && self.transfers <= other.transfers
+            && self.walking_distance <= other.walking_distance
+    }
+}
+
+///Pareto optimal labels of every reached stop by stop_id
+pub type ParetoSets = HashMap<String, Vec<Label>>;
+
+pub struct ParetoOptions {
+    ///Journeys longer than this are not explored
+    pub max_duration: Duration,
+    ///Journeys with more transfers than this are not explored
+    pub max_transfers: u32,
+}
+
+impl Default for ParetoOptions {
+    fn default() -> Self {
+        Self {
+            max_duration: Duration::hours(2),
+            max_transfers: 4,
+        }
+    }
+}
+
+///Weights for reducing the labels of a stop to a single travel time
+#[derive(Debug, Clone, Copy)]
+pub struct CostWeights {
+    ///Added to the travel time for every transfer
+    pub transfer_penalty: Duration,
+    ///Multiplier for time spent walking, 1.0 counts walking like any other travel time
+    pub walk_reluctance: f64,
+}
+
+impl Default for CostWeights {
+    fn default() -> Self {
+        Self {
+            transfer_penalty: Duration::ZERO,
+            walk_reluctance: 1.0,
+        }
+    }
+}
+
+impl CostWeights {
+    pub fn cost(&self, label: &Label) -> Duration {
+        let walking = Duration::seconds_f64(label.walking_distance * WALKING_SPEED);
+
+        label.duration
+            + self.transfer_penalty * label.transfers
+            + walking * (self.walk_reluctance - 1.0)
+    }
+}
+
+///Label during the search, with the trip it is on so staying aboard is not a transfer
+struct SearchLabel {
+    stop: usize,
+    label: Label,
+    boardings: u32,
+    trip: Option<Arc<str>>,
+    dominated: bool,
+}
+
+impl SearchLabel {
+    ///Continuing from a label on another trip can cost an extra boarding,
+    ///so across trips a label only dominates with at least one boarding less.
+    fn dominates(&self, other: &SearchLabel) -> bool {
+        let boardings = match self.trip == other.trip || other.trip.is_none() {
+            true => self.boardings,
+            false => self.boardings + 1,
+        };
+
+        self.label.duration <= other.label.duration
+            && self.label.walking_distance <= other.label.walking_distance
+            && boardings <= other.boardings
+    }
+}
+
+///Earliest departing edge of each route to each stop, and the edge staying on the trip,
+///keyed by the connected stop, whether the trip continues and the route or trip id
+type Departures<'a> = HashMap<(usize, bool, &'a Arc<str>), (&'a Arc<Edge>, OffsetDateTime)>;
+
+impl GtfsGraph {
+    ///Multi criteria search minimising travel time, transfers and walking distance.
+    ///Like McRAPTOR every stop keeps a bag of labels, but labels are settled
+    ///in order of travel time over the edges of the graph instead of in rounds over routes.
+    pub fn pareto_search(
+        &self,
+        start_id: &str,
+        start_time: OffsetDateTime,
+        options: &ParetoOptions,
+    ) -> Result<ParetoSets, Error> {
+        let start = self
+            .stop_index(start_id)
+            .ok_or(Error::MissingStop(start_id.to_string()))?;
+
+        let mut labels: Vec<SearchLabel> = Vec::new();
+        let mut bags: Vec<Vec<usize>> = vec![Vec::new(); self.stops.len()];
+        let mut queue: BinaryHeap<Reverse<(Duration, usize)>> = BinaryHeap::new();
+
+        let mut insert = |labels: &mut Vec<SearchLabel>,
+                          queue: &mut BinaryHeap<Reverse<(Duration, usize)>>,
+                          candidate: SearchLabel| {
+            let bag = &mut bags[candidate.stop];
+
+            if bag.iter().any(|&i| labels[i].dominates(&candidate)) {
+                return;
+            }
+
+            bag.retain(|&i| {
+                let dominated = candidate.dominates(&labels[i]);
+                labels[i].dominated |= dominated;
+                !dominated
+            });
+
+            queue.push(Reverse((candidate.label.duration, labels.len())));
+            bag.push(labels.len());
+            labels.push(candidate);
+        };
+
+        insert(
+            &mut labels,
+            &mut queue,
+            SearchLabel {
+                stop: start,
+                label: Label {
+                    duration: Duration::ZERO,
+                    transfers: 0,
+                    walking_distance: 0.0,
+                },
+                boardings: 0,
+                trip: None,
+                dominated: false,
+            },
+        );
+
+        while let Some(Reverse((_, index))) = queue.pop() {
+            if labels[index].dominated {
+                continue;
+            }
+
+            let current = &labels[index];
+            let (stop, label, boardings, trip) = (
+                current.stop,
+                current.label,
+                current.boardings,
+                current.trip.clone(),
+            );
+            let now = start_time + label.duration;
+
+            let mut departures: Departures = HashMap::new();
+
+            for edge in self.stops[stop].edges.iter() {
+                let Some(departure) = edge.departure_datetime(now, &self.time_zone) else {
+                    continue;
+                };
+
+                let key = match trip.as_ref() == Some(&edge.trip_id) {
+                    true => (edge.connected_stop, true, &edge.trip_id),
+                    false => (edge.connected_stop, false, &edge.route_id),
+                };
+
+                if departures
+                    .get(&key)
+                    .is_none_or(|(_, earliest)| departure < *earliest)
+                {
+                    departures.insert(key, (edge, departure));
+                }
+            }
+
+            let mut candidates: Vec<SearchLabel> = Vec::new();
+
+            for (edge, departure) in departures.into_values() {
+                let boardings = match trip.as_ref() == Some(&edge.trip_id) {
+                    true => boardings,
+                    false => boardings + 1,
+                };
+
+                candidates.push(SearchLabel {
+                    stop: edge.connected_stop,
+                    label: Label {
+                        duration: edge.arrival_datetime(departure) - start_time,
+                        transfers: boardings.saturating_sub(1),
+                        walking_distance: label.walking_distance,
+                    },
+                    boardings,
+                    trip: Some(edge.trip_id.clone()),
+                    dominated: false,
+                });
+            }
+
+            for transfer in self.stops[stop].transfers.iter() {
+                candidates.push(SearchLabel {
+                    stop: transfer.stop,
+                    label: Label {
+                        duration: label.duration + transfer.duration,
+                        transfers: label.transfers,
+                        walking_distance: label.walking_distance
+                            + transfer.duration.as_seconds_f64() / WALKING_SPEED,
+                    },
+                    boardings,
+                    trip: None,
+                    dominated: false,
+                });
+            }
+
+            for candidate in candidates {
+                if candidate.label.duration <= options.max_duration
+                    && candidate.label.transfers <= options.max_transfers
+                {
+                    insert(&mut labels, &mut queue, candidate);
+                }
+            }
+        }
+
+        Ok(bags
+            .into_iter()
+            .enumerate()
+            .filter(|(_, bag)| !bag.is_empty())
+            .map(|(stop, bag)| {
+                let bag: Vec<Label> = bag.into_iter().map(|i| labels[i].label).collect();
+
+                //Labels on different trips may be kept during the search, only report the optimal ones
+                let mut optimal: Vec<Label> = Vec::new();
+                for label in bag.iter() {
+                    if !bag
+                        .iter()
+                        .any(|other| other != label && other.dominates(label))
+                        && !optimal.contains(label)
+                    {
+                        optimal.push(*label);
+                    }
+                }
+                optimal.sort_by_key(|label| label.duration);
+
+                (self.stops[stop].id.clone(), optimal)
+            })
+            .collect())
+    }
+
+    ///Reduces the labels of every stop to the lowest generalised cost, which can be
+    ///rendered like the results of dijkstras. Walking from the stops to each pixel is not weighted.
+    ///The cost of a stop may come from a different journey than the costs of the stops
+    ///before it, so the result has no itineraries.
+    pub fn generalized_cost(
+        &self,
+        sets: &ParetoSets,
+        weights: &CostWeights,
+    ) -> HashMap<String, StopWithDuration> {
+        sets.iter()
+            .filter_map(|(stop_id, labels)| {
+                let cost = labels.iter().map(|label| weights.cost(label)).min()?;
+
+                Some((
+                    stop_id.clone(),
+                    StopWithDuration {
+                        stop: self.get_stop(stop_id)?,
+                        duration: cost.max(Duration::ZERO),
+                        arrival: Some(Arrival::Combined),
+                    },
+                ))
+            })
+            .collect()
+    }
+}
+
+fn as_seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_i64(duration.whole_seconds())
+}
@@ -1,64 +1,407 @@
-use std::{
-    collections::HashMap,
-    error,
-    sync::{Arc, RwLock},
-};
+use std::{collections::HashSet, error, mem, ops::Range, sync::Arc};
+
+use chrono::Datelike;
+use gtfs_structures::{DirectionType, Exception, Gtfs, LocationType, StopTime};
+use rayon::prelude::*;
+
+use crate::{coords::Coordinates, timezone::TimeZone};
 
-use gtfs_structures::Gtfs;
+use super::{
+    departures::Service,
+    heatmap::walking_time,
+    routes::{Route, Trip},
+    stop_search::Station,
+    validation::Issue,
+    Error, GtfsGraph, Stop, Transfer, TripStop,
+};
 
-use super::{Error, GtfsGraph, Stop};
+///A GTFS feed to build a graph from.
+///With a namespace every stop, trip and route id of the feed is prefixed with "namespace:",
+///so feeds with overlapping ids can be merged.
+pub struct Feed {
+    pub namespace: Option<String>,
+    pub gtfs: Gtfs,
+}
 
 impl TryFrom<Gtfs> for GtfsGraph {
     type Error = Error;
 
-    fn try_from(mut gtfs: Gtfs) -> Result<Self, Self::Error> {
-        let mut stops: HashMap<String, Arc<RwLock<Stop>>> = HashMap::new();
-        stops.reserve(gtfs.stops.len());
-        for (id, stop) in gtfs.stops.drain() {
-            if let Ok(stop) = Arc::unwrap_or_clone(stop).try_into() {
-                stops.insert(id, Arc::new(RwLock::new(stop)));
-            }
+    fn try_from(gtfs: Gtfs) -> Result<Self, Self::Error> {
+        let mut graph = GtfsGraph::new();
+        graph.add_feed(gtfs, None)?;
+
+        Ok(graph)
+    }
+}
+
+impl GtfsGraph {
+    ///Merges feeds into one graph and connects stops of different feeds
+    ///which are at most transfer_distance meters apart with walking transfers.
+    pub fn from_feeds(feeds: Vec<Feed>, transfer_distance: f64) -> Result<Self, Error> {
+        let mut graph = GtfsGraph::new();
+
+        for feed in feeds {
+            graph.add_feed(feed.gtfs, feed.namespace.as_deref())?;
         }
 
-        let mut graph = GtfsGraph {
-            stops,
-            edges: Vec::new(),
+        graph.connect_feeds(transfer_distance);
+
+        Ok(graph)
+    }
+
+    ///Adds the stops and trips of a feed to the graph.
+    ///Malformed data is skipped and recorded in the validation report instead of failing the build.
+    pub fn add_feed(&mut self, mut gtfs: Gtfs, namespace: Option<&str>) -> Result<(), Error> {
+        let id = |id: &str| match namespace {
+            Some(namespace) => format!("{}:{}", namespace, id),
+            None => id.to_string(),
         };
 
-        for (_, trip) in gtfs.trips.drain() {
-            let mut iter = trip.stop_times.into_iter().peekable();
-
-            let calendar = gtfs
-                .calendar
-                .get(&trip.service_id)
-                .expect("Trips ServiceID should alway point to a valid instance of calendar");
-
-            let weekdays = [
-                calendar.monday,
-                calendar.tuesday,
-                calendar.wednesday,
-                calendar.thursday,
-                calendar.friday,
-                calendar.saturday,
-                calendar.sunday,
-            ];
-
-            while let Some(stop) = iter.next() {
-                let next_stop = match iter.peek() {
-                    Some(stop) => stop,
-                    None => break,
+        if let Some(agency) = gtfs.agencies.first() {
+            self.set_time_zone(&agency.timezone);
+        }
+
+        let first_stop = self.stops.len();
+        self.stops.reserve(gtfs.stops.len());
+        for (_, stop) in gtfs.stops.drain() {
+            let stop = Arc::unwrap_or_clone(stop);
+
+            if stop.location_type == LocationType::StopArea {
+                self.stations.insert(
+                    id(&stop.id),
+                    Station {
+                        id: id(&stop.id),
+                        name: stop.name.clone(),
+                    },
+                );
+            }
+
+            match Stop::try_from(stop) {
+                Ok(mut stop) => {
+                    stop.id = id(&stop.id);
+                    stop.zone_id = stop.zone_id.as_deref().map(id);
+                    stop.parent_station = stop.parent_station.as_deref().map(id);
+
+                    if self.stop_indices.contains_key(&stop.id) {
+                        return Err(Error::DuplicateStop(stop.id));
+                    }
+
+                    self.push_stop(stop);
+                }
+                Err(Error::MissingCoordinates(stop_id)) => {
+                    self.report.push(Issue::StopWithoutCoordinates {
+                        stop_id: id(&stop_id),
+                    })
+                }
+                Err(_) => {}
+            }
+        }
+        self.feeds.push(first_stop..self.stops.len());
+
+        for issue in self.fares.add_feed(&gtfs, namespace) {
+            self.report.push(issue);
+        }
+
+        for (route_id, route) in gtfs.routes.iter() {
+            self.routes.insert(
+                id(route_id).into(),
+                Route {
+                    id: id(route_id),
+                    short_name: route.short_name.clone(),
+                    long_name: route.long_name.clone(),
+                    route_type: route.route_type,
+                },
+            );
+        }
+
+        let mut visited: HashSet<usize> = HashSet::new();
+
+        for (_, trip) in mem::take(&mut gtfs.trips) {
+            let trip_id: Arc<str> = id(&trip.id).into();
+            let route_id: Arc<str> = id(&trip.route_id).into();
+
+            let Some(weekdays) = service_weekdays(&gtfs, &trip.service_id) else {
+                self.report.push(Issue::MissingService {
+                    trip_id: trip_id.to_string(),
+                    service_id: trip.service_id.clone(),
+                });
+                continue;
+            };
+
+            if !gtfs.routes.contains_key(&trip.route_id) {
+                self.report.push(Issue::MissingRoute {
+                    trip_id: trip_id.to_string(),
+                    route_id: route_id.to_string(),
+                });
+            }
+
+            self.services
+                .entry(id(&trip.service_id))
+                .or_insert_with(|| Service::from_gtfs(&gtfs, &trip.service_id));
+
+            self.trips.insert(
+                trip_id.clone(),
+                Trip {
+                    id: trip_id.to_string(),
+                    route_id: route_id.to_string(),
+                    service_id: id(&trip.service_id),
+                    headsign: trip.trip_headsign.clone(),
+                    direction_id: trip.direction_id.map(|direction| match direction {
+                        DirectionType::Outbound => 0,
+                        DirectionType::Inbound => 1,
+                    }),
+                    shape_id: trip.shape_id.as_deref().map(id),
+                },
+            );
+
+            let times = interpolate_times(
+                &trip
+                    .stop_times
+                    .iter()
+                    .map(|stop_time| stop_time.departure_time.or(stop_time.arrival_time))
+                    .collect::<Vec<_>>(),
+                &self.trip_distances(&trip.stop_times, id),
+            );
+
+            //Previous stop with a usable time as stop index, departure time and stop_sequence
+            let mut previous: Option<(usize, u32, u32)> = None;
+
+            for (stop_time, time) in trip.stop_times.iter().zip(times) {
+                let stop_id = id(&stop_time.stop.id);
+
+                let Some(stop) = self.stop_index(&stop_id) else {
+                    self.report.push(Issue::MissingStop {
+                        trip_id: trip_id.to_string(),
+                        stop_id,
+                    });
+                    continue;
                 };
 
-                graph.connect_stops(
-                    &stop.stop.id,
-                    stop.departure_time
-                        .expect("stoptime should have departure time"),
-                    &next_stop.stop.id,
-                    weekdays,
-                )?;
+                let Some(time) = time else {
+                    self.report.push(Issue::StopTimeWithoutTime {
+                        trip_id: trip_id.to_string(),
+                        stop_sequence: u32::from(stop_time.stop_sequence),
+                    });
+                    continue;
+                };
+
+                if let Some((previous_stop, previous_time, previous_sequence)) = previous {
+                    if time < previous_time {
+                        self.report.push(Issue::NonMonotonicTime {
+                            trip_id: trip_id.to_string(),
+                            stop_sequence: u32::from(stop_time.stop_sequence),
+                        });
+                        continue;
+                    }
+
+                    if previous_stop == stop {
+                        self.report.push(Issue::ZeroLengthEdge {
+                            trip_id: trip_id.to_string(),
+                            stop_sequence: u32::from(stop_time.stop_sequence),
+                        });
+                    } else {
+                        self.connect_stops(
+                            &self.stops[previous_stop].id.clone(),
+                            previous_time,
+                            &stop_id,
+                            stop_time
+                                .arrival_time
+                                .unwrap_or(time)
+                                .clamp(previous_time, time),
+                            weekdays,
+                            TripStop {
+                                trip_id: &trip_id,
+                                route_id: &route_id,
+                                stop_sequence: previous_sequence,
+                            },
+                        )?;
+                    }
+                }
+
+                visited.insert(stop);
+                previous = Some((stop, time, u32::from(stop_time.stop_sequence)));
             }
         }
 
-        Ok(graph)
+        for (shape_id, mut points) in mem::take(&mut gtfs.shapes) {
+            points.sort_by_key(|point| point.sequence);
+            self.shapes.insert(
+                id(&shape_id),
+                points
+                    .iter()
+                    .map(|point| Coordinates {
+                        latitude: point.latitude,
+                        longitude: point.longitude,
+                    })
+                    .collect(),
+            );
+        }
+
+        for stop in first_stop..self.stops.len() {
+            if !visited.contains(&stop) {
+                self.report.push(Issue::OrphanStop {
+                    stop_id: self.stops[stop].id.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    ///Cumulative distance along the trip at each stop_time. Uses shape_dist_traveled
+    ///when every stop_time has it, straight line distance between the stops otherwise.
+    fn trip_distances(&self, stop_times: &[StopTime], id: impl Fn(&str) -> String) -> Vec<f64> {
+        if stop_times
+            .iter()
+            .all(|stop_time| stop_time.shape_dist_traveled.is_some())
+        {
+            return stop_times
+                .iter()
+                .map(|stop_time| stop_time.shape_dist_traveled.unwrap_or_default() as f64)
+                .collect();
+        }
+
+        let coordinates: Vec<Option<Coordinates>> = stop_times
+            .iter()
+            .map(|stop_time| {
+                self.stop_index(&id(&stop_time.stop.id))
+                    .map(|stop| self.stops[stop].coordinates)
+            })
+            .collect();
+
+        let mut distance = 0.0;
+        let mut previous: Option<Coordinates> = None;
+
+        coordinates
+            .into_iter()
+            .map(|coordinates| {
+                if let (Some(previous), Some(coordinates)) = (previous, coordinates) {
+                    distance += previous.haversine_distance(&coordinates);
+                }
+                previous = coordinates.or(previous);
+
+                distance
+            })
+            .collect()
     }
+
+    ///The first feed decides the time zone of the graph
+    fn set_time_zone(&mut self, name: &str) {
+        if !self.feeds.is_empty() {
+            if name != self.time_zone.name() {
+                self.report.push(Issue::ConflictingTimeZone {
+                    timezone: name.to_string(),
+                });
+            }
+            return;
+        }
+
+        match TimeZone::load(name) {
+            Ok(time_zone) => self.time_zone = time_zone,
+            Err(_) => self.report.push(Issue::UnknownTimeZone {
+                timezone: name.to_string(),
+            }),
+        }
+    }
+
+    ///Adds walking transfers both ways between stops of different feeds
+    ///which are at most max_distance meters apart
+    pub fn connect_feeds(&mut self, max_distance: f64) {
+        let stops = &self.stops;
+        let mut transfers: Vec<(usize, usize, f64)> = Vec::new();
+
+        for (i, feed) in self.feeds.iter().enumerate() {
+            for other in &self.feeds[i + 1..] {
+                transfers.par_extend(feed.clone().into_par_iter().flat_map_iter(|from| {
+                    other.clone().filter_map(move |to| {
+                        let distance = stops[from]
+                            .coordinates
+                            .haversine_distance(&stops[to].coordinates);
+
+                        (distance <= max_distance).then_some((from, to, distance))
+                    })
+                }));
+            }
+        }
+
+        for (from, to, distance) in transfers {
+            for (from, to) in [(from, to), (to, from)] {
+                Arc::make_mut(&mut self.stops[from])
+                    .transfers
+                    .push(Transfer {
+                        stop: to,
+                        duration: walking_time(distance),
+                    });
+            }
+        }
+    }
+}
+
+///Fills in missing times of a trip which only has times at its timepoints.
+///Times between two known times are interpolated linearly by the cumulative distance
+///of each stop_time, or evenly by stop if the known times are at the same distance.
+///Times before the first or after the last known time cannot be interpolated and stay missing.
+pub(super) fn interpolate_times(times: &[Option<u32>], distances: &[f64]) -> Vec<Option<u32>> {
+    let mut interpolated = times.to_vec();
+    let known: Vec<usize> = (0..times.len()).filter(|&i| times[i].is_some()).collect();
+
+    for window in known.windows(2) {
+        let (start, end) = (window[0], window[1]);
+        let (start_time, end_time) = (
+            times[start].unwrap_or_default(),
+            times[end].unwrap_or_default(),
+        );
+
+        if end_time < start_time {
+            continue;
+        }
+
+        let length = distances[end] - distances[start];
+
+        for (i, time) in interpolated
+            .iter_mut()
+            .enumerate()
+            .take(end)
+            .skip(start + 1)
+        {
+            let fraction = if length > 0.0 {
+                ((distances[i] - distances[start]) / length).clamp(0.0, 1.0)
+            } else {
+                (i - start) as f64 / (end - start) as f64
+            };
+
+            *time = Some(start_time + ((end_time - start_time) as f64 * fraction).round() as u32);
+        }
+    }
+
+    interpolated
+}
+
+///Days of the week a service runs on. Services without a calendar
+///run on the weekdays of their added calendar dates.
+fn service_weekdays(gtfs: &Gtfs, service_id: &str) -> Option<[bool; 7]> {
+    if let Some(calendar) = gtfs.calendar.get(service_id) {
+        return Some([
+            calendar.monday,
+            calendar.tuesday,
+            calendar.wednesday,
+            calendar.thursday,
+            calendar.friday,
+            calendar.saturday,
+            calendar.sunday,
+        ]);
+    }
+
+    let mut weekdays = [false; 7];
+
+    for calendar_date in gtfs
+        .calendar_dates
+        .get(service_id)?
+        .iter()
+        .filter(|calendar_date| matches!(calendar_date.exception_type, Exception::Added))
+    {
+        weekdays[calendar_date.date.weekday().num_days_from_monday() as usize] = true;
+    }
+
+    weekdays.contains(&true).then_some(weekdays)
 }
@@ -0,0 +1,455 @@
+use std::{collections::HashMap, sync::Arc};
+
+use time::{macros::format_description, Date, OffsetDateTime};
+
+use crate::{
+    protobuf::{write_bytes, write_varint_field, DecodeError, Reader},
+    timezone::TimeZone,
+};
+
+use super::{scenario::ScenarioOverlay, Edge, Error, GtfsGraph};
+
+///Name of overlays built from realtime feeds
+pub const REALTIME_OVERLAY: &str = "realtime";
+
+//Field numbers and enum values from gtfs-realtime.proto
+const FEED_MESSAGE_HEADER: u32 = 1;
+const FEED_MESSAGE_ENTITY: u32 = 2;
+const FEED_HEADER_VERSION: u32 = 1;
+const FEED_HEADER_TIMESTAMP: u32 = 3;
+const FEED_ENTITY_ID: u32 = 1;
+const FEED_ENTITY_TRIP_UPDATE: u32 = 3;
+const TRIP_UPDATE_TRIP: u32 = 1;
+const TRIP_UPDATE_STOP_TIME_UPDATE: u32 = 2;
+const TRIP_UPDATE_DELAY: u32 = 5;
+const TRIP_DESCRIPTOR_TRIP_ID: u32 = 1;
+const TRIP_DESCRIPTOR_START_DATE: u32 = 3;
+const TRIP_DESCRIPTOR_SCHEDULE_RELATIONSHIP: u32 = 4;
+const TRIP_DESCRIPTOR_ROUTE_ID: u32 = 5;
+const STOP_TIME_UPDATE_STOP_SEQUENCE: u32 = 1;
+const STOP_TIME_UPDATE_ARRIVAL: u32 = 2;
+const STOP_TIME_UPDATE_DEPARTURE: u32 = 3;
+const STOP_TIME_UPDATE_STOP_ID: u32 = 4;
+const STOP_TIME_UPDATE_SCHEDULE_RELATIONSHIP: u32 = 5;
+const STOP_TIME_EVENT_DELAY: u32 = 1;
+const STOP_TIME_EVENT_TIME: u32 = 2;
+
+const TRIP_CANCELED: u64 = 3;
+const STOP_SKIPPED: u64 = 1;
+
+///The parts of a GTFS-Realtime FeedMessage used for trip updates.
+///Vehicle positions, alerts and unknown fields are skipped when decoding.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FeedMessage {
+    ///POSIX time the feed was created
+    pub timestamp: u64,
+    pub trip_updates: Vec<TripUpdate>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TripUpdate {
+    pub trip_id: String,
+    pub route_id: Option<String>,
+    ///Service date as YYYYMMDD
+    pub start_date: Option<String>,
+    pub canceled: bool,
+    ///Delay in seconds for stops without an update of their own
+    pub delay: Option<i32>,
+    pub stop_time_updates: Vec<StopTimeUpdate>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StopTimeUpdate {
+    pub stop_sequence: Option<u32>,
+    pub stop_id: Option<String>,
+    pub arrival: Option<StopTimeEvent>,
+    pub departure: Option<StopTimeEvent>,
+    pub skipped: bool,
+}
+
+///Either a delay in seconds or an absolute POSIX time
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StopTimeEvent {
+    pub delay: Option<i32>,
+    pub time: Option<i64>,
+}
+
+impl FeedMessage {
+    pub fn decode(data: &[u8]) -> Result<Self, Error> {
+        Self::decode_message(data).map_err(|err| Error::InvalidRealtime(err.to_string()))
+    }
+
+    fn decode_message(data: &[u8]) -> Result<Self, DecodeError> {
+        let mut message = FeedMessage::default();
+
+        for field in Reader::new(data) {
+            match field? {
+                (FEED_MESSAGE_HEADER, header) => {
+                    for field in Reader::new(header.as_bytes()) {
+                        if let (FEED_HEADER_TIMESTAMP, timestamp) = field? {
+                            message.timestamp = timestamp.as_u64();
+                        }
+                    }
+                }
+                (FEED_MESSAGE_ENTITY, entity) => {
+                    for field in Reader::new(entity.as_bytes()) {
+                        if let (FEED_ENTITY_TRIP_UPDATE, trip_update) = field? {
+                            message
+                                .trip_updates
+                                .push(TripUpdate::decode(trip_update.as_bytes())?);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(message)
+    }
+
+    ///Encodes the message as GTFS-Realtime 2.0, e.g. for recording fixtures
+    pub fn encode(&self) -> Vec<u8> {
+        let mut header = Vec::new();
+        write_bytes(&mut header, FEED_HEADER_VERSION, b"2.0");
+        write_varint_field(&mut header, FEED_HEADER_TIMESTAMP, self.timestamp);
+
+        let mut buf = Vec::new();
+        write_bytes(&mut buf, FEED_MESSAGE_HEADER, &header);
+
+        for (i, trip_update) in self.trip_updates.iter().enumerate() {
+            let mut entity = Vec::new();
+            write_bytes(&mut entity, FEED_ENTITY_ID, i.to_string().as_bytes());
+            write_bytes(&mut entity, FEED_ENTITY_TRIP_UPDATE, &trip_update.encode());
+
+            write_bytes(&mut buf, FEED_MESSAGE_ENTITY, &entity);
+        }
+
+        buf
+    }
+}
+
+impl TripUpdate {
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        let mut update = TripUpdate::default();
+
+        for field in Reader::new(data) {
+            match field? {
+                (TRIP_UPDATE_TRIP, trip) => {
+                    for field in Reader::new(trip.as_bytes()) {
+                        match field? {
+                            (TRIP_DESCRIPTOR_TRIP_ID, id) => update.trip_id = id.as_string()?,
+                            (TRIP_DESCRIPTOR_ROUTE_ID, id) => {
+                                update.route_id = Some(id.as_string()?)
+                            }
+                            (TRIP_DESCRIPTOR_START_DATE, date) => {
+                                update.start_date = Some(date.as_string()?)
+                            }
+                            (TRIP_DESCRIPTOR_SCHEDULE_RELATIONSHIP, relationship) => {
+                                update.canceled = relationship.as_u64() == TRIP_CANCELED
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                (TRIP_UPDATE_STOP_TIME_UPDATE, stop_time_update) => update
+                    .stop_time_updates
+                    .push(StopTimeUpdate::decode(stop_time_update.as_bytes())?),
+                (TRIP_UPDATE_DELAY, delay) => update.delay = Some(delay.as_i64() as i32),
+                _ => {}
+            }
+        }
+
+        Ok(update)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut trip = Vec::new();
+        write_bytes(&mut trip, TRIP_DESCRIPTOR_TRIP_ID, self.trip_id.as_bytes());
+        if let Some(start_date) = &self.start_date {
+            write_bytes(&mut trip, TRIP_DESCRIPTOR_START_DATE, start_date.as_bytes());
+        }
+        if self.canceled {
+            write_varint_field(
+                &mut trip,
+                TRIP_DESCRIPTOR_SCHEDULE_RELATIONSHIP,
+                TRIP_CANCELED,
+            );
+        }
+        if let Some(route_id) = &self.route_id {
+            write_bytes(&mut trip, TRIP_DESCRIPTOR_ROUTE_ID, route_id.as_bytes());
+        }
+
+        let mut buf = Vec::new();
+        write_bytes(&mut buf, TRIP_UPDATE_TRIP, &trip);
+
+        for stop_time_update in &self.stop_time_updates {
+            write_bytes(
+                &mut buf,
+                TRIP_UPDATE_STOP_TIME_UPDATE,
+                &stop_time_update.encode(),
+            );
+        }
+
+        if let Some(delay) = self.delay {
+            write_varint_field(&mut buf, TRIP_UPDATE_DELAY, delay as i64 as u64);
+        }
+
+        buf
+    }
+}
+
+impl StopTimeUpdate {
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        let mut update = StopTimeUpdate::default();
+
+        for field in Reader::new(data) {
+            match field? {
+                (STOP_TIME_UPDATE_STOP_SEQUENCE, sequence) => {
+                    update.stop_sequence = Some(sequence.as_u64() as u32)
+                }
+                (STOP_TIME_UPDATE_STOP_ID, id) => update.stop_id = Some(id.as_string()?),
+                (STOP_TIME_UPDATE_ARRIVAL, event) => {
+                    update.arrival = Some(StopTimeEvent::decode(event.as_bytes())?)
+                }
+                (STOP_TIME_UPDATE_DEPARTURE, event) => {
+                    update.departure = Some(StopTimeEvent::decode(event.as_bytes())?)
+                }
+                (STOP_TIME_UPDATE_SCHEDULE_RELATIONSHIP, relationship) => {
+                    update.skipped = relationship.as_u64() == STOP_SKIPPED
+                }
+                _ => {}
+            }
+        }
+
+        Ok(update)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        if let Some(stop_sequence) = self.stop_sequence {
+            write_varint_field(
+                &mut buf,
+                STOP_TIME_UPDATE_STOP_SEQUENCE,
+                stop_sequence as u64,
+            );
+        }
+        if let Some(arrival) = &self.arrival {
+            write_bytes(&mut buf, STOP_TIME_UPDATE_ARRIVAL, &arrival.encode());
+        }
+        if let Some(departure) = &self.departure {
+            write_bytes(&mut buf, STOP_TIME_UPDATE_DEPARTURE, &departure.encode());
+        }
+        if let Some(stop_id) = &self.stop_id {
+            write_bytes(&mut buf, STOP_TIME_UPDATE_STOP_ID, stop_id.as_bytes());
+        }
+        if self.skipped {
+            write_varint_field(
+                &mut buf,
+                STOP_TIME_UPDATE_SCHEDULE_RELATIONSHIP,
+                STOP_SKIPPED,
+            );
+        }
+
+        buf
+    }
+}
+
+impl StopTimeEvent {
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        let mut event = StopTimeEvent::default();
+
+        for field in Reader::new(data) {
+            match field? {
+                (STOP_TIME_EVENT_DELAY, delay) => event.delay = Some(delay.as_i64() as i32),
+                (STOP_TIME_EVENT_TIME, time) => event.time = Some(time.as_i64()),
+                _ => {}
+            }
+        }
+
+        Ok(event)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        if let Some(delay) = self.delay {
+            write_varint_field(&mut buf, STOP_TIME_EVENT_DELAY, delay as i64 as u64);
+        }
+        if let Some(time) = self.time {
+            write_varint_field(&mut buf, STOP_TIME_EVENT_TIME, time as u64);
+        }
+
+        buf
+    }
+}
+
+///A stop visited by a trip as reconstructed from the edges of the graph
+struct TripStop {
+    stop: usize,
+    ///None for the first stop, which has no arriving edge
+    arrival: Option<u32>,
+    ///None for the last stop, which has no departing edge
+    departure: Option<Arc<Edge>>,
+}
+
+///A stop kept in the updated trip with its delayed times
+struct DelayedStop {
+    stop: usize,
+    arrival: Option<u32>,
+    departure: Option<(u32, Arc<Edge>)>,
+}
+
+impl GtfsGraph {
+    ///Builds an overlay which applies the trip updates of a feed to the timetable.
+    ///Canceled trips are removed. Trips with updates are replaced by a copy where
+    ///delays are added to the departures and skipped stops are passed without stopping.
+    ///A delay applies to the following stops until the next update, as in the GTFS-Realtime spec.
+    ///Updates only change the trip on their start_date, or without one on the date
+    ///of the feed timestamp in the time zone of the graph.
+    ///Namespace is the one the feed was added to the graph with, see parser::Feed.
+    pub fn realtime_overlay(
+        &self,
+        feed: &FeedMessage,
+        namespace: Option<&str>,
+    ) -> Result<ScenarioOverlay, Error> {
+        let id = |id: &str| -> Arc<str> {
+            match namespace {
+                Some(namespace) => format!("{}:{}", namespace, id).into(),
+                None => id.into(),
+            }
+        };
+
+        let mut overlay = ScenarioOverlay::new(REALTIME_OVERLAY.to_string());
+        let updates: HashMap<Arc<str>, &TripUpdate> = feed
+            .trip_updates
+            .iter()
+            .map(|update| (id(&update.trip_id), update))
+            .collect();
+
+        let feed_date = OffsetDateTime::from_unix_timestamp(feed.timestamp as i64)
+            .map(|timestamp| self.time_zone.to_local(timestamp).date())
+            .map_err(|err| Error::InvalidRealtime(err.to_string()))?;
+
+        let trips = self.trip_edges(|edge| updates.contains_key(&edge.trip_id));
+
+        for (trip_id, edges) in trips {
+            let update = updates[&trip_id];
+
+            let service_date = match update.start_date.as_deref() {
+                Some(date) => Date::parse(date, format_description!("[year][month][day]"))
+                    .map_err(|err| {
+                        Error::InvalidRealtime(format!("start_date {}: {}", date, err))
+                    })?,
+                None => feed_date,
+            };
+            overlay.remove_trip_on(trip_id.clone(), service_date);
+
+            if update.canceled {
+                continue;
+            }
+
+            let mut trip_stops: Vec<TripStop> = edges
+                .iter()
+                .enumerate()
+                .map(|(i, (stop, edge))| TripStop {
+                    stop: *stop,
+                    arrival: i.checked_sub(1).map(|i| edges[i].1.arrival_time),
+                    departure: Some(edge.clone()),
+                })
+                .collect();
+            trip_stops.push(TripStop {
+                stop: edges[edges.len() - 1].1.connected_stop,
+                arrival: Some(edges[edges.len() - 1].1.arrival_time),
+                departure: None,
+            });
+
+            let mut delay = update.delay.unwrap_or(0) as i64;
+            let mut kept: Vec<DelayedStop> = Vec::new();
+
+            for trip_stop in trip_stops {
+                let stop_update = update.stop_time_updates.iter().find(|stop_update| {
+                    match (&trip_stop.departure, stop_update.stop_sequence) {
+                        (Some(edge), Some(sequence)) => edge.stop_sequence == sequence,
+                        _ => stop_update
+                            .stop_id
+                            .as_deref()
+                            .is_some_and(|stop_id| *id(stop_id) == self.stops[trip_stop.stop].id),
+                    }
+                });
+
+                if let Some(stop_update) = stop_update {
+                    if stop_update.skipped {
+                        continue;
+                    }
+
+                    let scheduled = trip_stop.departure.as_ref().map(|edge| edge.departure_time);
+
+                    if let Some(event_delay) = stop_update
+                        .departure
+                        .or(stop_update.arrival)
+                        .and_then(|event| {
+                            event_delay(&event, scheduled, service_date, &self.time_zone)
+                        })
+                    {
+                        delay = event_delay;
+                    }
+                }
+
+                let delayed = |time: u32| (time as i64 + delay).max(0) as u32;
+
+                kept.push(DelayedStop {
+                    stop: trip_stop.stop,
+                    arrival: trip_stop.arrival.map(delayed),
+                    departure: trip_stop
+                        .departure
+                        .map(|edge| (delayed(edge.departure_time), edge)),
+                });
+            }
+
+            let realtime_trip_id: Arc<str> = format!("{}:{}", trip_id, REALTIME_OVERLAY).into();
+            overlay.run_trip_only_on(realtime_trip_id.clone(), service_date);
+
+            for window in kept.windows(2) {
+                let Some((departure_time, edge)) = &window[0].departure else {
+                    continue;
+                };
+
+                overlay.add_edge(
+                    &self.stops[window[0].stop].id,
+                    Edge {
+                        departure_time: *departure_time,
+                        arrival_time: window[1]
+                            .arrival
+                            .unwrap_or(*departure_time)
+                            .max(*departure_time),
+                        connected_stop: window[1].stop,
+                        //The service date decides when the copy runs
+                        weekdays: [true; 7].into(),
+                        trip_id: realtime_trip_id.clone(),
+                        route_id: edge.route_id.clone(),
+                        stop_sequence: edge.stop_sequence,
+                    },
+                );
+            }
+        }
+
+        Ok(overlay)
+    }
+}
+
+///Delay of an event in seconds. Absolute times are compared to the scheduled time
+///on the service date, so they are only usable for stops with a scheduled departure.
+fn event_delay(
+    event: &StopTimeEvent,
+    scheduled: Option<u32>,
+    service_date: Date,
+    time_zone: &TimeZone,
+) -> Option<i64> {
+    if let Some(delay) = event.delay {
+        return Some(delay as i64);
+    }
+
+    let scheduled = Edge::to_datetime(&scheduled?, service_date, time_zone);
+    Some(event.time? - scheduled.unix_timestamp())
+}
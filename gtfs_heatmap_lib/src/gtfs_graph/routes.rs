@@ -0,0 +1,255 @@
+use std::{collections::HashMap, sync::Arc};
+
+use gtfs_structures::RouteType;
+use serde::{Serialize, Serializer};
+use serde_json::json;
+
+use crate::{coords::Coordinates, gtfs_types::seconds_to_hhmmss};
+
+use super::{Edge, Error, GtfsGraph};
+
+///Route from routes.txt
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Route {
+    pub id: String,
+    pub short_name: Option<String>,
+    pub long_name: Option<String>,
+    ///route_type as the number used in routes.txt, like 3 for buses
+    #[serde(serialize_with = "route_type_code")]
+    pub route_type: RouteType,
+}
+
+///Trip from trips.txt
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Trip {
+    pub id: String,
+    pub route_id: String,
+    pub service_id: String,
+    pub headsign: Option<String>,
+    ///0 or 1 like in trips.txt
+    pub direction_id: Option<u8>,
+    pub shape_id: Option<String>,
+}
+
+///Stop visited by a trip, with times from the start of the service day like in stop_times.txt
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TripStopTime {
+    pub stop_id: String,
+    ///None for the last stop, only departures keep their stop_sequence in the graph
+    pub stop_sequence: Option<u32>,
+    ///None for the first stop
+    pub arrival_time: Option<String>,
+    ///None for the last stop
+    pub departure_time: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TripDetails {
+    #[serde(flatten)]
+    pub trip: Trip,
+    pub stop_times: Vec<TripStopTime>,
+    ///GeoJSON Feature with a LineString from shapes.txt,
+    ///or through the stops when the trip has no shape
+    pub shape: serde_json::Value,
+}
+
+///Trip of a route with the stops it visits
+#[derive(Serialize, Debug, Clone)]
+pub struct RouteTrip {
+    #[serde(flatten)]
+    pub trip: Trip,
+    ///Departure from the first stop
+    pub departure_time: String,
+    ///Index of the stop pattern of the trip in RouteDetails::patterns
+    pub pattern: usize,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RouteDetails {
+    #[serde(flatten)]
+    pub route: Route,
+    ///Trips ordered by departure time
+    pub trips: Vec<RouteTrip>,
+    ///Distinct sequences of stop_ids visited by the trips
+    pub patterns: Vec<Vec<String>>,
+}
+
+///Edges of a trip in stop_sequence order, each with the index of the stop it departs from
+type TripPath = Vec<(usize, Arc<Edge>)>;
+
+impl GtfsGraph {
+    pub fn get_route(&self, id: &str) -> Option<&Route> {
+        self.routes.get(id)
+    }
+
+    ///Routes ordered by route_id
+    pub fn routes(&self) -> Vec<&Route> {
+        let mut routes: Vec<&Route> = self.routes.values().collect();
+        routes.sort_by(|a, b| a.id.cmp(&b.id));
+        routes
+    }
+
+    pub fn route_details(&self, id: &str) -> Result<RouteDetails, Error> {
+        let route = self
+            .get_route(id)
+            .ok_or(Error::MissingRoute(id.to_string()))?;
+
+        //Patterns are numbered in the order of the first trip following them
+        let mut trip_edges: Vec<(Arc<str>, TripPath)> = self
+            .trip_edges(|edge| &*edge.route_id == id)
+            .into_iter()
+            .collect();
+        trip_edges.sort_by(|(a, a_edges), (b, b_edges)| {
+            (a_edges[0].1.departure_time, a).cmp(&(b_edges[0].1.departure_time, b))
+        });
+
+        let mut trips: Vec<RouteTrip> = Vec::new();
+        let mut patterns: Vec<Vec<String>> = Vec::new();
+
+        for (trip_id, edges) in trip_edges {
+            let Some(trip) = self.trips.get(&trip_id) else {
+                continue;
+            };
+
+            let pattern: Vec<String> = self
+                .trip_stop_times(&edges)
+                .into_iter()
+                .map(|stop_time| stop_time.stop_id)
+                .collect();
+
+            let pattern = match patterns.iter().position(|other| *other == pattern) {
+                Some(index) => index,
+                None => {
+                    patterns.push(pattern);
+                    patterns.len() - 1
+                }
+            };
+
+            trips.push(RouteTrip {
+                trip: trip.clone(),
+                departure_time: seconds_to_hhmmss(edges[0].1.departure_time),
+                pattern,
+            });
+        }
+
+        Ok(RouteDetails {
+            route: route.clone(),
+            trips,
+            patterns,
+        })
+    }
+
+    pub fn trip_details(&self, id: &str) -> Result<TripDetails, Error> {
+        let trip = self
+            .trips
+            .get(id)
+            .ok_or(Error::MissingTrip(id.to_string()))?;
+
+        let edges = self
+            .trip_edges(|edge| &*edge.trip_id == id)
+            .remove(id)
+            .unwrap_or_default();
+        let stop_times = self.trip_stop_times(&edges);
+
+        let coordinates: Vec<Coordinates> = match trip
+            .shape_id
+            .as_ref()
+            .and_then(|shape_id| self.shapes.get(shape_id))
+        {
+            Some(shape) => shape.clone(),
+            None => stop_times
+                .iter()
+                .filter_map(|stop_time| self.get_stop(&stop_time.stop_id))
+                .map(|stop| stop.coordinates)
+                .collect(),
+        };
+
+        Ok(TripDetails {
+            trip: trip.clone(),
+            stop_times,
+            shape: json!({
+                "type": "Feature",
+                "properties": {
+                    "trip_id": trip.id,
+                    "shape_id": trip.shape_id,
+                },
+                "geometry": {
+                    "type": "LineString",
+                    "coordinates": coordinates
+                        .iter()
+                        .map(|point| [point.longitude, point.latitude])
+                        .collect::<Vec<_>>(),
+                },
+            }),
+        })
+    }
+
+    ///Edges of the trips matching the filter by trip_id
+    pub(super) fn trip_edges(
+        &self,
+        matches: impl Fn(&Edge) -> bool,
+    ) -> HashMap<Arc<str>, TripPath> {
+        let mut trips: HashMap<Arc<str>, TripPath> = HashMap::new();
+
+        for (stop_index, stop) in self.stops.iter().enumerate() {
+            for edge in stop.edges.iter().filter(|edge| matches(edge)) {
+                trips
+                    .entry(edge.trip_id.clone())
+                    .or_default()
+                    .push((stop_index, edge.clone()));
+            }
+        }
+
+        for edges in trips.values_mut() {
+            edges.sort_by_key(|(_, edge)| edge.stop_sequence);
+        }
+
+        trips
+    }
+
+    fn trip_stop_times(&self, edges: &[(usize, Arc<Edge>)]) -> Vec<TripStopTime> {
+        let mut stop_times: Vec<TripStopTime> = edges
+            .iter()
+            .enumerate()
+            .map(|(i, (stop, edge))| TripStopTime {
+                stop_id: self.stops[*stop].id.clone(),
+                stop_sequence: Some(edge.stop_sequence),
+                arrival_time: i
+                    .checked_sub(1)
+                    .map(|i| seconds_to_hhmmss(edges[i].1.arrival_time)),
+                departure_time: Some(seconds_to_hhmmss(edge.departure_time)),
+            })
+            .collect();
+
+        if let Some((_, last)) = edges.last() {
+            stop_times.push(TripStopTime {
+                stop_id: self.stops[last.connected_stop].id.clone(),
+                stop_sequence: None,
+                arrival_time: Some(seconds_to_hhmmss(last.arrival_time)),
+                departure_time: None,
+            });
+        }
+
+        stop_times
+    }
+}
+
+fn route_type_code<S: Serializer>(
+    route_type: &RouteType,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_i16(match route_type {
+        RouteType::Tramway => 0,
+        RouteType::Subway => 1,
+        RouteType::Rail => 2,
+        RouteType::Bus => 3,
+        RouteType::Ferry => 4,
+        RouteType::CableCar => 5,
+        RouteType::Gondola => 6,
+        RouteType::Funicular => 7,
+        RouteType::Coach => 200,
+        RouteType::Air => 1100,
+        RouteType::Taxi => 1500,
+        RouteType::Other(code) => *code,
+    })
+}
@@ -0,0 +1,380 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use serde::Deserialize;
+use time::Date;
+
+use crate::gtfs_types::try_parse_time;
+
+use super::{realtime::REALTIME_OVERLAY, Edge, Error, GtfsGraph};
+
+///A named set of timetable edits, usually deserialized from json.
+///
+///```json
+///{
+///    "name": "tram-15-every-5-min",
+///    "removed_routes": ["2550"],
+///    "headway_scaling": { "1015": 0.5 },
+///    "added_trips": [{
+///        "route_id": "new-tram",
+///        "stops": ["1130446", "1130438"],
+///        "departure_times": ["07:00:00", "07:04:00"],
+///        "repeat": { "headway_secs": 600, "until": "19:00:00" }
+///    }]
+///}
+///```
+#[derive(Deserialize, Debug, Clone)]
+pub struct Scenario {
+    ///Can't be "realtime", which is the name of the overlay made from GTFS-Realtime updates
+    pub name: String,
+    #[serde(default)]
+    pub removed_routes: Vec<String>,
+    ///Route id mapped to a headway multiplier.
+    ///0.5 runs the route twice as often and 2.0 runs every second trip.
+    ///Multipliers are rounded to whole trips, so ones between 2/3 and 1.5
+    ///other than 1.0 would change nothing and are rejected.
+    #[serde(default)]
+    pub headway_scaling: HashMap<String, f64>,
+    #[serde(default)]
+    pub added_trips: Vec<SyntheticTrip>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SyntheticTrip {
+    pub route_id: String,
+    ///Stop ids in the order the trip visits them
+    pub stops: Vec<String>,
+    ///Departure time from each stop as HH:MM:SS, can go over 24:00:00.
+    ///Times can't decrease along the trip.
+    pub departure_times: Vec<String>,
+    ///Days on which the trip runs. First index is monday.
+    #[serde(default = "every_day")]
+    pub weekdays: [bool; 7],
+    pub repeat: Option<Repeat>,
+}
+
+///Repeats a synthetic trip every headway_secs until the first departure would be after until.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Repeat {
+    pub headway_secs: u32,
+    pub until: String,
+}
+
+fn every_day() -> [bool; 7] {
+    [true; 7]
+}
+
+///Scenario compiled against a graph.
+///Holds only the difference to the graph, so the graph itself is never rebuilt or modified.
+pub struct ScenarioOverlay {
+    pub name: String,
+    removed_routes: HashSet<Arc<str>>,
+    removed_trips: HashSet<Arc<str>>,
+    ///Trips removed on a single service date, like trips canceled by a realtime update
+    removed_trip_dates: HashSet<(Arc<str>, Date)>,
+    ///Extra edges keyed by the id of their departure stop
+    added_edges: HashMap<String, Vec<Arc<Edge>>>,
+    ///Service date of added trips which only run on that date
+    added_trip_dates: HashMap<Arc<str>, Date>,
+}
+
+impl ScenarioOverlay {
+    pub(super) fn is_removed(&self, edge: &Edge) -> bool {
+        self.removed_routes.contains(&edge.route_id) || self.removed_trips.contains(&edge.trip_id)
+    }
+
+    ///Whether the edge runs on the service date as far as date specific changes go.
+    ///Removed routes and trips are checked by is_removed and weekdays by the edge itself.
+    pub(super) fn runs_on(&self, edge: &Edge, service_date: Date) -> bool {
+        !self
+            .removed_trip_dates
+            .contains(&(edge.trip_id.clone(), service_date))
+            && self
+                .added_trip_dates
+                .get(&edge.trip_id)
+                .is_none_or(|date| *date == service_date)
+    }
+
+    pub(super) fn added_edges(&self, stop_id: &str) -> &[Arc<Edge>] {
+        self.added_edges
+            .get(stop_id)
+            .map(|edges| edges.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub(super) fn new(name: String) -> Self {
+        Self {
+            name,
+            removed_routes: HashSet::new(),
+            removed_trips: HashSet::new(),
+            removed_trip_dates: HashSet::new(),
+            added_edges: HashMap::new(),
+            added_trip_dates: HashMap::new(),
+        }
+    }
+
+    pub(super) fn remove_trip_on(&mut self, trip_id: Arc<str>, service_date: Date) {
+        self.removed_trip_dates.insert((trip_id, service_date));
+    }
+
+    ///Limits an added trip to the service date
+    pub(super) fn run_trip_only_on(&mut self, trip_id: Arc<str>, service_date: Date) {
+        self.added_trip_dates.insert(trip_id, service_date);
+    }
+
+    pub(super) fn add_edge(&mut self, departure_stop_id: &str, edge: Edge) {
+        self.added_edges
+            .entry(departure_stop_id.to_string())
+            .or_default()
+            .push(Arc::new(edge));
+    }
+}
+
+///A trip of the base graph reconstructed from its edges
+struct TripEdges {
+    first_departure: u32,
+    edges: Vec<(String, Arc<Edge>)>,
+}
+
+///Trips by route, first stop and service days, headways are scaled within each
+type Patterns = HashMap<(Arc<str>, String, [bool; 7]), Vec<(Arc<str>, TripEdges)>>;
+
+impl GtfsGraph {
+    ///Compiles a scenario into an overlay which can be passed to dijkstras_with_scenario.
+    pub fn build_scenario(&self, scenario: &Scenario) -> Result<ScenarioOverlay, Error> {
+        if scenario.name == REALTIME_OVERLAY {
+            return Err(Error::InvalidScenario(format!(
+                "the name {} is reserved for realtime updates",
+                REALTIME_OVERLAY
+            )));
+        }
+
+        let mut overlay = ScenarioOverlay::new(scenario.name.clone());
+        overlay.removed_routes = scenario
+            .removed_routes
+            .iter()
+            .map(|id| Arc::from(id.as_str()))
+            .collect();
+
+        self.scale_headways(scenario, &mut overlay)?;
+
+        for (trip_number, trip) in scenario.added_trips.iter().enumerate() {
+            self.add_synthetic_trip(scenario, trip_number, trip, &mut overlay)?;
+        }
+
+        Ok(overlay)
+    }
+
+    ///Trips of a route are grouped by their first stop and service days,
+    ///so headways are only measured between trips following the same pattern.
+    ///Multipliers are rounded to whole trips.
+    fn scale_headways(
+        &self,
+        scenario: &Scenario,
+        overlay: &mut ScenarioOverlay,
+    ) -> Result<(), Error> {
+        if scenario.headway_scaling.is_empty() {
+            return Ok(());
+        }
+
+        if let Some((route_id, _)) = scenario
+            .headway_scaling
+            .iter()
+            .find(|(_, multiplier)| !multiplier.is_finite() || **multiplier <= 0.0)
+        {
+            return Err(Error::InvalidScenario(format!(
+                "headway multiplier for route {} must be positive",
+                route_id
+            )));
+        }
+
+        if let Some((route_id, multiplier)) =
+            scenario.headway_scaling.iter().find(|(_, multiplier)| {
+                **multiplier > 2.0 / 3.0 && **multiplier < 1.5 && **multiplier != 1.0
+            })
+        {
+            return Err(Error::InvalidScenario(format!(
+                "headway multiplier {} for route {} rounds to whole trips as no change, \
+                 it must be at most 2/3 or at least 1.5",
+                multiplier, route_id
+            )));
+        }
+
+        let mut trips: HashMap<Arc<str>, TripEdges> = HashMap::new();
+
+        for stop in self.stops.iter() {
+            for edge in stop.edges.iter() {
+                if !scenario.headway_scaling.contains_key(&*edge.route_id) {
+                    continue;
+                }
+
+                let trip = trips.entry(edge.trip_id.clone()).or_insert(TripEdges {
+                    first_departure: u32::MAX,
+                    edges: Vec::new(),
+                });
+
+                trip.first_departure = trip.first_departure.min(edge.departure_time);
+                trip.edges.push((stop.id.clone(), edge.clone()));
+            }
+        }
+
+        let mut patterns: Patterns = HashMap::new();
+
+        for (trip_id, mut trip) in trips.drain() {
+            trip.edges.sort_by_key(|(_, edge)| edge.stop_sequence);
+
+            let (first_stop, first_edge) = &trip.edges[0];
+            let key = (
+                first_edge.route_id.clone(),
+                first_stop.clone(),
+                first_edge.weekdays.into(),
+            );
+
+            patterns.entry(key).or_default().push((trip_id, trip));
+        }
+
+        for ((route_id, _, _), mut pattern) in patterns.drain() {
+            let multiplier = scenario.headway_scaling[&*route_id];
+            pattern.sort_by_key(|(_, trip)| trip.first_departure);
+
+            if multiplier >= 1.0 {
+                let keep_every = multiplier.round() as usize;
+
+                for (_, (trip_id, _)) in pattern
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| i % keep_every != 0)
+                {
+                    overlay.removed_trips.insert(trip_id.clone());
+                }
+
+                continue;
+            }
+
+            let copies = ((1.0 / multiplier).round() as u32).saturating_sub(1);
+
+            for window in pattern.windows(2) {
+                let (trip_id, trip) = &window[0];
+                let gap = window[1].1.first_departure - trip.first_departure;
+
+                for copy in 1..=copies {
+                    let shift = gap * copy / (copies + 1);
+                    let copy_id: Arc<str> =
+                        format!("{}:{}:{}", trip_id, scenario.name, copy).into();
+
+                    for (stop_id, edge) in trip.edges.iter() {
+                        overlay.add_edge(
+                            stop_id,
+                            Edge {
+                                departure_time: edge.departure_time + shift,
+                                arrival_time: edge.arrival_time + shift,
+                                connected_stop: edge.connected_stop,
+                                weekdays: edge.weekdays,
+                                trip_id: copy_id.clone(),
+                                route_id: edge.route_id.clone(),
+                                stop_sequence: edge.stop_sequence,
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_synthetic_trip(
+        &self,
+        scenario: &Scenario,
+        trip_number: usize,
+        trip: &SyntheticTrip,
+        overlay: &mut ScenarioOverlay,
+    ) -> Result<(), Error> {
+        if trip.stops.len() != trip.departure_times.len() {
+            return Err(Error::InvalidScenario(format!(
+                "added trip {} has {} stops but {} departure times",
+                trip_number,
+                trip.stops.len(),
+                trip.departure_times.len()
+            )));
+        }
+
+        let parse = |time: &str| {
+            try_parse_time(time).map_err(|_| {
+                Error::InvalidScenario(format!(
+                    "added trip {} has invalid time {}",
+                    trip_number, time
+                ))
+            })
+        };
+
+        let times = trip
+            .departure_times
+            .iter()
+            .map(|time| parse(time))
+            .collect::<Result<Vec<u32>, Error>>()?;
+
+        if let Some(i) = times.windows(2).position(|times| times[1] < times[0]) {
+            return Err(Error::InvalidScenario(format!(
+                "added trip {} departs {} before {}",
+                trip_number,
+                trip.stops[i + 1],
+                trip.stops[i]
+            )));
+        }
+
+        let Some(first_departure) = times.first().copied() else {
+            return Ok(());
+        };
+
+        let mut offsets = vec![0];
+
+        if let Some(repeat) = &trip.repeat {
+            if repeat.headway_secs == 0 {
+                return Err(Error::InvalidScenario(format!(
+                    "added trip {} repeats with zero headway",
+                    trip_number
+                )));
+            }
+
+            let until = parse(&repeat.until)?;
+            offsets.extend(
+                (1..)
+                    .map(|i| i * repeat.headway_secs)
+                    .take_while(|offset| first_departure + offset <= until),
+            );
+        }
+
+        let route_id: Arc<str> = trip.route_id.as_str().into();
+
+        for (repetition, offset) in offsets.into_iter().enumerate() {
+            let trip_id: Arc<str> =
+                format!("{}:added:{}:{}", scenario.name, trip_number, repetition).into();
+
+            for (i, stops) in trip.stops.windows(2).enumerate() {
+                if self.stop_index(&stops[0]).is_none() {
+                    return Err(Error::MissingDepartureStop(stops[0].clone()));
+                }
+
+                overlay.add_edge(
+                    &stops[0],
+                    Edge {
+                        departure_time: times[i] + offset,
+                        arrival_time: times[i + 1] + offset,
+                        connected_stop: self
+                            .stop_index(&stops[1])
+                            .ok_or(Error::MissingArrivalStop(stops[1].clone()))?,
+                        weekdays: trip.weekdays.into(),
+                        trip_id: trip_id.clone(),
+                        route_id: route_id.clone(),
+                        stop_sequence: i as u32,
+                    },
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeSet;
+
+use serde::Serialize;
+use strsim::damerau_levenshtein;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::coords::{BoundingBox, Coordinates};
+
+use super::{routes::Route, Error, GtfsGraph, Stop};
+
+///Station from stops.txt which stops can belong to
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Station {
+    pub id: String,
+    pub name: Option<String>,
+}
+
+///Stop as listed in search results
+#[derive(Serialize, Debug, Clone)]
+pub struct StopSummary {
+    pub id: String,
+    pub name: Option<String>,
+    pub code: Option<String>,
+    #[serde(flatten)]
+    pub coordinates: Coordinates,
+}
+
+impl From<&Stop> for StopSummary {
+    fn from(stop: &Stop) -> Self {
+        Self {
+            id: stop.id.clone(),
+            name: stop.name.clone(),
+            code: stop.code.clone(),
+            coordinates: stop.coordinates,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StopDetails {
+    #[serde(flatten)]
+    pub stop: StopSummary,
+    pub parent_station: Option<Station>,
+    ///Routes departing from or arriving at the stop, ordered by route_id
+    pub routes: Vec<Route>,
+}
+
+impl GtfsGraph {
+    ///Stops whose name or code starts with the query, or has a word starting with it.
+    ///Case and accents are ignored, so "toolo" finds Töölö, and longer queries
+    ///tolerate a typo per four characters. Best matches come first.
+    pub fn search_stops(&self, query: &str, limit: usize) -> Vec<StopSummary> {
+        let query = normalize(query);
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let mut matches: Vec<(usize, String, &Stop)> = self
+            .stops
+            .iter()
+            .filter_map(|stop| {
+                let name = normalize(stop.name.as_deref().unwrap_or_default());
+                let code = normalize(stop.code.as_deref().unwrap_or_default());
+
+                Some((match_score(&query, &name, &code)?, name, stop.as_ref()))
+            })
+            .collect();
+
+        matches.sort_by(|(score, name, stop), (other_score, other_name, other)| {
+            (score, name, &stop.id).cmp(&(other_score, other_name, &other.id))
+        });
+
+        matches
+            .into_iter()
+            .take(limit)
+            .map(|(_, _, stop)| stop.into())
+            .collect()
+    }
+
+    pub fn stops_within(&self, bounding_box: &BoundingBox) -> Vec<StopSummary> {
+        self.stops
+            .iter()
+            .filter(|stop| bounding_box.contains(&stop.coordinates))
+            .map(|stop| stop.as_ref().into())
+            .collect()
+    }
+
+    pub fn stop_details(&self, id: &str) -> Result<StopDetails, Error> {
+        let index = self
+            .stop_index(id)
+            .ok_or(Error::MissingStop(id.to_string()))?;
+        let stop = &self.stops[index];
+
+        let route_ids: BTreeSet<&str> = stop
+            .edges
+            .iter()
+            .chain(
+                self.edges
+                    .iter()
+                    .filter(|edge| edge.connected_stop == index),
+            )
+            .map(|edge| edge.route_id.as_ref())
+            .collect();
+
+        Ok(StopDetails {
+            stop: stop.as_ref().into(),
+            parent_station: stop.parent_station.as_ref().map(|station_id| {
+                match self.stations.get(station_id) {
+                    Some(station) => station.clone(),
+                    None => Station {
+                        id: station_id.clone(),
+                        name: None,
+                    },
+                }
+            }),
+            routes: route_ids
+                .into_iter()
+                .filter_map(|route_id| self.get_route(route_id).cloned())
+                .collect(),
+        })
+    }
+}
+
+///Lowercase without accents, so ä matches a and å matches a
+fn normalize(text: &str) -> String {
+    text.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+///Lower is better, None if the name doesn't match at all
+fn match_score(query: &str, name: &str, code: &str) -> Option<usize> {
+    if name.starts_with(query) || (!code.is_empty() && code.starts_with(query)) {
+        return Some(0);
+    }
+
+    let words = name.split(|c: char| !c.is_alphanumeric());
+
+    if words.clone().any(|word| word.starts_with(query)) {
+        return Some(1);
+    }
+
+    //Compares the query against the beginning of the name and of every word
+    let length = query.chars().count();
+    let max_typos = length / 4;
+
+    std::iter::once(name)
+        .chain(words)
+        .map(|word| {
+            let prefix: String = word.chars().take(length).collect();
+            damerau_levenshtein(query, &prefix)
+        })
+        .filter(|typos| *typos <= max_typos)
+        .min()
+        .map(|typos| 1 + typos)
+}
@@ -1,9 +1,93 @@
+use super::*;
+
+///Feed used by tests which need real data, GTFS_HEATMAP_TEST_FEED overrides the default path
+fn test_feed(default: &str) -> String {
+    std::env::var("GTFS_HEATMAP_TEST_FEED").unwrap_or(default.to_string())
+}
+
+fn test_stop(id: &str, latitude: f64, longitude: f64) -> Stop {
+    Stop {
+        id: id.to_string(),
+        coordinates: Coordinates {
+            latitude,
+            longitude,
+        },
+        edges: Vec::new(),
+        transfers: Vec::new(),
+        zone_id: None,
+        name: None,
+        code: None,
+        parent_station: None,
+    }
+}
+
+///Graph without edges from stops given as id, latitude and longitude
+fn test_graph(stops: &[(&str, f64, f64)]) -> GtfsGraph {
+    let mut graph = GtfsGraph::new();
+
+    for (id, latitude, longitude) in stops {
+        graph.push_stop(test_stop(id, *latitude, *longitude));
+    }
+
+    graph
+}
+
+///Connects the stop times of a trip, given as stop_id, arrival and departure time.
+///stop_sequence counts from 1 in the order of the stop times.
+fn add_trip(
+    graph: &mut GtfsGraph,
+    trip_id: &str,
+    route_id: &str,
+    weekdays: [bool; 7],
+    stop_times: &[(&str, u32, u32)],
+) {
+    let (trip_id, route_id): (Arc<str>, Arc<str>) = (trip_id.into(), route_id.into());
+
+    for (sequence, pair) in stop_times.windows(2).enumerate() {
+        let ((from, _, departure), (to, arrival, _)) = (pair[0], pair[1]);
+        graph
+            .connect_stops(
+                from,
+                departure,
+                to,
+                arrival,
+                weekdays,
+                TripStop {
+                    trip_id: &trip_id,
+                    route_id: &route_id,
+                    stop_sequence: sequence as u32 + 1,
+                },
+            )
+            .unwrap();
+    }
+}
+
+fn test_route(id: &str, route_type: gtfs_structures::RouteType) -> routes::Route {
+    routes::Route {
+        id: id.to_string(),
+        short_name: None,
+        long_name: None,
+        route_type,
+    }
+}
+
+fn test_trip(id: &str, route_id: &str) -> routes::Trip {
+    routes::Trip {
+        id: id.to_string(),
+        route_id: route_id.to_string(),
+        service_id: "weekdays".to_string(),
+        headsign: None,
+        direction_id: None,
+        shape_id: None,
+    }
+}
+
 #[test]
 fn to_datetime_midnight() {
     let date = date!(2003 - 5 - 16);
     let time = SECONDS_IN_DAY;
 
-    let datetime = Edge::to_datetime(&time, date);
+    let datetime = Edge::to_datetime(&time, date, &TimeZone::utc());
 
     assert_eq!(datetime, datetime!(2003 - 5 - 17 0:00 UTC));
 }
@@ -13,14 +97,66 @@ fn to_datetime_past_midnight() {
     let date = date!(2003 - 5 - 16);
     let time = SECONDS_IN_DAY + 120;
 
-    let datetime = Edge::to_datetime(&time, date);
+    let datetime = Edge::to_datetime(&time, date, &TimeZone::utc());
 
     assert_eq!(datetime, datetime!(2003 - 5 - 17 0:02 UTC))
 }
 
+#[test]
+fn to_datetime_on_daylight_saving_days() {
+    let time_zone = TimeZone::load("Europe/Helsinki").unwrap();
+    assert!(TimeZone::load("Europe/Atlantis").is_err());
+
+    //Service days are measured from noon minus 12 hours, which isn't local midnight when clocks change
+    assert_eq!(
+        time_zone.service_day_start(date!(2024 - 3 - 31)),
+        datetime!(2024 - 3 - 30 21:00 UTC)
+    );
+    assert_eq!(
+        Edge::to_datetime(&(8 * 3600), date!(2024 - 3 - 31), &time_zone),
+        datetime!(2024 - 3 - 31 5:00 UTC)
+    );
+    assert_eq!(
+        Edge::to_datetime(&(8 * 3600), date!(2024 - 10 - 27), &time_zone),
+        datetime!(2024 - 10 - 27 6:00 UTC)
+    );
+    assert_eq!(
+        time_zone.service_day_start(date!(2024 - 6 - 1)),
+        datetime!(2024 - 5 - 31 21:00 UTC)
+    );
+}
+
+#[test]
+fn departures_are_resolved_in_the_feed_time_zone() {
+    use crate::timezone::Departure;
+
+    let time_zone = TimeZone::load("Europe/Helsinki").unwrap();
+    let resolve = |value: &str| value.parse::<Departure>().unwrap().resolve(&time_zone);
+
+    assert_eq!(
+        resolve("2024-03-31T08:00"),
+        datetime!(2024 - 3 - 31 5:00 UTC)
+    );
+    assert_eq!(
+        resolve("2024-01-15T08:00:00"),
+        datetime!(2024 - 1 - 15 6:00 UTC)
+    );
+    //Repeated local times resolve to the earlier instant
+    assert_eq!(
+        resolve("2024-10-27T03:30"),
+        datetime!(2024 - 10 - 27 0:30 UTC)
+    );
+    assert_eq!(
+        resolve("2024-03-31T08:00:00+00:00"),
+        datetime!(2024 - 3 - 31 8:00 UTC)
+    );
+    assert_eq!(resolve("1711872000"), datetime!(2024 - 3 - 31 8:00 UTC));
+    assert!("next tuesday".parse::<Departure>().is_err());
+}
+
 #[test]
 fn test_get_stops() {
-    let gtfs = gtfs_structures::Gtfs::from_path("../data").unwrap();
+    let gtfs = gtfs_structures::Gtfs::from_path(test_feed("../data")).unwrap();
 
     let gtfs_graph: GtfsGraph = gtfs.try_into().unwrap();
 
@@ -29,7 +165,7 @@ fn test_get_stops() {
 
 #[test]
 fn gtfs_to_graph() -> Result<(), Box<dyn error::Error>> {
-    let mut gtfs = gtfs_structures::Gtfs::from_path("../hsl.zip")?;
+    let mut gtfs = gtfs_structures::Gtfs::from_path(test_feed("../hsl.zip"))?;
 
     let mut stops: HashMap<String, Arc<RwLock<Stop>>> = HashMap::new();
     for (id, stop) in gtfs.stops.drain() {
@@ -42,9 +178,1676 @@ fn gtfs_to_graph() -> Result<(), Box<dyn error::Error>> {
 
 #[test]
 fn parse_gtfs_to_graph() -> Result<(), Box<dyn error::Error>> {
-    let gtfs = gtfs_structures::Gtfs::from_path("../hsl.zip")?;
+    let gtfs = gtfs_structures::Gtfs::from_path(test_feed("../hsl.zip"))?;
 
     let _gtfs_graph: GtfsGraph = gtfs.try_into()?;
 
     Ok(())
 }
+
+#[test]
+fn parse_opportunities_csv_with_weights() {
+    let csv = "name,lat,lon,jobs\nkamppi,60.1690,24.9320,1200\npasila,60.1990,24.9330,800\n";
+
+    let opportunities = accessibility::parse_opportunities_csv(csv, "jobs").unwrap();
+
+    assert_eq!(opportunities.len(), 2);
+    assert_eq!(opportunities[1].coordinates.latitude, 60.1990);
+    assert_eq!(opportunities[1].weight, 800.0);
+}
+
+#[test]
+fn travel_time_matrix_from_places_csv() {
+    use matrix::{parse_places_csv, Location, MatrixOptions};
+
+    let graph = three_stop_graph();
+    let origins = parse_places_csv("ID,stop_id\n\"kamppi \"\"A\"\"\",A\n").unwrap();
+    let destinations = parse_places_csv("id,stop_id,lat,lon\nc,C,,\nfar,,61.0,25.0\n").unwrap();
+
+    assert_eq!(origins[0].id, "kamppi \"A\"");
+    assert!(matches!(&destinations[1].location, Location::Coordinates(c) if c.latitude == 61.0));
+    assert!(parse_places_csv("id,stop_id\nmissing,\n").is_err());
+
+    let options = MatrixOptions {
+        start_time: datetime!(2024 - 10 - 14 7:55 UTC),
+        window: Duration::ZERO,
+        step: Duration::minutes(1),
+        threads: 1,
+    };
+    let matrix = graph
+        .travel_time_matrix(&origins[..], &destinations, &options)
+        .unwrap();
+
+    assert_eq!(matrix.times[0][0], Some(Duration::minutes(25)));
+    assert_eq!(
+        matrix.to_csv(),
+        "origin,destination,travel_time_seconds\nkamppi \"A\",c,1500\nkamppi \"A\",far,\n"
+    );
+}
+
+#[test]
+fn decay_function_cutoff() {
+    use accessibility::DecayFunction;
+
+    let cutoff = Duration::minutes(30);
+
+    assert_eq!(
+        DecayFunction::Step.factor(Duration::minutes(30), cutoff),
+        1.0
+    );
+    assert_eq!(
+        DecayFunction::Step.factor(Duration::minutes(31), cutoff),
+        0.0
+    );
+    assert_eq!(
+        DecayFunction::Linear.factor(Duration::minutes(15), cutoff),
+        0.5
+    );
+    assert_eq!(
+        DecayFunction::Linear.factor(Duration::ZERO, Duration::ZERO),
+        1.0
+    );
+    assert_eq!(
+        DecayFunction::Exponential {
+            half_life: Duration::minutes(10)
+        }
+        .factor(Duration::minutes(10), cutoff),
+        0.5
+    );
+}
+
+#[test]
+fn geotiff_is_georeferenced() {
+    use crate::geotiff::{Crs, Raster};
+    use tiff::{
+        decoder::{Decoder, DecodingResult},
+        tags::Tag,
+    };
+
+    let raster = Raster {
+        crs: Crs::WebMercator,
+        width: 2,
+        height: 1,
+        origin: (2_777_000.0, 8_440_000.0),
+        pixel_size: 19.0,
+        values: vec![600.0, f32::NAN],
+    };
+
+    let mut decoder = Decoder::new(std::io::Cursor::new(raster.to_geotiff().unwrap())).unwrap();
+
+    assert_eq!(decoder.dimensions().unwrap(), (2, 1));
+    assert_eq!(
+        decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag).unwrap(),
+        vec![19.0, 19.0, 0.0]
+    );
+    assert_eq!(
+        decoder.get_tag_f64_vec(Tag::ModelTiepointTag).unwrap()[3..5],
+        [2_777_000.0, 8_440_000.0]
+    );
+    assert_eq!(
+        decoder.get_tag_u16_vec(Tag::GeoKeyDirectoryTag).unwrap()[15],
+        3857
+    );
+
+    let DecodingResult::F32(values) = decoder.read_image().unwrap() else {
+        panic!("expected float32 samples");
+    };
+    assert_eq!(values[0], 600.0);
+    assert!(values[1].is_nan());
+}
+
+#[test]
+fn travel_time_raster_is_georeferenced_on_the_search() {
+    use crate::{coords::BoundingBox, geotiff::Crs};
+    use tiff::{
+        decoder::{Decoder, DecodingResult},
+        tags::Tag,
+    };
+
+    let graph = three_stop_graph();
+    let stop_times = graph
+        .dijkstras("A", datetime!(2024-10-14 07:55 UTC))
+        .unwrap();
+    let bounding_box = BoundingBox {
+        min: Coordinates {
+            latitude: 59.999,
+            longitude: 24.999,
+        },
+        max: Coordinates {
+            latitude: 60.001,
+            longitude: 25.001,
+        },
+    };
+
+    //At zoom 10 a pixel is 360 / 2^18 degrees, the box is snapped out to 3x3 pixels
+    let pixel_size = 360.0 / f64::from(1 << 18);
+    let raster = graph.travel_time_raster(&bounding_box, Crs::Wgs84, 10, &stop_times);
+    let mut decoder = Decoder::new(std::io::Cursor::new(raster.to_geotiff().unwrap())).unwrap();
+
+    assert_eq!(decoder.dimensions().unwrap(), (3, 3));
+    assert_eq!(
+        decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag).unwrap(),
+        vec![pixel_size, pixel_size, 0.0]
+    );
+    assert_eq!(
+        decoder.get_tag_f64_vec(Tag::ModelTiepointTag).unwrap()[3..5],
+        [18_203.0 * pixel_size, 43_692.0 * pixel_size]
+    );
+
+    let DecodingResult::F32(values) = decoder.read_image().unwrap() else {
+        panic!("expected float32 samples");
+    };
+    //A lies in the center pixel, reached at the start of the search
+    let center = raster.pixel_center(1, 1);
+    let walk =
+        heatmap::walking_time(center.haversine_distance(&graph.get_stop("A").unwrap().coordinates));
+    assert_eq!(values[4], walk.as_seconds_f32());
+    assert!(values[4] > 0.0 && values[4] < 60.0);
+    assert!(values.iter().all(|value| *value > 0.0 && *value < 600.0));
+}
+
+#[test]
+fn isochrone_rings_have_holes() {
+    //3x3 block of filled cells with the center missing, inside a 5x5 grid
+    let side = 5;
+    let filled: Vec<bool> = (0..side * side)
+        .map(|i| {
+            let (column, row) = (i % side, i / side);
+            (1..=3).contains(&column) && (1..=3).contains(&row) && !(column == 2 && row == 2)
+        })
+        .collect();
+
+    let rings = vector_tile::trace_rings(&filled, side);
+    let area = |ring: &Vec<(i32, i32)>| -> i64 {
+        ring.iter()
+            .zip(ring.iter().cycle().skip(1))
+            .map(|(a, b)| a.0 as i64 * b.1 as i64 - b.0 as i64 * a.1 as i64)
+            .sum()
+    };
+
+    assert_eq!(rings.len(), 2);
+    assert_eq!(rings[0].len(), 4);
+    assert_eq!(rings[1].len(), 4);
+    assert_eq!(area(&rings[0]), 2 * 192 * 192);
+    assert_eq!(area(&rings[1]), -2 * 64 * 64);
+}
+
+#[test]
+fn vector_tiles_decode_to_stop_points_and_isochrones() {
+    use crate::protobuf::{FieldValue, Reader};
+
+    ///Fields of a message by field number, in the order they were written
+    fn fields(data: &[u8]) -> Vec<(u32, FieldValue<'_>)> {
+        Reader::new(data).collect::<Result<_, _>>().unwrap()
+    }
+
+    fn packed(mut data: &[u8]) -> Vec<u32> {
+        let mut values = Vec::new();
+        while !data.is_empty() {
+            let (mut value, mut shift) = (0, 0);
+            while let Some((&byte, rest)) = data.split_first() {
+                data = rest;
+                value |= ((byte & 0x7f) as u32) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            values.push(value);
+        }
+        values
+    }
+
+    let graph = three_stop_graph();
+    let stop_times = graph
+        .dijkstras("A", datetime!(2024 - 10 - 14 7:55 UTC))
+        .unwrap();
+    let tile = graph.get_stop("A").unwrap().coordinates.as_tile(12);
+
+    let data = graph.generate_vector_tile(
+        tile.zoom,
+        tile.x,
+        tile.y,
+        &stop_times,
+        &[Duration::minutes(15)],
+    );
+
+    let layers: Vec<Vec<(u32, FieldValue)>> = fields(&data)
+        .into_iter()
+        .map(|(field, layer)| {
+            assert_eq!(field, 3);
+            fields(layer.as_bytes())
+        })
+        .collect();
+    fn layer_field<'a>(layer: &[(u32, FieldValue<'a>)], number: u32) -> Vec<&'a [u8]> {
+        layer
+            .iter()
+            .filter(|(field, _)| *field == number)
+            .map(|(_, value)| value.as_bytes())
+            .collect()
+    }
+    //Type and geometry commands of the only feature of a layer
+    let feature = |layer: &[(u32, FieldValue)]| {
+        let features = layer_field(layer, 2);
+        assert_eq!(features.len(), 1);
+        let feature = fields(features[0]);
+        let geometry_type = feature
+            .iter()
+            .find(|(field, _)| *field == 3)
+            .unwrap()
+            .1
+            .as_u64();
+        let geometry = feature
+            .iter()
+            .find(|(field, _)| *field == 4)
+            .unwrap()
+            .1
+            .as_bytes();
+
+        (geometry_type, packed(geometry))
+    };
+
+    assert_eq!(layers.len(), 2);
+    for (layer, name) in layers.iter().zip(["stops", "isochrones"]) {
+        assert_eq!(layer_field(layer, 1), vec![name.as_bytes()]);
+        let extent = layer.iter().find(|(field, _)| *field == 5).unwrap();
+        assert_eq!(extent.1.as_u64(), 4096);
+    }
+    assert_eq!(
+        layer_field(&layers[0], 3),
+        vec![b"stop_id".as_slice(), b"travel_time".as_slice()]
+    );
+
+    //A is the only stop of the tile, a MoveTo its position relative to the tile
+    let (x, y) = tile.relative_position(&graph.get_stop("A").unwrap().coordinates);
+    let zigzag = |value: f64| ((value * 4096.0) as u32) << 1;
+    assert_eq!(
+        feature(&layers[0]),
+        (1, vec![1 | 1 << 3, zigzag(x), zigzag(y)])
+    );
+
+    //Polygons start with a single MoveTo, then a LineTo and end with a ClosePath
+    let (geometry_type, commands) = feature(&layers[1]);
+    assert_eq!(geometry_type, 3);
+    assert_eq!(commands[0], 1 | 1 << 3);
+    assert_eq!(commands[3] & 0x7, 2);
+    assert_eq!(commands.last(), Some(&(7 | 1 << 3)));
+}
+
+///Three stops A -> B -> C served by trip t1 at 08:00 and 08:10 every day
+fn three_stop_graph() -> GtfsGraph {
+    let mut graph = test_graph(&[("A", 60.0, 25.0), ("B", 60.1, 25.0), ("C", 60.2, 25.0)]);
+    add_trip(
+        &mut graph,
+        "t1",
+        "r1",
+        [true; 7],
+        &[
+            ("A", 8 * 3600, 8 * 3600),
+            ("B", 8 * 3600 + 540, 8 * 3600 + 600),
+            ("C", 8 * 3600 + 1200, 8 * 3600 + 1200),
+        ],
+    );
+
+    graph
+}
+
+#[test]
+fn realtime_feed_round_trips() {
+    use realtime::{FeedMessage, StopTimeEvent, StopTimeUpdate, TripUpdate};
+
+    let feed = FeedMessage {
+        timestamp: 1_700_000_000,
+        trip_updates: vec![TripUpdate {
+            trip_id: "t1".to_string(),
+            start_date: Some("20241018".to_string()),
+            delay: Some(-30),
+            stop_time_updates: vec![StopTimeUpdate {
+                stop_sequence: Some(2),
+                departure: Some(StopTimeEvent {
+                    delay: Some(120),
+                    time: None,
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+    };
+
+    assert_eq!(FeedMessage::decode(&feed.encode()).unwrap(), feed);
+    assert!(FeedMessage::decode(&[0x0a, 0x05, 0x01]).is_err());
+}
+
+#[test]
+fn realtime_overlay_applies_delays_and_skips() {
+    use realtime::{FeedMessage, StopTimeEvent, StopTimeUpdate, TripUpdate};
+
+    let graph = three_stop_graph();
+    let feed = FeedMessage::decode(
+        &FeedMessage {
+            timestamp: 0,
+            trip_updates: vec![TripUpdate {
+                trip_id: "t1".to_string(),
+                start_date: Some("20241018".to_string()),
+                stop_time_updates: vec![
+                    StopTimeUpdate {
+                        stop_sequence: Some(1),
+                        departure: Some(StopTimeEvent {
+                            delay: Some(300),
+                            time: None,
+                        }),
+                        ..Default::default()
+                    },
+                    StopTimeUpdate {
+                        stop_sequence: Some(2),
+                        skipped: true,
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            }],
+        }
+        .encode(),
+    )
+    .unwrap();
+
+    let overlay = graph.realtime_overlay(&feed, None).unwrap();
+    let original = &graph.get_stop("A").unwrap().edges[0];
+    let added = overlay.added_edges("A");
+
+    assert!(!overlay.runs_on(original, date!(2024 - 10 - 18)));
+    assert!(overlay.runs_on(original, date!(2024 - 10 - 19)));
+    assert_eq!(added.len(), 1);
+    assert_eq!(added[0].departure_time, 8 * 3600 + 300);
+    assert_eq!(graph.stops[added[0].connected_stop].id, "C");
+    assert!(overlay.added_edges("B").is_empty());
+}
+
+#[test]
+fn realtime_overlay_removes_canceled_trips() {
+    use realtime::{FeedMessage, TripUpdate};
+
+    let graph = three_stop_graph();
+    let feed = FeedMessage {
+        timestamp: 0,
+        trip_updates: vec![TripUpdate {
+            trip_id: "t1".to_string(),
+            canceled: true,
+            ..Default::default()
+        }],
+    };
+
+    let overlay = graph.realtime_overlay(&feed, None).unwrap();
+
+    //Without a start_date the update is for the date of the feed timestamp
+    assert!(!overlay.runs_on(
+        &graph.get_stop("B").unwrap().edges[0],
+        date!(1970 - 01 - 01)
+    ));
+    assert!(overlay.added_edges("A").is_empty());
+}
+
+#[test]
+fn realtime_updates_only_change_their_service_date() {
+    use realtime::{FeedMessage, StopTimeEvent, StopTimeUpdate, TripUpdate};
+
+    let graph = three_stop_graph();
+    let feed = FeedMessage {
+        timestamp: 0,
+        trip_updates: vec![TripUpdate {
+            trip_id: "t1".to_string(),
+            start_date: Some("20241018".to_string()),
+            stop_time_updates: vec![StopTimeUpdate {
+                stop_sequence: Some(1),
+                departure: Some(StopTimeEvent {
+                    delay: Some(300),
+                    time: None,
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+    };
+    let overlay = graph.realtime_overlay(&feed, None).unwrap();
+
+    let arrival = |start: OffsetDateTime, overlay: &scenario::ScenarioOverlay| {
+        start
+            + graph
+                .dijkstras_with_scenario("A", start, Some(overlay))
+                .unwrap()["C"]
+                .duration
+    };
+
+    //Delayed on the 18th, on time on the 19th
+    assert_eq!(
+        arrival(datetime!(2024 - 10 - 18 7:55 UTC), &overlay),
+        datetime!(2024 - 10 - 18 8:25 UTC)
+    );
+    assert_eq!(
+        arrival(datetime!(2024 - 10 - 19 7:55 UTC), &overlay),
+        datetime!(2024 - 10 - 19 8:20 UTC)
+    );
+
+    //Canceled on the 18th, the trip on the 19th is too far away to wait for but still runs
+    let canceled = graph
+        .realtime_overlay(
+            &FeedMessage {
+                timestamp: 0,
+                trip_updates: vec![TripUpdate {
+                    trip_id: "t1".to_string(),
+                    start_date: Some("20241018".to_string()),
+                    canceled: true,
+                    ..Default::default()
+                }],
+            },
+            None,
+        )
+        .unwrap();
+    assert!(!graph
+        .dijkstras_with_scenario("A", datetime!(2024 - 10 - 18 7:55 UTC), Some(&canceled))
+        .unwrap()
+        .contains_key("C"));
+    assert_eq!(
+        arrival(datetime!(2024 - 10 - 19 7:55 UTC), &canceled),
+        datetime!(2024 - 10 - 19 8:20 UTC)
+    );
+
+    assert!(matches!(
+        graph.realtime_overlay(
+            &FeedMessage {
+                timestamp: 0,
+                trip_updates: vec![TripUpdate {
+                    trip_id: "t1".to_string(),
+                    start_date: Some("2024-10-18".to_string()),
+                    ..Default::default()
+                }],
+            },
+            None,
+        ),
+        Err(Error::InvalidRealtime(_))
+    ));
+}
+
+#[test]
+fn malformed_stops_are_reported_instead_of_panicking() {
+    let stop = |id: &str, latitude: Option<f64>| {
+        Arc::new(gtfs_structures::Stop {
+            id: id.to_string(),
+            latitude,
+            longitude: Some(24.9),
+            ..Default::default()
+        })
+    };
+
+    let mut gtfs = gtfs_structures::Gtfs::default();
+    gtfs.stops.insert("A".to_string(), stop("A", Some(60.2)));
+    gtfs.stops.insert("B".to_string(), stop("B", None));
+
+    let graph: GtfsGraph = gtfs.try_into().unwrap();
+    let report = graph.validation_report();
+
+    assert_eq!(graph.stop_count(), 1);
+    assert!(report
+        .issues
+        .contains(&validation::Issue::StopWithoutCoordinates {
+            stop_id: "B".to_string()
+        }));
+    assert_eq!(report.summary()["orphan_stop"], 1);
+
+    assert!(matches!(
+        GtfsGraph::new().insert_stop(Arc::unwrap_or_clone(stop("C", None))),
+        Err(Error::MissingCoordinates(_))
+    ));
+}
+
+#[test]
+fn missing_times_are_interpolated_by_distance() {
+    let times = [Some(100), None, None, Some(400), None];
+
+    assert_eq!(
+        parser::interpolate_times(&times, &[0.0, 500.0, 1000.0, 3000.0, 3500.0]),
+        vec![Some(100), Some(150), Some(200), Some(400), None]
+    );
+    assert_eq!(
+        parser::interpolate_times(&times, &[0.0, 0.0, 0.0, 0.0, 0.0]),
+        vec![Some(100), Some(200), Some(300), Some(400), None]
+    );
+}
+
+#[test]
+fn itinerary_merges_legs_of_the_same_trip() {
+    let mut graph = three_stop_graph();
+    graph.push_stop(test_stop("D", 60.2, 25.001));
+    Arc::make_mut(&mut graph.stops[2]).transfers.push(Transfer {
+        stop: 3,
+        duration: Duration::minutes(2),
+    });
+
+    let start = datetime!(2024-01-01 07:55 UTC);
+    let times = graph.dijkstras("A", start).unwrap();
+    let itinerary = graph.itinerary(&times, "D").unwrap();
+
+    assert_eq!(itinerary.duration, 27 * 60);
+    assert_eq!(
+        itinerary.legs,
+        vec![
+            itinerary::Leg::Transit {
+                route_id: "r1".to_string(),
+                trip_id: "t1".to_string(),
+                from_stop: "A".to_string(),
+                to_stop: "C".to_string(),
+                departure: datetime!(2024-01-01 08:00 UTC),
+                arrival: datetime!(2024-01-01 08:20 UTC),
+            },
+            itinerary::Leg::Walk {
+                from_stop: "C".to_string(),
+                to_stop: "D".to_string(),
+                duration: 120,
+            },
+        ]
+    );
+    assert!(matches!(
+        graph.itinerary(&times, "E"),
+        Err(Error::MissingStop(_))
+    ));
+}
+
+#[test]
+fn pareto_search_keeps_faster_journey_with_transfer() {
+    let mut graph = three_stop_graph();
+    add_trip(
+        &mut graph,
+        "t2",
+        "r2",
+        [true; 7],
+        &[
+            ("A", 8 * 3600, 8 * 3600),
+            ("B", 8 * 3600 + 300, 8 * 3600 + 300),
+        ],
+    );
+    add_trip(
+        &mut graph,
+        "t3",
+        "r3",
+        [true; 7],
+        &[
+            ("B", 8 * 3600 + 360, 8 * 3600 + 360),
+            ("C", 8 * 3600 + 720, 8 * 3600 + 720),
+        ],
+    );
+
+    let sets = graph
+        .pareto_search(
+            "A",
+            datetime!(2024-01-01 07:55 UTC),
+            &pareto::ParetoOptions::default(),
+        )
+        .unwrap();
+
+    let journeys: Vec<(i64, u32)> = sets["C"]
+        .iter()
+        .map(|label| (label.duration.whole_minutes(), label.transfers))
+        .collect();
+    assert_eq!(journeys, vec![(17, 1), (25, 0)]);
+
+    let cost = |transfer_penalty| {
+        graph.generalized_cost(
+            &sets,
+            &pareto::CostWeights {
+                transfer_penalty,
+                walk_reluctance: 1.0,
+            },
+        )["C"]
+            .duration()
+            .whole_minutes()
+    };
+    assert_eq!(cost(Duration::ZERO), 17);
+    assert_eq!(cost(Duration::minutes(10)), 25);
+}
+
+#[test]
+fn pareto_search_counts_walking_between_feeds() {
+    let mut graph = three_stop_graph();
+    graph.push_stop(test_stop("D", 60.2, 25.001));
+    graph.feeds = vec![0..3, 3..4];
+    graph.connect_feeds(200.0);
+
+    let sets = graph
+        .pareto_search(
+            "A",
+            datetime!(2024-01-01 07:55 UTC),
+            &pareto::ParetoOptions::default(),
+        )
+        .unwrap();
+
+    let distance = graph.stops[2]
+        .coordinates
+        .haversine_distance(&graph.stops[3].coordinates);
+    assert_eq!(sets["D"].len(), 1);
+    assert!((sets["D"][0].walking_distance - distance).abs() < 1.0);
+
+    let reluctant = graph.generalized_cost(
+        &sets,
+        &pareto::CostWeights {
+            transfer_penalty: Duration::ZERO,
+            walk_reluctance: 2.0,
+        },
+    );
+    assert_eq!(
+        reluctant["D"].duration() - sets["D"][0].duration,
+        heatmap::walking_time(distance)
+    );
+    assert!(matches!(
+        graph.itinerary(&reluctant, "D"),
+        Err(Error::NoItinerary(_))
+    ));
+}
+
+#[test]
+fn fares_follow_zones() {
+    let mut graph = three_stop_graph();
+    for (stop, zone) in [(0, "A"), (1, "A"), (2, "B")] {
+        Arc::make_mut(&mut graph.stops[stop]).zone_id = Some(zone.to_string());
+    }
+
+    let mut gtfs = gtfs_structures::Gtfs::default();
+    for (fare_id, price, zones) in [("A", "2.95", vec!["A"]), ("AB", "3.20", vec!["A", "B"])] {
+        gtfs.fare_attributes.insert(
+            fare_id.to_string(),
+            gtfs_structures::FareAttribute {
+                id: fare_id.to_string(),
+                price: price.to_string(),
+                currency: "EUR".to_string(),
+                payment_method: gtfs_structures::PaymentMethod::PreBoarding,
+                transfers: gtfs_structures::Transfers::Unlimited,
+                agency_id: None,
+                transfer_duration: Some(80 * 60),
+            },
+        );
+        gtfs.fare_rules.insert(
+            fare_id.to_string(),
+            zones
+                .into_iter()
+                .map(|zone| gtfs_structures::FareRule {
+                    fare_id: fare_id.to_string(),
+                    route_id: None,
+                    origin_id: None,
+                    destination_id: None,
+                    contains_id: Some(zone.to_string()),
+                })
+                .collect(),
+        );
+    }
+    assert!(graph.fares.add_feed(&gtfs, None).is_empty());
+
+    let start = datetime!(2024-01-01 07:55 UTC);
+    let times = graph.dijkstras("A", start).unwrap();
+    let fare_ids = |stop| {
+        graph
+            .itinerary(&times, stop)
+            .unwrap()
+            .fare
+            .unwrap()
+            .fare_ids
+    };
+    assert_eq!(fare_ids("B"), vec!["A".to_string()]);
+    assert_eq!(fare_ids("C"), vec!["AB".to_string()]);
+
+    let reaches = graph.fare_searches("A", start).unwrap();
+    assert_eq!(reaches[0].fare.id, "A");
+    assert!(!reaches[0].stop_times.contains_key("C"));
+
+    let within_budget = fares::reachable_within_budget(&reaches, 3.0);
+    assert!(within_budget.contains_key("B") && !within_budget.contains_key("C"));
+    assert!(fares::reachable_within_budget(&reaches, 3.2).contains_key("C"));
+    assert!(matches!(
+        graph.itinerary(&within_budget, "B"),
+        Err(Error::NoItinerary(_))
+    ));
+    assert!(graph
+        .itinerary(&within_budget, "A")
+        .unwrap()
+        .legs
+        .is_empty());
+
+    //Shaded by the cheapest fare reaching the pixel, 2.95 of the most expensive 3.20
+    let zoom = 12;
+    let pixel_at = |stop: &str, cutoff| {
+        let coordinates = graph.get_stop(stop).unwrap().coordinates;
+        let tile = coordinates.as_tile(zoom);
+        let (x, y) = coordinates.as_global_pixel(zoom);
+
+        graph
+            .generate_fare_tile(zoom, tile.x, tile.y, &reaches, cutoff)
+            .get_pixel(x % 256, y % 256)
+            .0[0]
+    };
+    assert_eq!(pixel_at("A", Duration::minutes(60)), 235);
+    assert_eq!(pixel_at("B", Duration::minutes(60)), 235);
+    assert_eq!(pixel_at("C", Duration::minutes(60)), 255);
+    assert_eq!(pixel_at("B", Duration::minutes(5)), 255);
+}
+
+///Night bus A -> B at 25:30 on Friday and Saturday services,
+///and A -> C at 49:00 on Friday services
+fn night_bus_graph() -> GtfsGraph {
+    let mut graph = test_graph(&[("A", 60.0, 25.0), ("B", 61.0, 25.0), ("C", 62.0, 25.0)]);
+
+    let weekend_nights = [false, false, false, false, true, true, false];
+    let friday = [false, false, false, false, true, false, false];
+
+    add_trip(
+        &mut graph,
+        "n1",
+        "night",
+        weekend_nights,
+        &[
+            ("A", 25 * 3600 + 1800, 25 * 3600 + 1800),
+            ("B", 25 * 3600 + 2700, 25 * 3600 + 2700),
+        ],
+    );
+    add_trip(
+        &mut graph,
+        "n2",
+        "night",
+        friday,
+        &[
+            ("A", 49 * 3600, 49 * 3600),
+            ("C", 49 * 3600 + 600, 49 * 3600 + 600),
+        ],
+    );
+
+    graph
+}
+
+#[test]
+fn fares_in_different_currencies_are_not_combined() {
+    use fares::Ride;
+
+    let mut gtfs = gtfs_structures::Gtfs::default();
+    for (fare_id, price, currency, routes) in [
+        ("eur", "2.00", "EUR", vec!["r1"]),
+        ("eur_all", "5.00", "EUR", vec!["r1", "r2"]),
+        ("sek", "1.00", "SEK", vec!["r2"]),
+    ] {
+        gtfs.fare_attributes.insert(
+            fare_id.to_string(),
+            gtfs_structures::FareAttribute {
+                id: fare_id.to_string(),
+                price: price.to_string(),
+                currency: currency.to_string(),
+                payment_method: gtfs_structures::PaymentMethod::PreBoarding,
+                transfers: gtfs_structures::Transfers::Unlimited,
+                agency_id: None,
+                transfer_duration: None,
+            },
+        );
+        gtfs.fare_rules.insert(
+            fare_id.to_string(),
+            routes
+                .into_iter()
+                .map(|route_id| gtfs_structures::FareRule {
+                    fare_id: fare_id.to_string(),
+                    route_id: Some(route_id.to_string()),
+                    origin_id: None,
+                    destination_id: None,
+                    contains_id: None,
+                })
+                .collect(),
+        );
+    }
+
+    let mut fares = fares::Fares::default();
+    assert!(fares.add_feed(&gtfs, None).is_empty());
+
+    let ride = |route_id: &str| Ride {
+        route_id: route_id.to_string(),
+        departure: datetime!(2024-01-01 08:00 UTC),
+        zones: vec![None, None],
+    };
+
+    //eur and sek would be cheaper together, but their prices can't be added up
+    let fare = fares.cheapest(&[ride("r1"), ride("r2")]).unwrap();
+    assert_eq!(fare.fare_ids, vec!["eur_all".to_string()]);
+    assert_eq!((fare.price, fare.currency.as_str()), (5.0, "EUR"));
+
+    let fare = fares.cheapest(&[ride("r2")]).unwrap();
+    assert_eq!(fare.currency, "EUR");
+    assert!(fares.cheapest(&[ride("r3")]).is_none());
+}
+#[test]
+fn night_buses_run_on_the_previous_service_day() {
+    let graph = night_bus_graph();
+    let reached = |start: OffsetDateTime| {
+        let times = graph.dijkstras("A", start).unwrap();
+        (
+            times.get("B").map(|stop| start + stop.duration),
+            times.get("C").map(|stop| start + stop.duration),
+        )
+    };
+
+    //Friday night belongs to Friday's service
+    assert_eq!(
+        reached(datetime!(2024 - 10 - 19 0:30 UTC)).0,
+        Some(datetime!(2024 - 10 - 19 1:45 UTC))
+    );
+    //Saturday night belongs to Saturday's service
+    assert_eq!(
+        reached(datetime!(2024 - 10 - 20 0:30 UTC)).0,
+        Some(datetime!(2024 - 10 - 20 1:45 UTC))
+    );
+    //Thursday's service has no night bus, so the next one is Friday night's
+    assert_eq!(
+        reached(datetime!(2024 - 10 - 18 0:30 UTC)).0,
+        Some(datetime!(2024 - 10 - 19 1:45 UTC))
+    );
+    //Neither has Sunday's service, and the next one is too far away
+    assert_eq!(reached(datetime!(2024 - 10 - 21 0:30 UTC)).0, None);
+
+    //49:00 on Friday's service is early on Sunday
+    assert_eq!(
+        reached(datetime!(2024 - 10 - 20 0:30 UTC)).1,
+        Some(datetime!(2024 - 10 - 20 1:10 UTC))
+    );
+    assert_eq!(reached(datetime!(2024 - 10 - 21 0:30 UTC)).1, None);
+}
+
+#[test]
+fn late_evening_search_catches_departures_after_midnight() {
+    let graph = night_bus_graph();
+    let edge = &graph.stops[0].edges[0];
+
+    assert_eq!(
+        edge.departure_datetime(datetime!(2024 - 10 - 18 23:50 UTC), &TimeZone::utc()),
+        Some(datetime!(2024 - 10 - 19 1:30 UTC))
+    );
+    assert_eq!(
+        edge.departure_datetime(datetime!(2024 - 10 - 19 1:31 UTC), &TimeZone::utc()),
+        Some(datetime!(2024 - 10 - 20 1:30 UTC))
+    );
+    //00:10 is within reach late in the evening, but not from noon
+    let mut graph = test_graph(&[("A", 60.0, 25.0), ("B", 60.1, 25.0)]);
+    add_trip(
+        &mut graph,
+        "t2",
+        "r2",
+        [true; 7],
+        &[("A", 600, 600), ("B", 1200, 1200)],
+    );
+    let midnight_edge = &graph.stops[0].edges[0];
+    assert_eq!(
+        midnight_edge.departure_datetime(datetime!(2024 - 10 - 18 23:00 UTC), &TimeZone::utc()),
+        Some(datetime!(2024 - 10 - 19 0:10 UTC))
+    );
+    assert_eq!(
+        midnight_edge.departure_datetime(datetime!(2024 - 10 - 18 12:00 UTC), &TimeZone::utc()),
+        None
+    );
+
+    assert_eq!(
+        crate::gtfs_types::seconds_to_hhmmss(25 * 3600 + 1800),
+        "25:30:00"
+    );
+}
+
+#[test]
+fn typical_week_counts_unreached_departures_as_max_duration() {
+    use typical_week::{Statistic, TypicalWeekOptions};
+
+    let graph = three_stop_graph();
+    let options = TypicalWeekOptions {
+        days: vec![Weekday::Monday],
+        first_departure: time!(7:50),
+        last_departure: time!(8:10),
+        ..TypicalWeekOptions::weekdays(date!(2024 - 10 - 17))
+    };
+
+    //Departing at 07:50, 08:00 and 08:10 on Monday the 14th, the last one missing the only trip
+    let week = graph.typical_week("A", &options).unwrap();
+    assert_eq!(week.samples, 3);
+
+    let duration = |statistic: &str| {
+        graph
+            .aggregate_typical_week(&week, statistic.parse().unwrap())
+            .get("B")
+            .map(|stop| stop.duration)
+    };
+
+    assert_eq!(duration("median"), Some(Duration::minutes(19)));
+    assert_eq!(duration("p0"), Some(Duration::minutes(9)));
+    assert_eq!(
+        duration("mean"),
+        Some((Duration::minutes(19) + Duration::minutes(9) + Duration::hours(2)) / 3)
+    );
+    assert_eq!(duration("p100"), None);
+    assert!("p101".parse::<Statistic>().is_err());
+    assert!(matches!(
+        graph.itinerary(&graph.aggregate_typical_week(&week, Statistic::Mean), "B"),
+        Err(Error::NoItinerary(_))
+    ));
+}
+
+#[test]
+fn frequency_counts_departures_within_the_window() {
+    use frequency::{capacity_weights, FrequencyOptions};
+
+    let mut graph = three_stop_graph();
+    let a = graph.get_stop("A").unwrap().coordinates;
+
+    let frequency = |graph: &GtfsGraph, options: &FrequencyOptions| graph.frequency_at(&a, options);
+
+    let mut options = FrequencyOptions::new(datetime!(2024 - 10 - 14 7:30 UTC));
+    assert_eq!(frequency(&graph, &options), 1.0);
+
+    options.window = Duration::hours(2);
+    assert_eq!(frequency(&graph, &options), 0.5);
+
+    options.start = datetime!(2024 - 10 - 14 8:05 UTC);
+    assert_eq!(frequency(&graph, &options), 0.0);
+
+    graph.routes.insert(
+        "r1".into(),
+        test_route("r1", gtfs_structures::RouteType::Rail),
+    );
+    let mut options = FrequencyOptions::new(datetime!(2024 - 10 - 14 7:30 UTC));
+    options.route_type_weights = capacity_weights();
+    assert_eq!(frequency(&graph, &options), 4.0);
+
+    //Dates removed in calendar_dates.txt have no departures
+    graph.trips.insert("t1".into(), test_trip("t1", "r1"));
+    graph.services.insert(
+        "weekdays".to_string(),
+        departures::Service {
+            calendar: Some(([true; 7], date!(2024 - 1 - 1), date!(2024 - 12 - 31))),
+            removed: [date!(2024 - 10 - 14)].into(),
+            ..Default::default()
+        },
+    );
+    assert_eq!(frequency(&graph, &options), 0.0);
+    options.start += Duration::days(1);
+    assert_eq!(frequency(&graph, &options), 4.0);
+}
+
+#[test]
+fn stop_search_ignores_accents_and_tolerates_typos() {
+    let mut graph = GtfsGraph::new();
+
+    for (i, (id, name)) in [
+        ("1", "Töölön tori"),
+        ("2", "Kamppi"),
+        ("3", "Kampinkuja"),
+        ("4", "Pasila"),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        graph.push_stop(Stop {
+            name: Some(name.to_string()),
+            code: Some(format!("H{}", id)),
+            ..test_stop(id, 60.0 + i as f64 * 0.01, 25.0)
+        });
+    }
+
+    let ids = |query: &str| -> Vec<String> {
+        graph
+            .search_stops(query, 10)
+            .into_iter()
+            .map(|stop| stop.id)
+            .collect()
+    };
+
+    assert_eq!(ids("toolo"), ["1"]);
+    assert_eq!(ids("TORI"), ["1"]);
+    assert_eq!(ids("kampi"), ["3", "2"]);
+    assert_eq!(ids("pasial"), ["4"]);
+    assert_eq!(ids("h4"), ["4"]);
+    assert!(ids("").is_empty());
+
+    let within = graph.stops_within(&crate::coords::BoundingBox {
+        min: Coordinates {
+            latitude: 60.005,
+            longitude: 24.0,
+        },
+        max: Coordinates {
+            latitude: 60.025,
+            longitude: 26.0,
+        },
+    });
+    assert_eq!(within.len(), 2);
+}
+
+#[test]
+fn stop_details_include_routes_and_parent_station() {
+    let mut graph = three_stop_graph();
+    graph.routes.insert(
+        "r1".into(),
+        routes::Route {
+            short_name: Some("550".to_string()),
+            ..test_route("r1", gtfs_structures::RouteType::Bus)
+        },
+    );
+    graph.stations.insert(
+        "S".to_string(),
+        stop_search::Station {
+            id: "S".to_string(),
+            name: Some("Station".to_string()),
+        },
+    );
+    Arc::make_mut(&mut graph.stops[2]).parent_station = Some("S".to_string());
+
+    //C is only arrived at, but is still served by the route
+    let details = graph.stop_details("C").unwrap();
+    assert_eq!(details.routes.len(), 1);
+    assert_eq!(details.routes[0].short_name.as_deref(), Some("550"));
+    assert_eq!(
+        details.parent_station.and_then(|station| station.name),
+        Some("Station".to_string())
+    );
+
+    assert!(matches!(
+        graph.stop_details("D"),
+        Err(Error::MissingStop(_))
+    ));
+}
+
+#[test]
+fn trips_are_reconstructed_with_patterns_and_shapes() {
+    let mut graph = three_stop_graph();
+    add_trip(
+        &mut graph,
+        "t2",
+        "r1",
+        [true; 7],
+        &[
+            ("A", 9 * 3600, 9 * 3600),
+            ("B", 9 * 3600 + 540, 9 * 3600 + 540),
+        ],
+    );
+
+    graph.routes.insert(
+        "r1".into(),
+        test_route("r1", gtfs_structures::RouteType::Bus),
+    );
+    for (id, shape_id) in [("t1", Some("s1")), ("t2", None)] {
+        graph.trips.insert(
+            id.into(),
+            routes::Trip {
+                shape_id: shape_id.map(str::to_string),
+                ..test_trip(id, "r1")
+            },
+        );
+    }
+    graph.shapes.insert(
+        "s1".to_string(),
+        vec![
+            Coordinates {
+                latitude: 60.0,
+                longitude: 25.0,
+            },
+            Coordinates {
+                latitude: 60.2,
+                longitude: 25.1,
+            },
+        ],
+    );
+
+    let route = graph.route_details("r1").unwrap();
+    assert_eq!(route.patterns, [vec!["A", "B", "C"], vec!["A", "B"]]);
+    assert_eq!(route.trips[0].trip.id, "t1");
+    assert_eq!(route.trips[0].departure_time, "08:00:00");
+    assert_eq!(route.trips[1].pattern, 1);
+
+    let trip = graph.trip_details("t1").unwrap();
+    assert_eq!(trip.stop_times.len(), 3);
+    assert_eq!(trip.stop_times[1].arrival_time.as_deref(), Some("08:09:00"));
+    assert_eq!(
+        trip.stop_times[1].departure_time.as_deref(),
+        Some("08:10:00")
+    );
+    assert_eq!(trip.stop_times[2].departure_time, None);
+    assert_eq!(
+        trip.shape["geometry"]["coordinates"],
+        serde_json::json!([[25.0, 60.0], [25.1, 60.2]])
+    );
+
+    //Without a shape the line goes through the stops
+    let trip = graph.trip_details("t2").unwrap();
+    assert_eq!(
+        trip.shape["geometry"]["coordinates"],
+        serde_json::json!([[25.0, 60.0], [25.0, 60.1]])
+    );
+
+    assert!(matches!(
+        graph.trip_details("t3"),
+        Err(Error::MissingTrip(_))
+    ));
+}
+
+#[test]
+fn departures_honour_calendar_exceptions() {
+    let mut graph = three_stop_graph();
+    graph.trips.insert(
+        "t1".into(),
+        routes::Trip {
+            headsign: Some("Centre".to_string()),
+            ..test_trip("t1", "r1")
+        },
+    );
+    //Not running on Midsummer Eve, running on the Saturday after it instead
+    graph.services.insert(
+        "weekdays".to_string(),
+        departures::Service {
+            calendar: Some((
+                [true, true, true, true, true, false, false],
+                date!(2024 - 1 - 1),
+                date!(2024 - 12 - 31),
+            )),
+            added: [date!(2024 - 6 - 22)].into(),
+            removed: [date!(2024 - 6 - 21)].into(),
+        },
+    );
+
+    let departures = graph
+        .departures("A", datetime!(2024 - 6 - 20 9:00 UTC), 3)
+        .unwrap();
+
+    assert_eq!(
+        departures
+            .iter()
+            .map(|departure| departure.time)
+            .collect::<Vec<_>>(),
+        [
+            datetime!(2024 - 6 - 22 8:00 UTC),
+            datetime!(2024 - 6 - 24 8:00 UTC),
+            datetime!(2024 - 6 - 25 8:00 UTC),
+        ]
+    );
+    assert_eq!(departures[0].destination, "C");
+    assert_eq!(departures[0].headsign.as_deref(), Some("Centre"));
+
+    assert!(matches!(
+        graph.departures("D", datetime!(2024 - 6 - 20 9:00 UTC), 3),
+        Err(Error::MissingStop(_))
+    ));
+}
+
+#[test]
+fn scenarios_scale_headways_and_add_trips() {
+    let mut graph = three_stop_graph();
+    add_trip(
+        &mut graph,
+        "t2",
+        "r1",
+        [true; 7],
+        &[
+            ("A", 8 * 3600 + 1800, 8 * 3600 + 1800),
+            ("B", 8 * 3600 + 2340, 8 * 3600 + 2400),
+            ("C", 8 * 3600 + 3000, 8 * 3600 + 3000),
+        ],
+    );
+    let scenario = |json: &str| {
+        graph.build_scenario(&serde_json::from_str::<scenario::Scenario>(json).unwrap())
+    };
+
+    let overlay = scenario(
+        r#"{
+            "name": "more",
+            "headway_scaling": { "r1": 0.5 },
+            "added_trips": [{
+                "route_id": "express",
+                "stops": ["A", "C"],
+                "departure_times": ["07:00:00", "07:05:00"],
+                "repeat": { "headway_secs": 600, "until": "07:20:00" }
+            }]
+        }"#,
+    )
+    .unwrap();
+
+    //The express every 10 minutes and a copy of t1 halfway to t2
+    let mut departures: Vec<u32> = overlay
+        .added_edges("A")
+        .iter()
+        .map(|edge| edge.departure_time)
+        .collect();
+    departures.sort();
+    assert_eq!(
+        departures,
+        [7 * 3600, 7 * 3600 + 600, 7 * 3600 + 1200, 8 * 3600 + 900]
+    );
+
+    let times = graph
+        .dijkstras_with_scenario("A", datetime!(2024-01-01 07:01 UTC), Some(&overlay))
+        .unwrap();
+    assert_eq!(times["C"].duration, Duration::minutes(14));
+
+    let removed = scenario(r#"{ "name": "no-r1", "removed_routes": ["r1"] }"#).unwrap();
+    let times = graph
+        .dijkstras_with_scenario("A", datetime!(2024-01-01 07:55 UTC), Some(&removed))
+        .unwrap();
+    assert!(!times.contains_key("C"));
+
+    for invalid in [
+        r#"{ "name": "never", "headway_scaling": { "r1": 0 } }"#,
+        r#"{ "name": "rounds-to-nothing", "headway_scaling": { "r1": 1.2 } }"#,
+        r#"{ "name": "realtime" }"#,
+        r#"{
+            "name": "backwards",
+            "added_trips": [{
+                "route_id": "express",
+                "stops": ["A", "C"],
+                "departure_times": ["07:05:00", "07:00:00"]
+            }]
+        }"#,
+    ] {
+        assert!(matches!(scenario(invalid), Err(Error::InvalidScenario(_))));
+    }
+    assert!(scenario(r#"{ "name": "same", "headway_scaling": { "r1": 1.0 } }"#).is_ok());
+}
+
+#[test]
+fn difference_tiles_colour_faster_pixels_blue_and_slower_red() {
+    let graph = three_stop_graph();
+    let zoom = 14;
+
+    //Leaving four minutes later still catches t1, so C is reached four minutes faster
+    let before = graph
+        .dijkstras("A", datetime!(2024 - 10 - 14 7:55 UTC))
+        .unwrap();
+    let after = graph
+        .dijkstras("A", datetime!(2024 - 10 - 14 7:59 UTC))
+        .unwrap();
+
+    let pixel_at = |stop: &str, before, after| {
+        let coordinates = graph.get_stop(stop).unwrap().coordinates;
+        let tile = coordinates.as_tile(zoom);
+        let (x, y) = coordinates.as_global_pixel(zoom);
+
+        *graph
+            .generate_difference_tile(zoom, tile.x, tile.y, before, after)
+            .unwrap()
+            .get_pixel(x % 256, y % 256)
+    };
+
+    assert_eq!(pixel_at("C", &before, &after).0, [221, 221, 255]);
+    assert_eq!(pixel_at("C", &after, &before).0, [255, 221, 221]);
+    assert_eq!(pixel_at("A", &before, &after).0, [255, 255, 255]);
+}
+
+#[test]
+fn parallel_rendering_and_searches_match_serial_ones() {
+    use crate::coords::BoundingBox;
+    use heatmap::encode_webp;
+    use matrix::{Location, MatrixOptions, Place};
+
+    fn assert_sync<T: Send + Sync>() {}
+    assert_sync::<GtfsGraph>();
+
+    let graph = three_stop_graph();
+    let start = datetime!(2024 - 10 - 14 7:55 UTC);
+    let stop_times = graph.dijkstras("A", start).unwrap();
+
+    //Searches from several threads share the graph without locking it
+    std::thread::scope(|scope| {
+        let searches: Vec<_> = (0..4)
+            .map(|_| scope.spawn(|| graph.dijkstras("A", start).unwrap()))
+            .collect();
+
+        for search in searches {
+            let times = search.join().unwrap();
+            assert_eq!(times.len(), stop_times.len());
+            assert_eq!(times["C"].duration, stop_times["C"].duration);
+        }
+    });
+
+    let places: Vec<Place> = ["A", "B", "C"]
+        .into_iter()
+        .map(|id| Place {
+            id: id.to_string(),
+            location: Location::Stop(id.to_string()),
+        })
+        .collect();
+    let matrix = |threads| {
+        let options = MatrixOptions {
+            start_time: start,
+            window: Duration::minutes(10),
+            step: Duration::minutes(2),
+            threads,
+        };
+        graph
+            .travel_time_matrix(&places, &places, &options)
+            .unwrap()
+            .times
+    };
+    assert_eq!(matrix(4), matrix(1));
+
+    let bounding_box = BoundingBox {
+        min: Coordinates {
+            latitude: 60.0,
+            longitude: 25.0,
+        },
+        max: Coordinates {
+            latitude: 60.2,
+            longitude: 25.1,
+        },
+    };
+    let mut written = Vec::new();
+    let count = graph
+        .render_tile_pyramid(&bounding_box, 9..=10, &stop_times, |tile, data| {
+            written.push(((tile.zoom, tile.x, tile.y), data.to_vec()));
+            Ok(())
+        })
+        .unwrap();
+
+    assert_eq!(count, written.len());
+    assert_eq!(
+        count,
+        bounding_box.tiles(9).count() + bounding_box.tiles(10).count()
+    );
+    for ((zoom, x, y), data) in written {
+        let (image, _) = graph
+            .generate_heatmap_tile(zoom, x, y, &stop_times)
+            .unwrap();
+        assert_eq!(data, encode_webp(image).unwrap());
+    }
+}
+
+#[test]
+fn prerendered_tiles_round_trip_through_mbtiles() {
+    use crate::{
+        coords::{BoundingBox, TileNumbers},
+        mbtiles::MbTiles,
+    };
+
+    let graph = three_stop_graph();
+    let stop_times = graph
+        .dijkstras("A", datetime!(2024 - 10 - 14 7:55 UTC))
+        .unwrap();
+    let bounding_box = BoundingBox {
+        min: Coordinates {
+            latitude: 60.0,
+            longitude: 25.0,
+        },
+        max: Coordinates {
+            latitude: 60.2,
+            longitude: 25.1,
+        },
+    };
+    let path =
+        std::env::temp_dir().join(format!("gtfs-heatmap-test-{}.mbtiles", std::process::id()));
+
+    let archive = MbTiles::create(&path, "test", "A at 07:55", &bounding_box, 9, 10).unwrap();
+    let count = archive
+        .in_transaction(|archive| {
+            graph.render_tile_pyramid(&bounding_box, 9..=10, &stop_times, |tile, data| {
+                archive.insert_tile(tile, data)
+            })
+        })
+        .unwrap();
+    drop(archive);
+
+    let archive = MbTiles::open(&path).unwrap();
+    let tile = stop_times["C"].stop.coordinates.as_tile(10);
+    let (image, _) = graph
+        .generate_heatmap_tile(tile.zoom, tile.x, tile.y, &stop_times)
+        .unwrap();
+
+    assert!(count > 0);
+    assert_eq!(
+        archive.get_tile(&tile).unwrap(),
+        Some(heatmap::encode_webp(image).unwrap())
+    );
+    assert_eq!(
+        archive
+            .get_tile(&TileNumbers {
+                zoom: 10,
+                x: 0,
+                y: 0
+            })
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        archive.get_metadata("maxzoom").unwrap().as_deref(),
+        Some("10")
+    );
+    assert_eq!(
+        archive.get_metadata("bounds").unwrap().as_deref(),
+        Some("25,60,25.1,60.2")
+    );
+
+    //Opening doesn't create missing archives or accept other databases
+    let other = path.with_extension("sqlite");
+    assert!(MbTiles::open(&other).is_err());
+    assert!(!other.exists());
+    rusqlite::Connection::open(&other)
+        .unwrap()
+        .execute_batch("CREATE TABLE tiles (x INTEGER)")
+        .unwrap();
+    assert!(matches!(
+        MbTiles::open(&other),
+        Err(crate::Error::InvalidTileArchive(_))
+    ));
+
+    std::fs::remove_file(path).unwrap();
+    std::fs::remove_file(other).unwrap();
+}
+
+#[test]
+fn merged_feeds_are_namespaced_and_connected_by_walking_transfers() {
+    use gtfs_structures::{Agency, Calendar, Gtfs, StopTime};
+    use parser::Feed;
+
+    //Both feeds have stops S1 and S2 and a daily trip taking ten minutes from S1 to S2
+    let feed = |time_zone: &str, latitude: f64, departure: u32| {
+        let mut gtfs = Gtfs::default();
+        gtfs.agencies.push(Agency {
+            timezone: time_zone.to_string(),
+            ..Default::default()
+        });
+
+        let stops: Vec<Arc<gtfs_structures::Stop>> = [("S1", latitude), ("S2", latitude + 0.1)]
+            .into_iter()
+            .map(|(id, latitude)| {
+                Arc::new(gtfs_structures::Stop {
+                    id: id.to_string(),
+                    latitude: Some(latitude),
+                    longitude: Some(25.0),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        for stop in stops.iter() {
+            gtfs.stops.insert(stop.id.clone(), stop.clone());
+        }
+
+        gtfs.calendar.insert(
+            "daily".to_string(),
+            Calendar {
+                id: "daily".to_string(),
+                monday: true,
+                tuesday: true,
+                wednesday: true,
+                thursday: true,
+                friday: true,
+                saturday: true,
+                sunday: true,
+                start_date: chrono::NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+                end_date: chrono::NaiveDate::from_ymd_opt(2024, 12, 31).unwrap(),
+            },
+        );
+        gtfs.trips.insert(
+            "t1".to_string(),
+            gtfs_structures::Trip {
+                id: "t1".to_string(),
+                service_id: "daily".to_string(),
+                route_id: "r1".to_string(),
+                stop_times: stops
+                    .iter()
+                    .zip([departure, departure + 600])
+                    .enumerate()
+                    .map(|(i, (stop, time))| StopTime {
+                        stop: stop.clone(),
+                        arrival_time: Some(time),
+                        departure_time: Some(time),
+                        stop_sequence: i as u16 + 1,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            },
+        );
+
+        gtfs
+    };
+
+    //S2 of hsl is about 55 meters from S1 of vr
+    let feeds = || {
+        vec![
+            Feed {
+                namespace: Some("hsl".to_string()),
+                gtfs: feed("UTC", 60.0, 8 * 3600),
+            },
+            Feed {
+                namespace: Some("vr".to_string()),
+                gtfs: feed("Europe/Helsinki", 60.1005, 8 * 3600 + 1800),
+            },
+        ]
+    };
+
+    let graph = GtfsGraph::from_feeds(feeds(), 200.0).unwrap();
+
+    assert_eq!(graph.stop_count(), 4);
+    assert!(graph.get_stop("S1").is_none());
+    assert_eq!(graph.get_stop("hsl:S2").unwrap().transfers.len(), 1);
+    assert!(graph.get_stop("hsl:S1").unwrap().transfers.is_empty());
+    assert_eq!(graph.time_zone().name(), "UTC");
+    assert!(graph
+        .validation_report()
+        .issues
+        .contains(&validation::Issue::ConflictingTimeZone {
+            timezone: "Europe/Helsinki".to_string()
+        }));
+
+    //Times of every feed are read in the time zone of the first one.
+    //hsl's trip and the transfer reach vr:S1 in time for vr's trip at 08:30.
+    let times = graph
+        .dijkstras("hsl:S1", datetime!(2024 - 10 - 14 7:55 UTC))
+        .unwrap();
+    assert!(times["vr:S1"].duration > Duration::minutes(15));
+    assert!(times["vr:S1"].duration < Duration::minutes(16));
+    assert_eq!(times["vr:S2"].duration, Duration::minutes(45));
+
+    //Without transfers the feeds are separate networks
+    let graph = GtfsGraph::from_feeds(feeds(), 0.0).unwrap();
+    let times = graph
+        .dijkstras("hsl:S1", datetime!(2024 - 10 - 14 7:55 UTC))
+        .unwrap();
+    assert!(!times.contains_key("vr:S1"));
+
+    let mut feeds = feeds();
+    feeds[1].namespace = Some("hsl".to_string());
+    assert!(matches!(
+        GtfsGraph::from_feeds(feeds, 200.0),
+        Err(Error::DuplicateStop(_))
+    ));
+}
+
+#[test]
+fn noon_searches_do_not_wait_for_the_next_morning() {
+    let graph = three_stop_graph();
+
+    //t1 leaves A at 08:00, which has passed at noon, and the next one runs tomorrow
+    let times = graph
+        .dijkstras("A", datetime!(2024 - 10 - 14 12:00 UTC))
+        .unwrap();
+    assert!(!times.contains_key("B") && !times.contains_key("C"));
+
+    let times = graph
+        .dijkstras("A", datetime!(2024 - 10 - 14 7:55 UTC))
+        .unwrap();
+    assert_eq!(times["C"].duration, Duration::minutes(25));
+}
+
+#[test]
+fn empty_searches_render_no_tiles() {
+    use crate::coords::BoundingBox;
+
+    let graph = three_stop_graph();
+    let empty = HashMap::new();
+    let stop_times = graph
+        .dijkstras("A", datetime!(2024 - 10 - 14 7:55 UTC))
+        .unwrap();
+    let bounding_box = BoundingBox {
+        min: Coordinates {
+            latitude: 60.0,
+            longitude: 25.0,
+        },
+        max: Coordinates {
+            latitude: 60.2,
+            longitude: 25.1,
+        },
+    };
+
+    assert!(graph.generate_heatmap_tile(10, 583, 294, &empty).is_none());
+    assert!(graph
+        .generate_heatmap_area(&bounding_box, 9, &empty)
+        .is_none());
+    assert!(graph
+        .generate_difference_tile(10, 583, 294, &stop_times, &empty)
+        .is_none());
+    assert!(matches!(
+        graph.render_tile_pyramid(&bounding_box, 9..=9, &empty, |_, _| Ok(())),
+        Err(crate::Error::EmptySearch)
+    ));
+}
+
+#[test]
+fn accessibility_counts_opportunities_within_the_cutoff() {
+    use crate::coords::BoundingBox;
+    use accessibility::{AccessibilityOptions, DecayFunction, Opportunity};
+
+    let graph = three_stop_graph();
+    let opportunities: Vec<Opportunity> = [("B", 10.0), ("C", 100.0)]
+        .into_iter()
+        .map(|(stop, weight)| Opportunity {
+            coordinates: graph.get_stop(stop).unwrap().coordinates,
+            weight,
+        })
+        .collect();
+
+    //Two cells of a kilometer, the first one centered on A and the second one east of it
+    let options = |cutoff_minutes, max_cells| AccessibilityOptions {
+        bounding_box: BoundingBox {
+            min: Coordinates {
+                latitude: 59.9956,
+                longitude: 24.991,
+            },
+            max: Coordinates {
+                latitude: 60.0044,
+                longitude: 25.026,
+            },
+        },
+        cell_size: 1000.0,
+        start_time: datetime!(2024 - 10 - 14 7:55 UTC),
+        cutoff: Duration::minutes(cutoff_minutes),
+        decay: DecayFunction::Step,
+        max_cells,
+    };
+    let values = |cutoff_minutes| {
+        let grid = graph
+            .accessibility(&opportunities, &options(cutoff_minutes, 2))
+            .unwrap();
+        assert_eq!((grid.rows, grid.columns), (1, 2));
+
+        grid.cells.iter().map(|cell| cell.value).collect::<Vec<_>>()
+    };
+
+    //B is 14 minutes from A and C 25 minutes, plus about 17 minutes of walking from the east cell
+    assert_eq!(values(20), vec![10.0, 0.0]);
+    assert_eq!(values(30), vec![110.0, 0.0]);
+    assert_eq!(values(45), vec![110.0, 110.0]);
+    assert!(matches!(
+        graph.accessibility(&opportunities, &options(30, 1)),
+        Err(Error::InvalidGrid(_))
+    ));
+}
@@ -0,0 +1,205 @@
+use std::{collections::HashMap, str::FromStr};
+
+use rayon::{prelude::*, ThreadPoolBuilder};
+use time::{macros::time, Date, Duration, OffsetDateTime, PrimitiveDateTime, Time, Weekday};
+
+use super::{
+    dijkstras::{Arrival, StopWithDuration},
+    Error, GtfsGraph,
+};
+
+///Departure times sampled for a typical week
+pub struct TypicalWeekOptions {
+    ///Any date of the week to sample, services are only looked up by weekday
+    pub week_of: Date,
+    pub days: Vec<Weekday>,
+    ///Local times in the time zone of the feeds, both included
+    pub first_departure: Time,
+    pub last_departure: Time,
+    pub step: Duration,
+    ///Searches not reaching a stop count as taking this long
+    pub max_duration: Duration,
+    ///Zero uses one thread per core
+    pub threads: usize,
+}
+
+impl TypicalWeekOptions {
+    ///Every 10 minutes from 07:00 to 19:00, Monday to Friday of the week of week_of
+    pub fn weekdays(week_of: Date) -> Self {
+        Self {
+            week_of,
+            days: vec![
+                Weekday::Monday,
+                Weekday::Tuesday,
+                Weekday::Wednesday,
+                Weekday::Thursday,
+                Weekday::Friday,
+            ],
+            first_departure: time!(7:00),
+            last_departure: time!(19:00),
+            step: Duration::minutes(10),
+            max_duration: Duration::hours(2),
+            threads: 0,
+        }
+    }
+
+    fn departures(&self) -> Vec<PrimitiveDateTime> {
+        let monday =
+            self.week_of - Duration::days(self.week_of.weekday().number_days_from_monday() as i64);
+
+        let mut departures = Vec::new();
+
+        for day in self.days.iter() {
+            let date = monday + Duration::days(day.number_days_from_monday() as i64);
+            let mut departure = PrimitiveDateTime::new(date, self.first_departure);
+            let last = PrimitiveDateTime::new(date, self.last_departure);
+
+            while departure <= last {
+                departures.push(departure);
+
+                if self.step <= Duration::ZERO {
+                    break;
+                }
+                departure += self.step;
+            }
+        }
+
+        departures
+    }
+}
+
+///How the travel times of the sampled departures are reduced to one per stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Statistic {
+    Mean,
+    ///Travel time not exceeded by this percentage of departures
+    Percentile(u8),
+}
+
+impl FromStr for Statistic {
+    type Err = String;
+
+    ///Accepts mean, median and percentiles like p90
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "mean" => Ok(Statistic::Mean),
+            "median" => Ok(Statistic::Percentile(50)),
+            _ => value
+                .strip_prefix('p')
+                .and_then(|percentile| percentile.parse().ok())
+                .filter(|percentile| *percentile <= 100)
+                .map(Statistic::Percentile)
+                .ok_or(format!(
+                    "Unknown statistic {}, expected mean, median or p0 to p100",
+                    value
+                )),
+        }
+    }
+}
+
+///Travel times to every reached stop from each sampled departure
+pub struct TypicalWeek {
+    ///Number of departures searched from
+    pub samples: usize,
+    max_duration: Duration,
+    ///Only departures reaching the stop within max_duration have a travel time
+    durations: HashMap<String, Vec<Duration>>,
+}
+
+impl TypicalWeek {
+    fn aggregate(&self, durations: &[Duration], statistic: Statistic) -> Option<Duration> {
+        if self.samples == 0 {
+            return None;
+        }
+
+        let mut durations = durations.to_vec();
+        durations.resize(self.samples, self.max_duration);
+
+        let duration = match statistic {
+            Statistic::Mean => durations.iter().sum::<Duration>() / self.samples as u32,
+            Statistic::Percentile(percentile) => {
+                durations.sort();
+                //Nearest rank, the 0th percentile being the fastest departure
+                let rank = (percentile as usize * self.samples).div_ceil(100);
+                durations[rank.saturating_sub(1)]
+            }
+        };
+
+        (duration < self.max_duration).then_some(duration)
+    }
+}
+
+impl GtfsGraph {
+    ///Searches from a stop at every departure of a typical week.
+    ///Departures are searched in parallel on a pool of options.threads threads.
+    pub fn typical_week(
+        &self,
+        start_id: &str,
+        options: &TypicalWeekOptions,
+    ) -> Result<TypicalWeek, Error> {
+        let departures: Vec<OffsetDateTime> = options
+            .departures()
+            .into_iter()
+            .map(|departure| self.time_zone.from_local(departure))
+            .collect();
+
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(options.threads)
+            .build()
+            .map_err(|err| Error::ThreadPool(err.to_string()))?;
+
+        let durations = pool.install(|| {
+            departures
+                .par_iter()
+                .try_fold(HashMap::new, |mut durations, departure| {
+                    for (id, stop_time) in self.dijkstras(start_id, *departure)? {
+                        if stop_time.duration < options.max_duration {
+                            durations
+                                .entry(id)
+                                .or_insert_with(Vec::new)
+                                .push(stop_time.duration);
+                        }
+                    }
+
+                    Ok::<HashMap<String, Vec<Duration>>, Error>(durations)
+                })
+                .try_reduce(HashMap::new, |mut durations, other| {
+                    for (id, other) in other {
+                        durations.entry(id).or_default().extend(other);
+                    }
+
+                    Ok(durations)
+                })
+        })?;
+
+        Ok(TypicalWeek {
+            samples: departures.len(),
+            max_duration: options.max_duration,
+            durations,
+        })
+    }
+
+    ///Reduces the travel times of a typical week to one per stop, which can be
+    ///rendered like the results of dijkstras. Stops the statistic doesn't reach
+    ///within max_duration are left out. The travel times combine many searches,
+    ///so the result has no itineraries.
+    pub fn aggregate_typical_week(
+        &self,
+        week: &TypicalWeek,
+        statistic: Statistic,
+    ) -> HashMap<String, StopWithDuration> {
+        week.durations
+            .iter()
+            .filter_map(|(stop_id, durations)| {
+                Some((
+                    stop_id.clone(),
+                    StopWithDuration {
+                        stop: self.get_stop(stop_id)?,
+                        duration: week.aggregate(durations, statistic)?,
+                        arrival: Some(Arrival::Combined),
+                    },
+                ))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+///Problem found in a feed while building the graph.
+///The data causing it was skipped, so the graph is still usable.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Issue {
+    ///Stop with location type stop has no latitude or longitude
+    StopWithoutCoordinates { stop_id: String },
+    ///Trip refers to a service with neither a calendar nor added calendar dates
+    MissingService { trip_id: String, service_id: String },
+    ///Trip refers to a route which is not in routes.txt
+    MissingRoute { trip_id: String, route_id: String },
+    ///stop_time refers to a stop which is not in the graph
+    MissingStop { trip_id: String, stop_id: String },
+    ///stop_time has neither an arrival nor a departure time
+    StopTimeWithoutTime { trip_id: String, stop_sequence: u32 },
+    ///stop_time departs before the previous stop of the trip
+    NonMonotonicTime { trip_id: String, stop_sequence: u32 },
+    ///Two consecutive stop_times of a trip are at the same stop
+    ZeroLengthEdge { trip_id: String, stop_sequence: u32 },
+    ///Stop is not visited by any trip
+    OrphanStop { stop_id: String },
+    ///Fare price is not a number
+    InvalidFare { fare_id: String },
+    ///fare_rule refers to a fare which is not in fare_attributes.txt
+    MissingFare { fare_id: String },
+    ///agency_timezone is not in the time zone database, times are treated as UTC
+    UnknownTimeZone { timezone: String },
+    ///Feed is in another time zone than the first feed, its times are treated as in the first one
+    ConflictingTimeZone { timezone: String },
+}
+
+impl Issue {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Issue::StopWithoutCoordinates { .. } => "stop_without_coordinates",
+            Issue::MissingService { .. } => "missing_service",
+            Issue::MissingRoute { .. } => "missing_route",
+            Issue::MissingStop { .. } => "missing_stop",
+            Issue::StopTimeWithoutTime { .. } => "stop_time_without_time",
+            Issue::NonMonotonicTime { .. } => "non_monotonic_time",
+            Issue::ZeroLengthEdge { .. } => "zero_length_edge",
+            Issue::OrphanStop { .. } => "orphan_stop",
+            Issue::InvalidFare { .. } => "invalid_fare",
+            Issue::MissingFare { .. } => "missing_fare",
+            Issue::UnknownTimeZone { .. } => "unknown_time_zone",
+            Issue::ConflictingTimeZone { .. } => "conflicting_time_zone",
+        }
+    }
+}
+
+///Issues found in the feeds a graph was built from
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct ValidationReport {
+    pub issues: Vec<Issue>,
+}
+
+impl ValidationReport {
+    pub(super) fn push(&mut self, issue: Issue) {
+        self.issues.push(issue);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    ///Number of issues of each kind
+    pub fn summary(&self) -> BTreeMap<&'static str, usize> {
+        let mut summary = BTreeMap::new();
+
+        for issue in &self.issues {
+            *summary.entry(issue.kind()).or_default() += 1;
+        }
+
+        summary
+    }
+}
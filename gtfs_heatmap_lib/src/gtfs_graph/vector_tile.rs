@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+
+use rayon::prelude::*;
+use time::Duration;
+
+use crate::{
+    coords::TileNumbers,
+    mvt::{self, Feature, Geometry, Layer, Point, Value},
+};
+
+use super::{dijkstras::StopWithDuration, heatmap::reachable_time, GtfsGraph};
+
+const EXTENT: u32 = 4096;
+///Isochrones are traced from travel times sampled on a grid of this many cells per tile side
+const GRID_RESOLUTION: i32 = 64;
+const CELL_SIZE: i32 = EXTENT as i32 / GRID_RESOLUTION;
+///Cells sampled outside the tile on each side, so polygons of neighbouring tiles overlap
+const GRID_BUFFER: i32 = 1;
+
+impl GtfsGraph {
+    ///Encodes reached stops and isochrone polygons of the tile as a Mapbox Vector Tile.
+    ///The stops layer has a point per stop with its stop_id and travel_time in seconds.
+    ///The isochrones layer has a polygon per band covering the area reachable within
+    ///that many minutes, so bands overlap and can be filtered client side.
+    pub fn generate_vector_tile(
+        &self,
+        zoom: u32,
+        tile_x: u32,
+        tile_y: u32,
+        stop_times: &HashMap<String, StopWithDuration>,
+        bands: &[Duration],
+    ) -> Vec<u8> {
+        let tile = TileNumbers {
+            zoom,
+            x: tile_x,
+            y: tile_y,
+        };
+
+        mvt::encode_tile(&[
+            Layer {
+                name: "stops".to_string(),
+                extent: EXTENT,
+                features: stop_features(&tile, stop_times),
+            },
+            Layer {
+                name: "isochrones".to_string(),
+                extent: EXTENT,
+                features: isochrone_features(&tile, stop_times, bands),
+            },
+        ])
+    }
+}
+
+fn stop_features(
+    tile: &TileNumbers,
+    stop_times: &HashMap<String, StopWithDuration>,
+) -> Vec<Feature> {
+    stop_times
+        .values()
+        .filter_map(|stop_time| {
+            let (x, y) = tile.relative_position(&stop_time.stop.coordinates);
+
+            if !(0.0..1.0).contains(&x) || !(0.0..1.0).contains(&y) {
+                return None;
+            }
+
+            Some(Feature {
+                geometry: Geometry::Point(((x * EXTENT as f64) as i32, (y * EXTENT as f64) as i32)),
+                properties: vec![
+                    ("stop_id", Value::String(stop_time.stop.id.clone())),
+                    (
+                        "travel_time",
+                        Value::Int(stop_time.duration.whole_seconds()),
+                    ),
+                ],
+            })
+        })
+        .collect()
+}
+
+fn isochrone_features(
+    tile: &TileNumbers,
+    stop_times: &HashMap<String, StopWithDuration>,
+    bands: &[Duration],
+) -> Vec<Feature> {
+    let side = (GRID_RESOLUTION + 2 * GRID_BUFFER) as usize;
+
+    //Travel time at the center of each cell, row by row
+    let times: Vec<Option<Duration>> = (0..side * side)
+        .into_par_iter()
+        .map(|i| {
+            let column = (i % side) as i32 - GRID_BUFFER;
+            let row = (i / side) as i32 - GRID_BUFFER;
+
+            reachable_time(
+                &tile.get_relative_coordinates(
+                    (column as f64 + 0.5) / GRID_RESOLUTION as f64,
+                    (row as f64 + 0.5) / GRID_RESOLUTION as f64,
+                ),
+                stop_times,
+            )
+        })
+        .collect();
+
+    bands
+        .iter()
+        .filter_map(|band| {
+            let filled: Vec<bool> = times
+                .iter()
+                .map(|time| time.is_some_and(|time| time <= *band))
+                .collect();
+
+            let rings = trace_rings(&filled, side);
+
+            if rings.is_empty() {
+                return None;
+            }
+
+            Some(Feature {
+                geometry: Geometry::Polygon(rings),
+                properties: vec![("minutes", Value::Int(band.whole_minutes()))],
+            })
+        })
+        .collect()
+}
+
+///Traces the outlines of filled cells of a side by side grid into polygon rings in tile coordinates.
+///Cell edges are walked clockwise around every filled cell, so after removing the edges
+///shared by two filled cells the rest link up into clockwise exteriors and counter clockwise holes.
+pub(super) fn trace_rings(filled: &[bool], side: usize) -> Vec<Vec<Point>> {
+    let is_filled = |column: i32, row: i32| {
+        (0..side as i32).contains(&column)
+            && (0..side as i32).contains(&row)
+            && filled[row as usize * side + column as usize]
+    };
+
+    let mut edges: HashMap<Point, Vec<Point>> = HashMap::new();
+
+    for row in 0..side as i32 {
+        for column in 0..side as i32 {
+            if !is_filled(column, row) {
+                continue;
+            }
+
+            let sides = [
+                ((column, row), (column + 1, row), (column, row - 1)),
+                ((column + 1, row), (column + 1, row + 1), (column + 1, row)),
+                ((column + 1, row + 1), (column, row + 1), (column, row + 1)),
+                ((column, row + 1), (column, row), (column - 1, row)),
+            ];
+
+            for (start, end, neighbour) in sides {
+                if !is_filled(neighbour.0, neighbour.1) {
+                    edges.entry(start).or_default().push(end);
+                }
+            }
+        }
+    }
+
+    let mut exteriors: Vec<Vec<Point>> = Vec::new();
+    let mut holes: Vec<Vec<Point>> = Vec::new();
+
+    while let Some(&start) = edges.keys().next() {
+        let mut ring = vec![start];
+        let mut current = start;
+
+        while let Some(next) = edges.get_mut(&current).and_then(|ends| ends.pop()) {
+            if edges.get(&current).is_some_and(|ends| ends.is_empty()) {
+                edges.remove(&current);
+            }
+
+            if next == start {
+                break;
+            }
+
+            ring.push(next);
+            current = next;
+        }
+
+        let ring = remove_collinear(ring);
+
+        if ring.len() < 3 {
+            continue;
+        }
+
+        if signed_area(&ring) > 0 {
+            exteriors.push(ring);
+        } else {
+            holes.push(ring);
+        }
+    }
+
+    //Holes go right after the smallest exterior containing them
+    let mut polygons: Vec<Vec<Vec<Point>>> = exteriors.into_iter().map(|ring| vec![ring]).collect();
+
+    for hole in holes {
+        let inside = filled_side(&hole);
+
+        if let Some(polygon) = polygons
+            .iter_mut()
+            .filter(|polygon| contains(&polygon[0], inside))
+            .min_by_key(|polygon| signed_area(&polygon[0]))
+        {
+            polygon.push(hole);
+        }
+    }
+
+    polygons
+        .into_iter()
+        .flatten()
+        .map(|ring| {
+            ring.into_iter()
+                .map(|(column, row)| {
+                    (
+                        (column - GRID_BUFFER) * CELL_SIZE,
+                        (row - GRID_BUFFER) * CELL_SIZE,
+                    )
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn remove_collinear(ring: Vec<Point>) -> Vec<Point> {
+    let len = ring.len();
+
+    (0..len)
+        .filter(|&i| {
+            let (previous, point, next) = (ring[(i + len - 1) % len], ring[i], ring[(i + 1) % len]);
+
+            (point.0 - previous.0) * (next.1 - point.1)
+                != (point.1 - previous.1) * (next.0 - point.0)
+        })
+        .map(|i| ring[i])
+        .collect()
+}
+
+///Twice the area of the ring, positive when clockwise on screen
+fn signed_area(ring: &[Point]) -> i64 {
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|(a, b)| a.0 as i64 * b.1 as i64 - b.0 as i64 * a.1 as i64)
+        .sum()
+}
+
+///A point just inside the filled cells next to the first edge of the ring
+fn filled_side(ring: &[Point]) -> (f64, f64) {
+    let (a, b) = (ring[0], ring[1]);
+    let (dx, dy) = ((b.0 - a.0).signum() as f64, (b.1 - a.1).signum() as f64);
+
+    (
+        (a.0 as f64 + b.0 as f64) / 2.0 - dy * 0.25,
+        (a.1 as f64 + b.1 as f64) / 2.0 + dx * 0.25,
+    )
+}
+
+///Even odd rule point in polygon test
+fn contains(ring: &[Point], point: (f64, f64)) -> bool {
+    let mut inside = false;
+
+    for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+        let (ax, ay, bx, by) = (a.0 as f64, a.1 as f64, b.0 as f64, b.1 as f64);
+
+        if (ay > point.1) != (by > point.1) && point.0 < (bx - ax) * (point.1 - ay) / (by - ay) + ax
+        {
+            inside = !inside;
+        }
+    }
+
+    inside
+}
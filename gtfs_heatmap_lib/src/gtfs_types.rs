@@ -27,6 +27,28 @@ pub fn parse_time(string: &str) -> u32 {
     num
 }
 
+///Same as parse_time, but returns an error instead of panicking on malformed input.
+pub fn try_parse_time(string: &str) -> Result<u32, ParseError> {
+    let mut splits = string.split(":");
+
+    let mut num = 0;
+
+    for multiplier in [3600, 60, 1] {
+        num += splits
+            .next()
+            .ok_or(ParseError)?
+            .parse::<u32>()
+            .map_err(|_| ParseError)?
+            * multiplier;
+    }
+
+    if splits.next().is_some() {
+        return Err(ParseError);
+    }
+
+    Ok(num)
+}
+
 #[derive(Debug)]
 pub struct Stop {
     pub stop_id: String,
@@ -87,6 +109,20 @@ impl fmt::Display for Day {
     }
 }
 
+impl From<Day> for time::Weekday {
+    fn from(day: Day) -> Self {
+        match day {
+            Day::Monday => time::Weekday::Monday,
+            Day::Tuesday => time::Weekday::Tuesday,
+            Day::Wednesday => time::Weekday::Wednesday,
+            Day::Thursday => time::Weekday::Thursday,
+            Day::Friday => time::Weekday::Friday,
+            Day::Saturday => time::Weekday::Saturday,
+            Day::Sunday => time::Weekday::Sunday,
+        }
+    }
+}
+
 pub struct IntOutOfBounds;
 
 impl TryFrom<u8> for Day {
@@ -162,10 +198,11 @@ impl fmt::Display for Hour {
     }
 }
 
+///Formats seconds from the start of a service day like GTFS, so times past midnight are over 24:00:00
 pub fn seconds_to_hhmmss(timestamp: u32) -> String {
     format!(
         "{:02}:{:02}:{:02}",
-        timestamp / 3600 % 60,
+        timestamp / 3600,
         timestamp / 60 % 60,
         timestamp % 60
     )
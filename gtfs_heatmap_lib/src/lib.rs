@@ -1,8 +1,13 @@
 #![feature(async_closure)]
 
 pub mod coords;
+pub mod geotiff;
 pub mod gtfs_graph;
 pub mod gtfs_types;
+pub mod mbtiles;
+pub mod mvt;
+mod protobuf;
+pub mod timezone;
 
 pub use gtfs_structures::Gtfs;
 use std::sync::Arc;
@@ -13,6 +18,18 @@ use thiserror::Error;
 pub enum Error {
     #[error("Failed to parse")]
     ParseError,
+    #[error("Tile archive error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Not an MBTiles archive: {0}")]
+    InvalidTileArchive(String),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Image error: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("TIFF error: {0}")]
+    Tiff(#[from] tiff::TiffError),
+    #[error("The search reached no stops")]
+    EmptySearch,
 }
 
 pub async fn get_stops(gtfs_data: &Gtfs) -> Vec<&Arc<gtfs_structures::Stop>> {
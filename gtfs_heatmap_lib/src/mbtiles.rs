@@ -0,0 +1,137 @@
+use std::path::Path;
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+use crate::{
+    coords::{BoundingBox, TileNumbers},
+    Error,
+};
+
+///Tile archive in the MBTiles 1.3 format, a sqlite database with one row per tile.
+///Tiles are stored as webp images.
+pub struct MbTiles {
+    connection: Connection,
+}
+
+impl MbTiles {
+    ///Creates a new archive, replacing the file if it already exists.
+    ///Metadata is stored as given, description is a free form text shown by viewers.
+    pub fn create(
+        path: impl AsRef<Path>,
+        name: &str,
+        description: &str,
+        bounds: &BoundingBox,
+        min_zoom: u32,
+        max_zoom: u32,
+    ) -> Result<Self, Error> {
+        if path.as_ref().exists() {
+            std::fs::remove_file(&path)?;
+        }
+
+        let connection = Connection::open(path)?;
+
+        connection.execute_batch(
+            "CREATE TABLE metadata (name TEXT, value TEXT);
+            CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+            CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);",
+        )?;
+
+        let center = bounds.center();
+
+        for (key, value) in [
+            ("name", name.to_string()),
+            ("description", description.to_string()),
+            ("format", "webp".to_string()),
+            ("type", "overlay".to_string()),
+            ("version", "1".to_string()),
+            ("minzoom", min_zoom.to_string()),
+            ("maxzoom", max_zoom.to_string()),
+            (
+                "bounds",
+                format!(
+                    "{},{},{},{}",
+                    bounds.min.longitude,
+                    bounds.min.latitude,
+                    bounds.max.longitude,
+                    bounds.max.latitude
+                ),
+            ),
+            (
+                "center",
+                format!("{},{},{}", center.longitude, center.latitude, min_zoom),
+            ),
+        ] {
+            connection.execute(
+                "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
+                params![key, value],
+            )?;
+        }
+
+        Ok(Self { connection })
+    }
+
+    ///Opens an existing archive read only, failing if it is missing or doesn't have the tables of one
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+        for query in [
+            "SELECT name, value FROM metadata",
+            "SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles",
+        ] {
+            connection
+                .prepare(query)
+                .map_err(|err| Error::InvalidTileArchive(err.to_string()))?;
+        }
+
+        Ok(Self { connection })
+    }
+
+    ///Runs insert in a single transaction, committing once instead of after every tile.
+    ///Nothing is stored if insert fails.
+    pub fn in_transaction<T>(
+        &self,
+        insert: impl FnOnce(&Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let transaction = self.connection.unchecked_transaction()?;
+        let result = insert(self)?;
+        transaction.commit()?;
+
+        Ok(result)
+    }
+
+    ///Tile rows in MBTiles count from the south, unlike the xyz tiles used everywhere else
+    fn tile_row(tile: &TileNumbers) -> u32 {
+        2_u32.pow(tile.zoom) - 1 - tile.y
+    }
+
+    pub fn insert_tile(&self, tile: &TileNumbers, data: &[u8]) -> Result<(), Error> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)",
+            params![tile.zoom, tile.x, Self::tile_row(tile), data],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_tile(&self, tile: &TileNumbers) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                params![tile.zoom, tile.x, Self::tile_row(tile)],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn get_metadata(&self, name: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT value FROM metadata WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+}
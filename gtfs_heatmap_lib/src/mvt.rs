@@ -0,0 +1,174 @@
+use crate::protobuf::{
+    write_bytes, write_key, write_packed, write_varint_field, zigzag, WIRE_FIXED64,
+};
+
+///Tile coordinates of features, from 0 to the layer extent
+pub type Point = (i32, i32);
+
+pub enum Geometry {
+    Point(Point),
+    ///Rings of one or more polygons. Each exterior ring is clockwise on screen
+    ///and followed by its counter clockwise holes, rings are closed implicitly.
+    Polygon(Vec<Vec<Point>>),
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Int(i64),
+    Double(f64),
+}
+
+pub struct Feature {
+    pub geometry: Geometry,
+    pub properties: Vec<(&'static str, Value)>,
+}
+
+pub struct Layer {
+    pub name: String,
+    pub extent: u32,
+    pub features: Vec<Feature>,
+}
+
+//Field numbers from vector_tile.proto
+const TILE_LAYERS: u32 = 3;
+const LAYER_NAME: u32 = 1;
+const LAYER_FEATURES: u32 = 2;
+const LAYER_KEYS: u32 = 3;
+const LAYER_VALUES: u32 = 4;
+const LAYER_EXTENT: u32 = 5;
+const LAYER_VERSION: u32 = 15;
+const FEATURE_TAGS: u32 = 2;
+const FEATURE_TYPE: u32 = 3;
+const FEATURE_GEOMETRY: u32 = 4;
+const VALUE_STRING: u32 = 1;
+const VALUE_DOUBLE: u32 = 3;
+const VALUE_SINT: u32 = 6;
+
+const GEOMETRY_POINT: u64 = 1;
+const GEOMETRY_POLYGON: u64 = 3;
+
+const COMMAND_MOVE_TO: u32 = 1;
+const COMMAND_LINE_TO: u32 = 2;
+const COMMAND_CLOSE_PATH: u32 = 7;
+
+///Encodes layers as a Mapbox Vector Tile 2.1, served as application/vnd.mapbox-vector-tile.
+///Only the parts of the protobuf schema needed for points and polygons are written.
+pub fn encode_tile(layers: &[Layer]) -> Vec<u8> {
+    let mut tile = Vec::new();
+
+    for layer in layers {
+        write_bytes(&mut tile, TILE_LAYERS, &encode_layer(layer));
+    }
+
+    tile
+}
+
+fn encode_layer(layer: &Layer) -> Vec<u8> {
+    let mut keys: Vec<&str> = Vec::new();
+    let mut values: Vec<&Value> = Vec::new();
+    let mut buf = Vec::new();
+
+    write_varint_field(&mut buf, LAYER_VERSION, 2);
+    write_bytes(&mut buf, LAYER_NAME, layer.name.as_bytes());
+
+    for feature in &layer.features {
+        let mut tags = Vec::new();
+
+        for (key, value) in &feature.properties {
+            tags.push(index_of(&mut keys, *key));
+            tags.push(index_of(&mut values, value));
+        }
+
+        write_bytes(&mut buf, LAYER_FEATURES, &encode_feature(feature, &tags));
+    }
+
+    for key in keys {
+        write_bytes(&mut buf, LAYER_KEYS, key.as_bytes());
+    }
+
+    for value in values {
+        write_bytes(&mut buf, LAYER_VALUES, &encode_value(value));
+    }
+
+    write_varint_field(&mut buf, LAYER_EXTENT, layer.extent as u64);
+
+    buf
+}
+
+fn encode_feature(feature: &Feature, tags: &[u32]) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    write_packed(&mut buf, FEATURE_TAGS, tags);
+
+    let (geometry_type, commands) = match &feature.geometry {
+        Geometry::Point(point) => (GEOMETRY_POINT, point_commands(*point)),
+        Geometry::Polygon(rings) => (GEOMETRY_POLYGON, polygon_commands(rings)),
+    };
+
+    write_varint_field(&mut buf, FEATURE_TYPE, geometry_type);
+    write_packed(&mut buf, FEATURE_GEOMETRY, &commands);
+
+    buf
+}
+
+fn encode_value(value: &Value) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    match value {
+        Value::String(string) => write_bytes(&mut buf, VALUE_STRING, string.as_bytes()),
+        Value::Double(double) => {
+            write_key(&mut buf, VALUE_DOUBLE, WIRE_FIXED64);
+            buf.extend_from_slice(&double.to_le_bytes());
+        }
+        Value::Int(int) => write_varint_field(&mut buf, VALUE_SINT, zigzag(*int)),
+    }
+
+    buf
+}
+
+fn point_commands(point: Point) -> Vec<u32> {
+    vec![
+        command(COMMAND_MOVE_TO, 1),
+        zigzag(point.0 as i64) as u32,
+        zigzag(point.1 as i64) as u32,
+    ]
+}
+
+///Coordinates are deltas from the previous point, the cursor carries over between rings
+fn polygon_commands(rings: &[Vec<Point>]) -> Vec<u32> {
+    let mut commands = Vec::new();
+    let mut cursor: Point = (0, 0);
+
+    for ring in rings.iter().filter(|ring| ring.len() >= 3) {
+        for (i, point) in ring.iter().enumerate() {
+            match i {
+                0 => commands.push(command(COMMAND_MOVE_TO, 1)),
+                1 => commands.push(command(COMMAND_LINE_TO, ring.len() as u32 - 1)),
+                _ => {}
+            }
+
+            commands.push(zigzag((point.0 - cursor.0) as i64) as u32);
+            commands.push(zigzag((point.1 - cursor.1) as i64) as u32);
+            cursor = *point;
+        }
+
+        commands.push(command(COMMAND_CLOSE_PATH, 1));
+    }
+
+    commands
+}
+
+fn command(id: u32, count: u32) -> u32 {
+    (id & 0x7) | (count << 3)
+}
+
+fn index_of<T: PartialEq>(items: &mut Vec<T>, item: T) -> u32 {
+    match items.iter().position(|existing| *existing == item) {
+        Some(index) => index as u32,
+        None => {
+            items.push(item);
+            items.len() as u32 - 1
+        }
+    }
+}
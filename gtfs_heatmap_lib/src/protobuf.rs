@@ -0,0 +1,167 @@
+use thiserror::Error;
+
+pub(crate) const WIRE_VARINT: u32 = 0;
+pub(crate) const WIRE_FIXED64: u32 = 1;
+pub(crate) const WIRE_LENGTH_DELIMITED: u32 = 2;
+pub(crate) const WIRE_FIXED32: u32 = 5;
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("Message ends in the middle of a field")]
+    UnexpectedEnd,
+    #[error("Varint is longer than 10 bytes")]
+    VarintTooLong,
+    #[error("Unsupported wire type {0}")]
+    UnsupportedWireType(u32),
+    #[error("String field is not valid UTF-8")]
+    InvalidString,
+}
+
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub(crate) fn write_key(buf: &mut Vec<u8>, field: u32, wire_type: u32) {
+    write_varint(buf, ((field << 3) | wire_type) as u64);
+}
+
+pub(crate) fn write_varint_field(buf: &mut Vec<u8>, field: u32, value: u64) {
+    write_key(buf, field, WIRE_VARINT);
+    write_varint(buf, value);
+}
+
+pub(crate) fn write_bytes(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_key(buf, field, WIRE_LENGTH_DELIMITED);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+pub(crate) fn write_packed(buf: &mut Vec<u8>, field: u32, values: &[u32]) {
+    let mut packed = Vec::new();
+
+    for value in values {
+        write_varint(&mut packed, *value as u64);
+    }
+
+    write_bytes(buf, field, &packed);
+}
+
+pub(crate) fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+///Value of a field as read from the wire, interpreting it is up to the message
+pub(crate) enum FieldValue<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> FieldValue<'a> {
+    pub(crate) fn as_u64(&self) -> u64 {
+        match self {
+            FieldValue::Varint(value) | FieldValue::Fixed64(value) => *value,
+            FieldValue::Fixed32(value) => *value as u64,
+            FieldValue::Bytes(_) => 0,
+        }
+    }
+
+    ///int32 and int64 fields, negative numbers are sign extended to 64 bits on the wire
+    pub(crate) fn as_i64(&self) -> i64 {
+        self.as_u64() as i64
+    }
+
+    pub(crate) fn as_bytes(&self) -> &'a [u8] {
+        match self {
+            FieldValue::Bytes(bytes) => bytes,
+            _ => &[],
+        }
+    }
+
+    pub(crate) fn as_string(&self) -> Result<String, DecodeError> {
+        String::from_utf8(self.as_bytes().to_vec()).map_err(|_| DecodeError::InvalidString)
+    }
+}
+
+///Iterates over the fields of an encoded message as field numbers and values.
+///Unknown fields can simply be skipped by the caller.
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn read_varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0;
+
+        for i in 0..10 {
+            let (&byte, rest) = self.data.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+            self.data = rest;
+            value |= ((byte & 0x7f) as u64) << (7 * i);
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(DecodeError::VarintTooLong)
+    }
+
+    fn read_slice(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.data.len() < len {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+
+        let (slice, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(slice)
+    }
+
+    fn read_field(&mut self) -> Result<(u32, FieldValue<'a>), DecodeError> {
+        let key = self.read_varint()?;
+        let field = (key >> 3) as u32;
+
+        let value = match key as u32 & 0x7 {
+            WIRE_VARINT => FieldValue::Varint(self.read_varint()?),
+            WIRE_FIXED64 => FieldValue::Fixed64(u64::from_le_bytes(
+                self.read_slice(8)?.try_into().expect("slice is 8 bytes"),
+            )),
+            WIRE_LENGTH_DELIMITED => {
+                let len = self.read_varint()? as usize;
+                FieldValue::Bytes(self.read_slice(len)?)
+            }
+            WIRE_FIXED32 => FieldValue::Fixed32(u32::from_le_bytes(
+                self.read_slice(4)?.try_into().expect("slice is 4 bytes"),
+            )),
+            wire_type => return Err(DecodeError::UnsupportedWireType(wire_type)),
+        };
+
+        Ok((field, value))
+    }
+}
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = Result<(u32, FieldValue<'a>), DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let field = self.read_field();
+
+        if field.is_err() {
+            self.data = &[];
+        }
+
+        Some(field)
+    }
+}
@@ -0,0 +1,133 @@
+use std::str::FromStr;
+
+use chrono::{Offset, TimeZone as _};
+use chrono_tz::Tz;
+use thiserror::Error;
+use time::{
+    format_description::well_known::Rfc3339,
+    macros::{format_description, time},
+    Date, Duration, OffsetDateTime, PrimitiveDateTime, UtcOffset,
+};
+
+#[derive(Error, Debug)]
+pub enum TimeZoneError {
+    #[error("Unknown time zone {0}")]
+    Unknown(String),
+}
+
+///Time zone of the IANA time zone database, which is embedded by chrono-tz
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeZone(Tz);
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        Self::utc()
+    }
+}
+
+impl TimeZone {
+    pub fn utc() -> Self {
+        Self(Tz::UTC)
+    }
+
+    ///Looks up a zone like Europe/Helsinki
+    pub fn load(name: &str) -> Result<Self, TimeZoneError> {
+        name.parse::<Tz>()
+            .map(Self)
+            .map_err(|_| TimeZoneError::Unknown(name.to_string()))
+    }
+
+    pub fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    ///Offset from UTC at an instant
+    pub fn offset_at(&self, instant: OffsetDateTime) -> UtcOffset {
+        let seconds = chrono::DateTime::from_timestamp(instant.unix_timestamp(), 0)
+            .map(|utc| {
+                self.0
+                    .offset_from_utc_datetime(&utc.naive_utc())
+                    .fix()
+                    .local_minus_utc()
+            })
+            .unwrap_or(0);
+
+        UtcOffset::from_whole_seconds(seconds).unwrap_or(UtcOffset::UTC)
+    }
+
+    pub fn to_local(&self, instant: OffsetDateTime) -> OffsetDateTime {
+        instant.to_offset(self.offset_at(instant))
+    }
+
+    ///Instant of a local time. Times repeated when clocks go back resolve to the first one,
+    ///times skipped when clocks go forward are shifted forward by the length of the gap.
+    pub fn from_local(&self, local: PrimitiveDateTime) -> OffsetDateTime {
+        let as_utc = local.assume_utc();
+        let before = self.offset_at(as_utc - Duration::DAY);
+        let after = self.offset_at(as_utc + Duration::DAY);
+
+        let candidates: Vec<OffsetDateTime> = [before, after]
+            .into_iter()
+            .map(|offset| local.assume_offset(offset))
+            .filter(|instant| self.offset_at(*instant) == instant.offset())
+            .collect();
+
+        candidates
+            .into_iter()
+            .min()
+            .unwrap_or(local.assume_offset(before))
+    }
+
+    ///Times in GTFS are measured from noon minus 12 hours on the service day,
+    ///which is midnight except on days when clocks change
+    pub fn service_day_start(&self, date: Date) -> OffsetDateTime {
+        self.from_local(PrimitiveDateTime::new(date, time!(12:00))) - Duration::hours(12)
+    }
+}
+
+///Departure time of a search, either an instant or a local time in the time zone of the feeds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Departure {
+    Instant(OffsetDateTime),
+    Local(PrimitiveDateTime),
+}
+
+impl Departure {
+    pub fn resolve(&self, time_zone: &TimeZone) -> OffsetDateTime {
+        match self {
+            Departure::Instant(instant) => *instant,
+            Departure::Local(local) => time_zone.from_local(*local),
+        }
+    }
+}
+
+impl FromStr for Departure {
+    type Err = time::error::Parse;
+
+    ///Accepts unix timestamps, RFC 3339 like 2024-10-18T08:00:00+03:00
+    ///and local times like 2024-10-18T08:00 or 2024-10-18T08:00:00
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Ok(Ok(instant)) = value
+            .parse::<i64>()
+            .map(OffsetDateTime::from_unix_timestamp)
+        {
+            return Ok(Departure::Instant(instant));
+        }
+
+        if let Ok(instant) = OffsetDateTime::parse(value, &Rfc3339) {
+            return Ok(Departure::Instant(instant));
+        }
+
+        PrimitiveDateTime::parse(
+            value,
+            format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]"),
+        )
+        .or_else(|_| {
+            PrimitiveDateTime::parse(
+                value,
+                format_description!("[year]-[month]-[day]T[hour]:[minute]"),
+            )
+        })
+        .map(Departure::Local)
+    }
+}
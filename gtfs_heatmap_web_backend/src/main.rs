@@ -1,57 +1,437 @@
 use std::collections::HashMap;
-use std::io::Cursor;
-use std::sync::Mutex;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
+use gtfs_heatmap_lib::coords::{BoundingBox, Coordinates, TileNumbers};
+use gtfs_heatmap_lib::geotiff::Crs;
+use gtfs_heatmap_lib::gtfs_graph::accessibility::{
+    parse_opportunities, AccessibilityOptions, DecayFunction,
+};
 use gtfs_heatmap_lib::gtfs_graph::dijkstras::StopWithDuration;
+use gtfs_heatmap_lib::gtfs_graph::fares::{reachable_within_budget, FareReach};
+use gtfs_heatmap_lib::gtfs_graph::frequency::{capacity_weights, FrequencyOptions};
+use gtfs_heatmap_lib::gtfs_graph::heatmap::encode_webp;
+use gtfs_heatmap_lib::gtfs_graph::pareto::{CostWeights, ParetoOptions};
+use gtfs_heatmap_lib::gtfs_graph::parser::Feed;
+use gtfs_heatmap_lib::gtfs_graph::realtime::{FeedMessage, REALTIME_OVERLAY};
+use gtfs_heatmap_lib::gtfs_graph::scenario::{Scenario, ScenarioOverlay};
+use gtfs_heatmap_lib::gtfs_graph::typical_week::{Statistic, TypicalWeek, TypicalWeekOptions};
 use gtfs_heatmap_lib::gtfs_graph::GtfsGraph;
-use rocket::response::Responder;
-use rocket::time::OffsetDateTime;
+use gtfs_heatmap_lib::gtfs_types::Day;
+use gtfs_heatmap_lib::mbtiles::MbTiles;
+use gtfs_heatmap_lib::timezone::Departure;
+use image::{DynamicImage, GrayImage};
+use rocket::data::{Data, ToByteUnit};
+use rocket::response::{self, Responder};
+use rocket::serde::Deserialize;
+use rocket::time::format_description::well_known::Iso8601;
+use rocket::time::{Date, Duration, OffsetDateTime, Time};
 
 use gtfs_heatmap_lib::Gtfs;
 
 use rocket::fairing::{Fairing, Info, Kind};
-use rocket::http::Header;
-use rocket::{Request, Response, State};
+use rocket::http::{Header, Status};
+use rocket::request::{self, FromParam, FromRequest, Outcome};
+use rocket::{Build, Request, Response, Rocket, State};
 
 #[macro_use]
 extern crate rocket;
 
-#[derive(Responder)]
-#[response(content_type = "text/plain")]
+#[cfg(test)]
+mod tests;
+
+///Error returned by every route as a JSON body like
+///{"error": {"code": "stop_not_found", "message": "..."}}, where code is from Error::code
+#[derive(Debug)]
 enum Error {
-    #[response(status = 500, content_type = "text/plain")]
-    GtfsErr(String),
-    #[response(status = 500, content_type = "text/plain")]
-    JsonError(String),
+    ///Failure of the graph or the library not caused by the request
+    Gtfs(String),
+    ///Failure to serialize a response
+    Json(String),
+    ///Scenario which isn't valid JSON for a scenario or can't be applied to the graph
+    InvalidScenario(String),
+    ScenarioNotFound(String),
+    StopNotFound(String),
+    RouteNotFound(String),
+    TripNotFound(String),
+    ///No search was saved under the name, or no search of the kind was made yet
+    SearchNotFound(String),
+    ///save_as name reserved for scenario searches
+    InvalidSaveName(String),
+    InvalidAccessibilityRequest(String),
+    InvalidRasterRequest(String),
+    InvalidVectorTileRequest(String),
+    ///Zoom, x or y outside the tiles of the zoom level
+    InvalidTile(String),
+    ReloadInProgress(String),
+    InvalidRealtime(String),
+    InvalidTimestamp(String),
+    InvalidTypicalWeek(String),
+    ///Well formed request the graph can't answer, like an itinerary to a stop not reached
+    Unprocessable(String),
+}
+
+impl Error {
+    fn status(&self) -> Status {
+        match self {
+            Error::Gtfs(_) | Error::Json(_) => Status::InternalServerError,
+            Error::ScenarioNotFound(_)
+            | Error::StopNotFound(_)
+            | Error::RouteNotFound(_)
+            | Error::TripNotFound(_)
+            | Error::SearchNotFound(_) => Status::NotFound,
+            Error::InvalidSaveName(_)
+            | Error::InvalidAccessibilityRequest(_)
+            | Error::InvalidRasterRequest(_)
+            | Error::InvalidVectorTileRequest(_)
+            | Error::InvalidTile(_)
+            | Error::InvalidRealtime(_)
+            | Error::InvalidTimestamp(_)
+            | Error::InvalidTypicalWeek(_) => Status::BadRequest,
+            Error::InvalidScenario(_) | Error::Unprocessable(_) => Status::UnprocessableEntity,
+            Error::ReloadInProgress(_) => Status::Conflict,
+        }
+    }
+
+    ///Stable identifier clients can match on, unlike the message
+    fn code(&self) -> &'static str {
+        match self {
+            Error::Gtfs(_) => "internal_error",
+            Error::Json(_) => "serialization_error",
+            Error::InvalidScenario(_) => "invalid_scenario",
+            Error::ScenarioNotFound(_) => "scenario_not_found",
+            Error::StopNotFound(_) => "stop_not_found",
+            Error::RouteNotFound(_) => "route_not_found",
+            Error::TripNotFound(_) => "trip_not_found",
+            Error::SearchNotFound(_) => "search_not_found",
+            Error::InvalidSaveName(_) => "invalid_save_name",
+            Error::InvalidAccessibilityRequest(_) => "invalid_accessibility_request",
+            Error::InvalidRasterRequest(_) => "invalid_raster_request",
+            Error::InvalidVectorTileRequest(_) => "invalid_vector_tile_request",
+            Error::InvalidTile(_) => "invalid_tile",
+            Error::ReloadInProgress(_) => "reload_in_progress",
+            Error::InvalidRealtime(_) => "invalid_realtime",
+            Error::InvalidTimestamp(_) => "invalid_timestamp",
+            Error::InvalidTypicalWeek(_) => "invalid_typical_week",
+            Error::Unprocessable(_) => "unprocessable",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            Error::Gtfs(message)
+            | Error::Json(message)
+            | Error::InvalidScenario(message)
+            | Error::ScenarioNotFound(message)
+            | Error::StopNotFound(message)
+            | Error::RouteNotFound(message)
+            | Error::TripNotFound(message)
+            | Error::SearchNotFound(message)
+            | Error::InvalidSaveName(message)
+            | Error::InvalidAccessibilityRequest(message)
+            | Error::InvalidRasterRequest(message)
+            | Error::InvalidVectorTileRequest(message)
+            | Error::InvalidTile(message)
+            | Error::ReloadInProgress(message)
+            | Error::InvalidRealtime(message)
+            | Error::InvalidTimestamp(message)
+            | Error::InvalidTypicalWeek(message)
+            | Error::Unprocessable(message) => message,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Error {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        (self.status(), error_body(self.code(), self.message())).respond_to(request)
+    }
+}
+
+fn error_body(code: &str, message: &str) -> Json {
+    Json(serde_json::json!({ "error": { "code": code, "message": message } }).to_string())
+}
+
+///Errors raised by Rocket itself, like unknown paths, unparseable parameters
+///and failing request guards, get the same JSON body as route errors
+#[catch(default)]
+fn default_catcher(status: Status, _request: &Request) -> (Status, Json) {
+    let code = status.reason_lossy().to_lowercase().replace(' ', "_");
+    (status, error_body(&code, status.reason_lossy()))
 }
 
 impl From<gtfs_heatmap_lib::Error> for Error {
     fn from(value: gtfs_heatmap_lib::Error) -> Self {
-        Self::GtfsErr(value.to_string())
+        Self::Gtfs(value.to_string())
     }
 }
 
 impl From<serde_json::Error> for Error {
     fn from(value: serde_json::Error) -> Self {
-        Self::JsonError(value.to_string())
+        Self::Json(value.to_string())
     }
 }
 
 impl From<gtfs_heatmap_lib::gtfs_graph::Error> for Error {
     fn from(value: gtfs_heatmap_lib::gtfs_graph::Error) -> Self {
-        Self::GtfsErr(value.to_string())
+        use gtfs_heatmap_lib::gtfs_graph::Error as GraphError;
+
+        let message = value.to_string();
+        match value {
+            GraphError::MissingStop(_) => Self::StopNotFound(message),
+            GraphError::MissingRoute(_) => Self::RouteNotFound(message),
+            GraphError::MissingTrip(_) => Self::TripNotFound(message),
+            GraphError::InvalidScenario(_) => Self::InvalidScenario(message),
+            GraphError::InvalidGrid(_) => Self::InvalidAccessibilityRequest(message),
+            GraphError::NoStopNearby(..)
+            | GraphError::NotReached(_)
+            | GraphError::NoItinerary(_)
+            | GraphError::MissingCoordinates(_)
+            | GraphError::InvalidOpportunities(_)
+            | GraphError::InvalidPlaces(_)
+            | GraphError::InvalidRealtime(_) => Self::Unprocessable(message),
+            _ => Self::Gtfs(message),
+        }
+    }
+}
+
+///Deepest zoom level tiles are served at
+const MAX_ZOOM: u32 = 22;
+
+///Checks the tile exists, x and y being below 2^zoom
+fn validate_tile(zoom: u32, x: u32, y: u32) -> Result<(), Error> {
+    if zoom > MAX_ZOOM {
+        return Err(Error::InvalidTile(format!(
+            "zoom must be at most {}",
+            MAX_ZOOM
+        )));
+    }
+
+    let tiles = 1 << zoom;
+    if x >= tiles || y >= tiles {
+        return Err(Error::InvalidTile(format!(
+            "x and y must be below {} at zoom {}",
+            tiles, zoom
+        )));
     }
+
+    Ok(())
 }
+
 pub struct CORS;
 
+///Walking transfers are added between stops of different feeds closer than this in meters
+const DEFAULT_TRANSFER_DISTANCE: f64 = 200.0;
+
+///Largest accessibility grid computed unless max_accessibility_cells is configured
+const DEFAULT_MAX_ACCESSIBILITY_CELLS: usize = 100_000;
+
+///A feed from the feeds list in Rocket.toml, path is a zip or a directory
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+struct FeedConfig {
+    path: String,
+    namespace: Option<String>,
+}
+
+///Where the graph is built from, kept so it can be rebuilt on reload
+struct FeedSources {
+    feeds: Vec<FeedConfig>,
+    transfer_distance: f64,
+    ///Required in the X-Admin-Token header of admin endpoints, which are forbidden when unset
+    admin_token: Option<String>,
+    reloading: Arc<AtomicBool>,
+}
+
+///Graph currently being served. Reloading replaces the whole Arc.
+struct LoadedGraph(Arc<RwLock<Arc<GtfsGraph>>>);
+
+///Request guard for the graph. It keeps the graph alive for the whole request,
+///so requests started before a reload finish on the old graph.
+struct Graph {
+    graph: Arc<GtfsGraph>,
+    loaded: Arc<RwLock<Arc<GtfsGraph>>>,
+}
+
+impl Graph {
+    ///Runs store, which keeps results computed on this graph, unless a reload has replaced it.
+    ///Results point into the graph they were computed on, so the ones of requests that started
+    ///before a reload are dropped. The graph is held meanwhile, so a reload can't swap it
+    ///until the results are stored, and then clears them.
+    fn store(&self, store: impl FnOnce()) {
+        let current = self.loaded.read().unwrap();
+        if Arc::ptr_eq(&current, &self.graph) {
+            store();
+        }
+    }
+}
+
+impl Deref for Graph {
+    type Target = GtfsGraph;
+
+    fn deref(&self) -> &GtfsGraph {
+        &self.graph
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Graph {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        match request.rocket().state::<LoadedGraph>() {
+            Some(loaded) => Outcome::Success(Graph {
+                graph: loaded.0.read().unwrap().clone(),
+                loaded: loaded.0.clone(),
+            }),
+            None => Outcome::Error((Status::InternalServerError, ())),
+        }
+    }
+}
+
+///Guard for admin endpoints, checks X-Admin-Token against the configured admin_token.
+///Without an admin_token admin endpoints are forbidden.
+struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let Some(sources) = request.rocket().state::<FeedSources>() else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+
+        match &sources.admin_token {
+            None => Outcome::Error((Status::Forbidden, ())),
+            Some(token) if request.headers().get_one("X-Admin-Token") != Some(token) => {
+                Outcome::Error((Status::Unauthorized, ()))
+            }
+            Some(_) => Outcome::Success(Admin),
+        }
+    }
+}
+
+///Compiled scenarios by name
+struct Scenarios(Arc<RwLock<HashMap<String, ScenarioOverlay>>>);
+
+///Search results saved by name for later rendering.
+///Scenario searches are saved under the name given by scenario_search.
+struct SavedStopTimes(Arc<Mutex<HashMap<String, StopTimes>>>);
+
+///Prefix of the saved names of scenario searches, which save_as names can't start with
+const SCENARIO_SEARCH_PREFIX: &str = "scenario:";
+
+///Name the latest search of a scenario is saved under, like scenario:tram-15-every-5-min
+fn scenario_search(name: &str) -> String {
+    format!("{}{}", SCENARIO_SEARCH_PREFIX, name)
+}
+
+///Search results are shared behind an Arc, so tiles can be rendered without holding a lock
+type StopTimes = Arc<HashMap<String, StopWithDuration>>;
+
+///Result of the latest search, rendered by the tile endpoints
+type LatestStopTimes = Arc<Mutex<StopTimes>>;
+
+///Result of the latest fare search, one search per fare ordered from the cheapest
+struct LatestFareReaches(Arc<Mutex<Arc<Vec<FareReach>>>>);
+
+///Result of the latest typical week search, kept so it can be rendered with any statistic
+struct LatestTypicalWeek(Arc<Mutex<Option<TypicalWeekSearch>>>);
+
+///Typical week with its travel times reduced by each statistic rendered so far,
+///so tiles don't reduce the whole week again
+struct TypicalWeekSearch {
+    week: TypicalWeek,
+    aggregated: HashMap<Statistic, StopTimes>,
+}
+
+///Largest accessibility grid in cells, max_accessibility_cells in Rocket.toml
+struct MaxAccessibilityCells(usize);
+
+///Pre-rendered tiles from the tile_archive configured in Rocket.toml, if any.
+///Archives are made with the prerender command of gtfs-heatmap.
+struct TileArchive(Option<Mutex<MbTiles>>);
+
+#[derive(Responder)]
+#[response(status = 202, content_type = "text/plain")]
+struct Accepted(&'static str);
+
 #[derive(Responder)]
 #[response(status = 200, content_type = "image/png")]
 struct PngImage(Vec<u8>);
 
+#[derive(Responder)]
+#[response(status = 200, content_type = "image/tiff")]
+struct Tiff(Vec<u8>);
+
+#[derive(Responder)]
+#[response(status = 200, content_type = "application/vnd.mapbox-vector-tile")]
+struct VectorTile(Vec<u8>);
+
 #[derive(Responder)]
 #[response(status = 200, content_type = "application/json")]
 struct Json(String);
 
+///Last segment of a vector tile path, the y coordinate followed by .mvt
+struct MvtTileY(u32);
+
+impl<'a> FromParam<'a> for MvtTileY {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        param
+            .strip_suffix(".mvt")
+            .and_then(|y| y.parse().ok())
+            .map(MvtTileY)
+            .ok_or(param)
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 200, content_type = "text/csv")]
+struct Csv(String);
+
+///Parses a departure given as a unix timestamp, RFC 3339 or local time in the time zone of the feeds
+fn parse_departure(departure: &str, graph: &GtfsGraph) -> Result<OffsetDateTime, Error> {
+    departure
+        .parse::<Departure>()
+        .map(|departure| departure.resolve(graph.time_zone()))
+        .map_err(|err| Error::InvalidTimestamp(format!("{}: {}", departure, err)))
+}
+
+#[derive(Responder)]
+enum AccessibilityResponse {
+    Json(Json),
+    Csv(Csv),
+    Image(PngImage),
+}
+
+#[derive(FromForm)]
+struct AccessibilityQuery<'r> {
+    timestamp: &'r str,
+    min_latitude: f64,
+    min_longitude: f64,
+    max_latitude: f64,
+    max_longitude: f64,
+    ///Cell size in meters
+    #[field(default = 250.0)]
+    cell_size: f64,
+    #[field(default = 30)]
+    cutoff_minutes: i64,
+    ///step, linear, exponential or gaussian
+    #[field(default = "step")]
+    decay: &'r str,
+    ///Half life for exponential and standard deviation for gaussian decay
+    decay_minutes: Option<f64>,
+    ///Column or property to read opportunity weights from
+    #[field(default = "weight")]
+    weight: &'r str,
+    ///json, csv or webp
+    #[field(default = "json")]
+    format: &'r str,
+}
+
 #[rocket::async_trait]
 impl Fairing for CORS {
     fn info(&self) -> Info {
@@ -78,32 +458,380 @@ fn index() -> &'static str {
 }
 
 #[get("/api/stops")]
-async fn stops(gtfs_data: &State<GtfsGraph>) -> Result<Json, Error> {
+async fn stops(gtfs_data: Graph) -> Result<Json, Error> {
     let stop_times = gtfs_data.get_stops();
     Ok(Json(serde_json::to_string(&stop_times)?))
 }
 
-#[get("/api/stops/<stop_id>/dijkstras/<timestamp>")]
+///Stops whose name or code starts with q, ignoring case and accents and tolerating typos
+#[get("/api/stops/search?<q>&<limit>")]
+async fn search_stops(q: &str, limit: Option<usize>, gtfs_data: Graph) -> Result<Json, Error> {
+    let stops = gtfs_data.search_stops(q, limit.unwrap_or(20).min(100));
+    Ok(Json(serde_json::to_string(&stops)?))
+}
+
+#[get("/api/stops/within?<min_latitude>&<min_longitude>&<max_latitude>&<max_longitude>")]
+async fn stops_within(
+    min_latitude: f64,
+    min_longitude: f64,
+    max_latitude: f64,
+    max_longitude: f64,
+    gtfs_data: Graph,
+) -> Result<Json, Error> {
+    let stops = gtfs_data.stops_within(&BoundingBox {
+        min: Coordinates {
+            latitude: min_latitude,
+            longitude: min_longitude,
+        },
+        max: Coordinates {
+            latitude: max_latitude,
+            longitude: max_longitude,
+        },
+    });
+    Ok(Json(serde_json::to_string(&stops)?))
+}
+
+///Name, code, parent station and the routes serving a stop
+#[get("/api/stops/<stop_id>")]
+async fn stop_details(stop_id: &str, gtfs_data: Graph) -> Result<Json, Error> {
+    Ok(Json(serde_json::to_string(
+        &gtfs_data.stop_details(stop_id)?,
+    )?))
+}
+
+///Next departures from a stop on the dates their service runs, from now unless from is given
+#[get("/api/stops/<stop_id>/departures?<from>&<count>")]
+async fn departures(
+    stop_id: &str,
+    from: Option<&str>,
+    count: Option<usize>,
+    gtfs_data: Graph,
+) -> Result<Json, Error> {
+    let from = match from {
+        Some(from) => parse_departure(from, &gtfs_data)?,
+        None => OffsetDateTime::now_utc(),
+    };
+    let departures = gtfs_data.departures(stop_id, from, count.unwrap_or(10).min(100))?;
+    Ok(Json(serde_json::to_string(&departures)?))
+}
+
+#[get("/api/routes")]
+async fn routes(gtfs_data: Graph) -> Result<Json, Error> {
+    Ok(Json(serde_json::to_string(&gtfs_data.routes())?))
+}
+
+///Route with its trips and the distinct stop patterns they follow
+#[get("/api/routes/<route_id>")]
+async fn route_details(route_id: &str, gtfs_data: Graph) -> Result<Json, Error> {
+    Ok(Json(serde_json::to_string(
+        &gtfs_data.route_details(route_id)?,
+    )?))
+}
+
+///Trip with its stop times and its shape as a GeoJSON LineString
+#[get("/api/trips/<trip_id>")]
+async fn trip_details(trip_id: &str, gtfs_data: Graph) -> Result<Json, Error> {
+    Ok(Json(serde_json::to_string(
+        &gtfs_data.trip_details(trip_id)?,
+    )?))
+}
+
+///Issues found in the feeds while building the graph
+#[get("/api/validation")]
+async fn validation(gtfs_data: Graph) -> Result<Json, Error> {
+    Ok(Json(serde_json::to_string(gtfs_data.validation_report())?))
+}
+
+///With save_as the result is also kept under that name, so it can be compared in difference tiles.
+///Names starting with scenario: are reserved for scenario searches.
+#[get("/api/stops/<stop_id>/dijkstras/<departure>?<save_as>")]
 async fn dijkstras(
     stop_id: &str,
-    timestamp: i64,
-    gtfs_data: &State<GtfsGraph>,
-    stored_stop_times: &State<Mutex<HashMap<String, StopWithDuration>>>,
+    departure: &str,
+    save_as: Option<&str>,
+    gtfs_data: Graph,
+    stored_stop_times: &State<LatestStopTimes>,
+    saved_stop_times: &State<SavedStopTimes>,
+) -> Result<Json, Error> {
+    if save_as.is_some_and(|name| name.starts_with(SCENARIO_SEARCH_PREFIX)) {
+        return Err(Error::InvalidSaveName(format!(
+            "names starting with {} are reserved for scenario searches",
+            SCENARIO_SEARCH_PREFIX
+        )));
+    }
+
+    let start_time = parse_departure(departure, &gtfs_data)?;
+    let times = Arc::new(gtfs_data.dijkstras(stop_id, start_time)?);
+
+    let json = serde_json::to_string(&times)?;
+
+    gtfs_data.store(|| {
+        if let Some(name) = save_as {
+            saved_stop_times
+                .0
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), times.clone());
+        }
+        *stored_stop_times.lock().unwrap() = times;
+    });
+
+    Ok(Json(json))
+}
+
+///Multi criteria search returning the Pareto optimal travel time, transfers and walking distance
+///of every reached stop. Tiles render the lowest generalised cost, where transfer_penalty
+///is in minutes and walk_reluctance multiplies the time spent walking.
+#[get(
+    "/api/stops/<stop_id>/pareto/<departure>?<transfer_penalty>&<walk_reluctance>&<max_transfers>"
+)]
+async fn pareto(
+    stop_id: &str,
+    departure: &str,
+    transfer_penalty: Option<i64>,
+    walk_reluctance: Option<f64>,
+    max_transfers: Option<u32>,
+    gtfs_data: Graph,
+    stored_stop_times: &State<LatestStopTimes>,
 ) -> Result<Json, Error> {
-    let times = gtfs_data.dijkstras(
-        stop_id,
-        OffsetDateTime::from_unix_timestamp(timestamp).expect("lol"),
-    )?;
+    let start_time = parse_departure(departure, &gtfs_data)?;
 
-    let mut lock = stored_stop_times.lock().unwrap();
+    let mut options = ParetoOptions::default();
+    if let Some(max_transfers) = max_transfers {
+        options.max_transfers = max_transfers;
+    }
+
+    let sets = gtfs_data.pareto_search(stop_id, start_time, &options)?;
+    let weights = CostWeights {
+        transfer_penalty: Duration::minutes(transfer_penalty.unwrap_or(0)),
+        walk_reluctance: walk_reluctance.unwrap_or(1.0),
+    };
 
-    *lock = times.clone();
+    let times = Arc::new(gtfs_data.generalized_cost(&sets, &weights));
+    gtfs_data.store(|| *stored_stop_times.lock().unwrap() = times);
+
+    Ok(Json(serde_json::to_string(&sets)?))
+}
 
-    Ok(Json(serde_json::to_string(&times)?))
+#[get("/api/fares")]
+async fn fares(gtfs_data: Graph) -> Result<Json, Error> {
+    let fares: Vec<_> = gtfs_data.fare_attributes().collect();
+    Ok(Json(serde_json::to_string(&fares)?))
 }
 
+///Searches once per fare, returning the stops each fare reaches. With budget the fastest
+///times using fares costing at most budget become the latest search rendered by tiles.
+#[get("/api/stops/<stop_id>/fares/<departure>?<budget>")]
+async fn fare_search(
+    stop_id: &str,
+    departure: &str,
+    budget: Option<f64>,
+    gtfs_data: Graph,
+    stored_stop_times: &State<LatestStopTimes>,
+    fare_reaches: &State<LatestFareReaches>,
+) -> Result<Json, Error> {
+    let start_time = parse_departure(departure, &gtfs_data)?;
+
+    let reaches = gtfs_data.fare_searches(stop_id, start_time)?;
+
+    let json = serde_json::to_string(&reaches)?;
+
+    gtfs_data.store(|| {
+        if let Some(budget) = budget {
+            *stored_stop_times.lock().unwrap() =
+                Arc::new(reachable_within_budget(&reaches, budget));
+        }
+        *fare_reaches.0.lock().unwrap() = Arc::new(reaches);
+    });
+
+    Ok(Json(json))
+}
+
+///Price of the cheapest fare reaching each pixel within cutoff minutes in the latest fare search
+#[get("/api/tiles/fares/<zoom>/<x>/<y>/tile.webp?<cutoff>")]
+async fn fare_tiles(
+    zoom: u32,
+    x: u32,
+    y: u32,
+    cutoff: Option<i64>,
+    gtfs_graph: Graph,
+    fare_reaches: &State<LatestFareReaches>,
+) -> Result<PngImage, Error> {
+    validate_tile(zoom, x, y)?;
+    let reaches = fare_reaches.0.lock().unwrap().clone();
+
+    let tile = gtfs_graph.generate_fare_tile(
+        zoom,
+        x,
+        y,
+        &reaches,
+        Duration::minutes(cutoff.unwrap_or(60)),
+    );
+    encode_tile(tile)
+}
+
+///Searches at every departure of a typical week, returning the travel time to each stop reduced
+///with statistic, mean by default. The result becomes the latest search rendered by tiles.
+///days is a comma separated list like Mon,Tue and from and to are hours in the time zone
+///of the feeds, defaulting to every 10 minutes from 07 to 19 Monday to Friday.
+///week_of is any date of the week to sample like 2024-10-14, by default the current week.
+#[get("/api/stops/<stop_id>/typical_week?<days>&<from>&<to>&<step>&<week_of>&<statistic>")]
+#[allow(clippy::too_many_arguments)]
+async fn typical_week(
+    stop_id: &str,
+    days: Option<&str>,
+    from: Option<u8>,
+    to: Option<u8>,
+    step: Option<i64>,
+    week_of: Option<&str>,
+    statistic: Option<&str>,
+    gtfs_data: Graph,
+    stored_stop_times: &State<LatestStopTimes>,
+    latest_week: &State<LatestTypicalWeek>,
+) -> Result<Json, Error> {
+    let invalid = |message: String| Error::InvalidTypicalWeek(message);
+    let hour = |hour: u8| Time::from_hms(hour, 0, 0).map_err(|err| invalid(err.to_string()));
+
+    let week_of = match week_of {
+        Some(date) => Date::parse(date, &Iso8601::DATE).map_err(|err| invalid(err.to_string()))?,
+        None => gtfs_data
+            .time_zone()
+            .to_local(OffsetDateTime::now_utc())
+            .date(),
+    };
+    let statistic = statistic
+        .unwrap_or("mean")
+        .parse::<Statistic>()
+        .map_err(invalid)?;
+
+    let mut options = TypicalWeekOptions::weekdays(week_of);
+    if let Some(days) = days {
+        options.days = days
+            .split(',')
+            .map(|day| {
+                day.parse::<Day>()
+                    .map(Into::into)
+                    .map_err(|_| invalid(format!("Unknown day {}", day)))
+            })
+            .collect::<Result<_, Error>>()?;
+    }
+    if let Some(from) = from {
+        options.first_departure = hour(from)?;
+    }
+    if let Some(to) = to {
+        options.last_departure = hour(to)?;
+    }
+    if let Some(step) = step {
+        if step <= 0 {
+            return Err(invalid("step has to be at least one minute".to_string()));
+        }
+        options.step = Duration::minutes(step);
+    }
+
+    let week = gtfs_data.typical_week(stop_id, &options)?;
+    let times = Arc::new(gtfs_data.aggregate_typical_week(&week, statistic));
+
+    let json = serde_json::to_string(&times)?;
+
+    gtfs_data.store(|| {
+        *latest_week.0.lock().unwrap() = Some(TypicalWeekSearch {
+            week,
+            aggregated: HashMap::from([(statistic, times.clone())]),
+        });
+        *stored_stop_times.lock().unwrap() = times;
+    });
+
+    Ok(Json(json))
+}
+
+///Renders the latest typical week search reduced with statistic, like mean, median or p90
+#[get("/api/tiles/typical_week/<statistic>/<zoom>/<x>/<y>/tile.webp")]
+async fn typical_week_tiles(
+    statistic: &str,
+    zoom: u32,
+    x: u32,
+    y: u32,
+    gtfs_graph: Graph,
+    latest_week: &State<LatestTypicalWeek>,
+) -> Result<PngImage, Error> {
+    validate_tile(zoom, x, y)?;
+    let statistic = statistic
+        .parse::<Statistic>()
+        .map_err(Error::InvalidTypicalWeek)?;
+    let stop_times = {
+        let mut latest_week = latest_week.0.lock().unwrap();
+        let search = latest_week.as_mut().ok_or(Error::SearchNotFound(
+            "no typical week has been searched".to_string(),
+        ))?;
+
+        search
+            .aggregated
+            .entry(statistic)
+            .or_insert_with(|| Arc::new(gtfs_graph.aggregate_typical_week(&search.week, statistic)))
+            .clone()
+    };
+    encode_tile(heatmap_tile(&gtfs_graph, zoom, x, y, &stop_times)?)
+}
+
+///Service level independent of any origin, the departures per hour within walking distance
+///of each pixel during hours from departure. With weighted, departures of higher capacity
+///modes like rail count more. max is the departures per hour drawn black.
+#[get("/api/tiles/frequency/<zoom>/<x>/<y>/tile.webp?<departure>&<hours>&<weighted>&<max>")]
+#[allow(clippy::too_many_arguments)]
+async fn frequency_tiles(
+    zoom: u32,
+    x: u32,
+    y: u32,
+    departure: &str,
+    hours: Option<i64>,
+    weighted: Option<bool>,
+    max: Option<f64>,
+    gtfs_graph: Graph,
+) -> Result<PngImage, Error> {
+    validate_tile(zoom, x, y)?;
+    let mut options = FrequencyOptions::new(parse_departure(departure, &gtfs_graph)?);
+    options.window = Duration::hours(hours.unwrap_or(1).max(1));
+    if weighted.unwrap_or(false) {
+        options.route_type_weights = capacity_weights();
+    }
+    if let Some(max) = max.filter(|max| *max > 0.0) {
+        options.max_frequency = max;
+    }
+
+    let tile = gtfs_graph.generate_frequency_tile(zoom, x, y, &options);
+    encode_tile(tile)
+}
+
+///Itinerary to a stop in the latest search, or in the search saved under the name in saved
+#[get("/api/itinerary/<stop_id>?<saved>")]
+async fn itinerary(
+    stop_id: &str,
+    saved: Option<&str>,
+    gtfs_data: Graph,
+    stored_stop_times: &State<LatestStopTimes>,
+    saved_stop_times: &State<SavedStopTimes>,
+) -> Result<Json, Error> {
+    let times = match saved {
+        Some(name) => saved_stop_times
+            .0
+            .lock()
+            .unwrap()
+            .get(name)
+            .ok_or(Error::SearchNotFound(name.to_string()))?
+            .clone(),
+        None => stored_stop_times.lock().unwrap().clone(),
+    };
+
+    Ok(Json(serde_json::to_string(
+        &gtfs_data.itinerary(&times, stop_id)?,
+    )?))
+}
+
+///Heatmap of the latest search. Until a search is made the tiles of the tile_archive are served,
+///falling back to rendering the tiles missing from it.
 #[allow(unused_variables)]
-#[get("/api/tiles/<stop_id>/<hour>/<day>/<zoom>/<x>/<y>/tile.webp")]
+#[get("/api/tiles/<stop_id>/<hour>/<day>/<zoom>/<x>/<y>/tile.webp", rank = 2)]
+#[allow(clippy::too_many_arguments)]
 async fn tiles(
     stop_id: &str,
     hour: u32,
@@ -111,41 +839,594 @@ async fn tiles(
     zoom: u32,
     x: u32,
     y: u32,
-    gtfs_graph: &State<GtfsGraph>,
-    stop_time: &State<Mutex<HashMap<String, StopWithDuration>>>,
-) -> Option<PngImage> {
-    use image::ImageFormat::WebP;
+    gtfs_graph: Graph,
+    stop_time: &State<LatestStopTimes>,
+    tile_archive: &State<TileArchive>,
+) -> Result<PngImage, Error> {
+    validate_tile(zoom, x, y)?;
+    let stop_time = stop_time.lock().unwrap().clone();
+
+    if stop_time.is_empty() {
+        if let Some(tile) = archived_tile(tile_archive, zoom, x, y)? {
+            return Ok(tile);
+        }
+    }
+
+    encode_tile(heatmap_tile(&gtfs_graph, zoom, x, y, &stop_time)?)
+}
+
+///Renders the difference between two saved searches, see generate_difference_tile.
+#[get("/api/tiles/diff/<before>/<after>/<zoom>/<x>/<y>/tile.webp")]
+async fn difference_tiles(
+    before: &str,
+    after: &str,
+    zoom: u32,
+    x: u32,
+    y: u32,
+    gtfs_graph: Graph,
+    saved_stop_times: &State<SavedStopTimes>,
+) -> Result<PngImage, Error> {
+    validate_tile(zoom, x, y)?;
+    let (before, after) = {
+        let saved = saved_stop_times.0.lock().unwrap();
+        let get = |name: &str| {
+            saved
+                .get(name)
+                .cloned()
+                .ok_or(Error::SearchNotFound(name.to_string()))
+        };
+        (get(before)?, get(after)?)
+    };
+
+    let tile = gtfs_graph
+        .generate_difference_tile(zoom, x, y, &before, &after)
+        .ok_or(Error::SearchNotFound(
+            "a search reached no stops".to_string(),
+        ))?;
+    encode_tile(tile)
+}
+
+#[get("/api/tiles/prerendered/<zoom>/<x>/<y>/tile.webp")]
+async fn prerendered_tiles(
+    zoom: u32,
+    x: u32,
+    y: u32,
+    tile_archive: &State<TileArchive>,
+) -> Result<Option<PngImage>, Error> {
+    validate_tile(zoom, x, y)?;
+    archived_tile(tile_archive, zoom, x, y)
+}
+
+///Tile from the tile_archive, None without an archive or if the archive doesn't have it
+fn archived_tile(
+    tile_archive: &TileArchive,
+    zoom: u32,
+    x: u32,
+    y: u32,
+) -> Result<Option<PngImage>, Error> {
+    let Some(archive) = &tile_archive.0 else {
+        return Ok(None);
+    };
+
+    Ok(archive
+        .lock()
+        .unwrap()
+        .get_tile(&TileNumbers { zoom, x, y })?
+        .map(PngImage))
+}
+
+///Reached stops and isochrones of the latest search as a vector tile.
+///bands is a comma separated list of isochrone limits in minutes.
+#[get("/api/vtiles/<zoom>/<x>/<y>?<bands>")]
+async fn vector_tiles(
+    zoom: u32,
+    x: u32,
+    y: MvtTileY,
+    bands: Option<&str>,
+    gtfs_graph: Graph,
+    stop_time: &State<LatestStopTimes>,
+) -> Result<VectorTile, Error> {
+    validate_tile(zoom, x, y.0)?;
+    let bands = bands
+        .unwrap_or("15,30,45,60")
+        .split(',')
+        .map(|minutes| minutes.trim().parse().map(Duration::minutes))
+        .collect::<Result<Vec<Duration>, _>>()
+        .map_err(|_| {
+            Error::InvalidVectorTileRequest("bands must be a list of minutes".to_string())
+        })?;
+
+    let stop_times = stop_time.lock().unwrap().clone();
+
+    Ok(VectorTile(gtfs_graph.generate_vector_tile(
+        zoom,
+        x,
+        y.0,
+        &stop_times,
+        &bands,
+    )))
+}
+
+///Heatmap tile of stop_times, which are empty when no search has been made
+fn heatmap_tile(
+    gtfs_graph: &GtfsGraph,
+    zoom: u32,
+    x: u32,
+    y: u32,
+    stop_times: &HashMap<String, StopWithDuration>,
+) -> Result<GrayImage, Error> {
+    let (tile, _) = gtfs_graph
+        .generate_heatmap_tile(zoom, x, y, stop_times)
+        .ok_or(Error::SearchNotFound(
+            "no search has reached any stops".to_string(),
+        ))?;
+    Ok(tile)
+}
+
+fn encode_tile(tile: impl Into<DynamicImage>) -> Result<PngImage, Error> {
+    Ok(PngImage(
+        encode_webp(tile).map_err(gtfs_heatmap_lib::Error::from)?,
+    ))
+}
+
+#[derive(FromForm)]
+struct RasterQuery {
+    min_latitude: f64,
+    min_longitude: f64,
+    max_latitude: f64,
+    max_longitude: f64,
+    #[field(default = 13)]
+    zoom: u32,
+    #[field(default = 3857)]
+    epsg: u16,
+}
+
+///Travel times of the latest search over an area as a float32 GeoTIFF in seconds
+#[get("/api/raster.tif?<query..>")]
+async fn raster(
+    query: RasterQuery,
+    gtfs_graph: Graph,
+    stop_time: &State<LatestStopTimes>,
+) -> Result<Tiff, Error> {
+    let crs = Crs::from_epsg(query.epsg).ok_or(Error::InvalidRasterRequest(
+        "epsg must be 3857 or 4326".to_string(),
+    ))?;
+
+    if query.zoom > 18 {
+        return Err(Error::InvalidRasterRequest(
+            "zoom must be at most 18".to_string(),
+        ));
+    }
+
+    let stop_times = stop_time.lock().unwrap().clone();
+
+    let raster = gtfs_graph.travel_time_raster(
+        &BoundingBox {
+            min: Coordinates {
+                latitude: query.min_latitude,
+                longitude: query.min_longitude,
+            },
+            max: Coordinates {
+                latitude: query.max_latitude,
+                longitude: query.max_longitude,
+            },
+        },
+        crs,
+        query.zoom,
+        &stop_times,
+    );
+
+    Ok(Tiff(raster.to_geotiff()?))
+}
+
+#[get("/api/scenarios")]
+async fn list_scenarios(scenarios: &State<Scenarios>) -> Result<Json, Error> {
+    let scenarios = scenarios.0.read().unwrap();
+    let names: Vec<&String> = scenarios.keys().collect();
+    Ok(Json(serde_json::to_string(&names)?))
+}
+
+///Compiles the scenario in the request body and stores it under its name,
+///replacing any earlier scenario with the same name.
+#[post("/api/scenarios", data = "<body>")]
+async fn create_scenario(
+    body: &str,
+    gtfs_data: Graph,
+    scenarios: &State<Scenarios>,
+    saved_stop_times: &State<SavedStopTimes>,
+) -> Result<Json, Error> {
+    let scenario: Scenario =
+        serde_json::from_str(body).map_err(|err| Error::InvalidScenario(err.to_string()))?;
+
+    let overlay = gtfs_data
+        .build_scenario(&scenario)
+        .map_err(|err| Error::InvalidScenario(err.to_string()))?;
+
+    gtfs_data.store(|| {
+        saved_stop_times
+            .0
+            .lock()
+            .unwrap()
+            .remove(&scenario_search(&scenario.name));
+        scenarios
+            .0
+            .write()
+            .unwrap()
+            .insert(scenario.name.clone(), overlay);
+    });
+
+    Ok(Json(serde_json::to_string(&scenario.name)?))
+}
+
+///Applies a GTFS-Realtime TripUpdates feed, sent as protobuf, to the timetable.
+///The result is stored as the scenario "realtime", so it is searched and rendered
+///through the scenario endpoints. Namespace is the one of the feed the updates are for.
+#[post("/api/realtime?<namespace>", data = "<body>")]
+async fn realtime(
+    namespace: Option<&str>,
+    body: Data<'_>,
+    gtfs_data: Graph,
+    scenarios: &State<Scenarios>,
+    saved_stop_times: &State<SavedStopTimes>,
+) -> Result<Json, Error> {
+    let body = body
+        .open(32.mebibytes())
+        .into_bytes()
+        .await
+        .map_err(|err| Error::InvalidRealtime(err.to_string()))?;
+
+    let feed = FeedMessage::decode(&body).map_err(|err| Error::InvalidRealtime(err.to_string()))?;
+    let overlay = gtfs_data.realtime_overlay(&feed, namespace)?;
+
+    gtfs_data.store(|| {
+        saved_stop_times
+            .0
+            .lock()
+            .unwrap()
+            .remove(&scenario_search(REALTIME_OVERLAY));
+        scenarios
+            .0
+            .write()
+            .unwrap()
+            .insert(REALTIME_OVERLAY.to_string(), overlay);
+    });
+
+    Ok(Json(serde_json::to_string(&feed.trip_updates.len())?))
+}
+
+#[delete("/api/scenarios/<name>")]
+async fn delete_scenario(
+    name: &str,
+    scenarios: &State<Scenarios>,
+    saved_stop_times: &State<SavedStopTimes>,
+) -> Result<Json, Error> {
+    saved_stop_times
+        .0
+        .lock()
+        .unwrap()
+        .remove(&scenario_search(name));
+    scenarios
+        .0
+        .write()
+        .unwrap()
+        .remove(name)
+        .ok_or(Error::ScenarioNotFound(name.to_string()))?;
+
+    Ok(Json(serde_json::to_string(name)?))
+}
+
+///The result is saved as scenario:<name>, so it can be compared with saved searches in difference tiles
+#[get("/api/scenarios/<name>/stops/<stop_id>/dijkstras/<departure>")]
+async fn scenario_dijkstras(
+    name: &str,
+    stop_id: &str,
+    departure: &str,
+    gtfs_data: Graph,
+    scenarios: &State<Scenarios>,
+    saved_stop_times: &State<SavedStopTimes>,
+) -> Result<Json, Error> {
+    let start_time = parse_departure(departure, &gtfs_data)?;
+    let times = {
+        let scenarios = scenarios.0.read().unwrap();
+        let scenario = scenarios
+            .get(name)
+            .ok_or(Error::ScenarioNotFound(name.to_string()))?;
+
+        gtfs_data.dijkstras_with_scenario(stop_id, start_time, Some(scenario))?
+    };
+
+    let json = serde_json::to_string(&times)?;
+
+    gtfs_data.store(|| {
+        saved_stop_times
+            .0
+            .lock()
+            .unwrap()
+            .insert(scenario_search(name), Arc::new(times));
+    });
+
+    Ok(Json(json))
+}
+
+#[allow(unused_variables)]
+#[get("/api/scenarios/<name>/tiles/<stop_id>/<hour>/<day>/<zoom>/<x>/<y>/tile.webp")]
+#[allow(clippy::too_many_arguments)]
+async fn scenario_tiles(
+    name: &str,
+    stop_id: &str,
+    hour: u32,
+    day: &str,
+    zoom: u32,
+    x: u32,
+    y: u32,
+    gtfs_graph: Graph,
+    saved_stop_times: &State<SavedStopTimes>,
+) -> Result<PngImage, Error> {
+    validate_tile(zoom, x, y)?;
+    let stop_times = saved_stop_times
+        .0
+        .lock()
+        .unwrap()
+        .get(&scenario_search(name))
+        .ok_or(Error::SearchNotFound(name.to_string()))?
+        .clone();
+
+    encode_tile(heatmap_tile(&gtfs_graph, zoom, x, y, &stop_times)?)
+}
+
+///Counts opportunities reachable from each cell of a grid.
+///The body is a csv or GeoJSON point dataset, see gtfs_graph::accessibility.
+#[post("/api/accessibility?<query..>", data = "<body>")]
+async fn accessibility(
+    query: AccessibilityQuery<'_>,
+    body: Data<'_>,
+    gtfs_data: Graph,
+    max_cells: &State<MaxAccessibilityCells>,
+) -> Result<AccessibilityResponse, Error> {
+    let invalid = |message: &str| Error::InvalidAccessibilityRequest(message.to_string());
+
+    let body = body
+        .open(64.mebibytes())
+        .into_string()
+        .await
+        .map_err(|err| invalid(&err.to_string()))?;
 
-    let guard: std::sync::MutexGuard<'_, HashMap<String, StopWithDuration>> =
-        stop_time.lock().ok()?;
+    let opportunities =
+        parse_opportunities(&body, query.weight).map_err(|err| invalid(&err.to_string()))?;
 
-    let (tile, time) = gtfs_graph.generate_heatmap_tile(zoom, x, y, &guard);
-    let mut writer = Cursor::new(Vec::new());
-    tile.write_to(&mut writer, WebP)
-        .inspect_err(|err| println!("Error: {}", err))
-        .ok()?;
-    println!("{}\n", time);
-    Some(PngImage(writer.into_inner()))
+    let decay_duration = || {
+        query
+            .decay_minutes
+            .map(|minutes| Duration::seconds_f64(minutes * 60.0))
+            .ok_or(invalid("decay_minutes is required for this decay function"))
+    };
+
+    let decay = match query.decay {
+        "step" => DecayFunction::Step,
+        "linear" => DecayFunction::Linear,
+        "exponential" => DecayFunction::Exponential {
+            half_life: decay_duration()?,
+        },
+        "gaussian" => DecayFunction::Gaussian {
+            sigma: decay_duration()?,
+        },
+        _ => return Err(invalid("unknown decay function")),
+    };
+
+    if query.cell_size <= 0.0 {
+        return Err(invalid("cell_size must be positive"));
+    }
+
+    if query.cutoff_minutes <= 0 {
+        return Err(invalid("cutoff_minutes must be positive"));
+    }
+
+    let options = AccessibilityOptions {
+        bounding_box: BoundingBox {
+            min: Coordinates {
+                latitude: query.min_latitude,
+                longitude: query.min_longitude,
+            },
+            max: Coordinates {
+                latitude: query.max_latitude,
+                longitude: query.max_longitude,
+            },
+        },
+        cell_size: query.cell_size,
+        start_time: parse_departure(query.timestamp, &gtfs_data)?,
+        cutoff: Duration::minutes(query.cutoff_minutes),
+        decay,
+        max_cells: max_cells.0,
+    };
+
+    let grid = gtfs_data.accessibility(&opportunities, &options)?;
+
+    Ok(match query.format {
+        "csv" => AccessibilityResponse::Csv(Csv(grid.to_csv())),
+        "webp" => AccessibilityResponse::Image(encode_tile(grid.to_image())?),
+        _ => AccessibilityResponse::Json(Json(serde_json::to_string(&grid)?)),
+    })
+}
+
+///Rebuilds the graph from the configured feeds in the background and swaps it in when done.
+///Search results and scenarios point into the old graph, so they are cleared on swap,
+///and searches still running on the old graph drop theirs, see Graph::store.
+#[post("/api/admin/reload")]
+#[allow(clippy::too_many_arguments)]
+async fn reload(
+    _admin: Admin,
+    sources: &State<FeedSources>,
+    graph: &State<LoadedGraph>,
+    latest_stop_times: &State<LatestStopTimes>,
+    saved_stop_times: &State<SavedStopTimes>,
+    fare_reaches: &State<LatestFareReaches>,
+    typical_week: &State<LatestTypicalWeek>,
+    scenarios: &State<Scenarios>,
+) -> Result<Accepted, Error> {
+    if sources.reloading.swap(true, Ordering::SeqCst) {
+        return Err(Error::ReloadInProgress(
+            "feeds are already being reloaded".to_string(),
+        ));
+    }
+
+    let feeds = sources.feeds.clone();
+    let transfer_distance = sources.transfer_distance;
+    let reloading = sources.reloading.clone();
+    let graph = graph.0.clone();
+    let latest_stop_times = latest_stop_times.inner().clone();
+    let saved_stop_times = saved_stop_times.0.clone();
+    let fare_reaches = fare_reaches.0.clone();
+    let typical_week = typical_week.0.clone();
+    let scenarios = scenarios.0.clone();
+
+    rocket::tokio::spawn(async move {
+        let built =
+            rocket::tokio::task::spawn_blocking(move || build_graph(&feeds, transfer_distance))
+                .await;
+
+        match built {
+            Ok(Ok(new_graph)) => {
+                *graph.write().unwrap() = Arc::new(new_graph);
+                *latest_stop_times.lock().unwrap() = Arc::new(HashMap::new());
+                saved_stop_times.lock().unwrap().clear();
+                *fare_reaches.lock().unwrap() = Arc::new(Vec::new());
+                *typical_week.lock().unwrap() = None;
+                scenarios.write().unwrap().clear();
+                info!("Reloaded GTFS feeds");
+            }
+            Ok(Err(err)) => error!("Reloading GTFS feeds failed: {}", err),
+            Err(err) => error!("Reloading GTFS feeds panicked: {}", err),
+        }
+
+        reloading.store(false, Ordering::SeqCst);
+    });
+
+    Ok(Accepted("reload started"))
+}
+
+fn build_graph(feeds: &[FeedConfig], transfer_distance: f64) -> Result<GtfsGraph, String> {
+    let feeds = feeds
+        .iter()
+        .map(|feed| {
+            Ok(Feed {
+                gtfs: Gtfs::from_path(&feed.path)
+                    .map_err(|err| format!("GTFS feed {} is not readable: {}", feed.path, err))?,
+                namespace: feed.namespace.clone(),
+            })
+        })
+        .collect::<Result<Vec<Feed>, String>>()?;
+
+    GtfsGraph::from_feeds(feeds, transfer_distance).map_err(|err| err.to_string())
 }
 
 /*
 #[get("/api/graph")]
-fn get_graph(gtfs_data: &State<GtfsGraph>) -> Result<Json, Error> {
+fn get_graph(gtfs_data: Graph) -> Result<Json, Error> {
     Ok(Json(gtfs_data.serialize(Json)?))
 }
 */
 #[launch]
 fn rocket() -> _ {
-    let gtfs_data: GtfsGraph = Gtfs::from_path("../data/")
-        .expect("GTFS data should exsist in \"data/\" folder")
-        .try_into()
-        .expect("Should just work??");
+    let rocket = rocket::build();
+
+    let feeds = rocket
+        .figment()
+        .extract_inner::<Vec<FeedConfig>>("feeds")
+        .unwrap_or_else(|_| {
+            vec![FeedConfig {
+                path: "../data/".to_string(),
+                namespace: None,
+            }]
+        });
+    let transfer_distance = rocket
+        .figment()
+        .extract_inner::<f64>("transfer_distance")
+        .unwrap_or(DEFAULT_TRANSFER_DISTANCE);
+    let admin_token = rocket.figment().extract_inner::<String>("admin_token").ok();
+
+    let gtfs_data = build_graph(&feeds, transfer_distance).expect("Should just work??");
+
+    let tile_archive = rocket
+        .figment()
+        .extract_inner::<String>("tile_archive")
+        .ok()
+        .map(|path| Mutex::new(MbTiles::open(path).expect("Tile archive should be readable")));
+
+    server(
+        rocket,
+        gtfs_data,
+        FeedSources {
+            feeds,
+            transfer_distance,
+            admin_token,
+            reloading: Arc::new(AtomicBool::new(false)),
+        },
+        tile_archive,
+    )
+}
 
-    let stop_times: Mutex<HashMap<String, StopWithDuration>> = Mutex::new(HashMap::new());
+///Mounts the routes serving gtfs_data, split from rocket so tests can serve a graph of their own
+fn server(
+    rocket: Rocket<Build>,
+    gtfs_data: GtfsGraph,
+    sources: FeedSources,
+    tile_archive: Option<Mutex<MbTiles>>,
+) -> Rocket<Build> {
+    let stop_times: LatestStopTimes = Arc::new(Mutex::new(Arc::new(HashMap::new())));
+    let max_accessibility_cells = rocket
+        .figment()
+        .extract_inner::<usize>("max_accessibility_cells")
+        .unwrap_or(DEFAULT_MAX_ACCESSIBILITY_CELLS);
 
-    rocket::build()
+    rocket
         .attach(CORS)
-        .manage(gtfs_data)
+        .register("/", catchers![default_catcher])
+        .manage(LoadedGraph(Arc::new(RwLock::new(Arc::new(gtfs_data)))))
+        .manage(sources)
         .manage(stop_times)
-        .mount("/", routes![index, stops, tiles, dijkstras])
+        .manage(Scenarios(Arc::new(RwLock::new(HashMap::new()))))
+        .manage(SavedStopTimes(Arc::new(Mutex::new(HashMap::new()))))
+        .manage(LatestFareReaches(Arc::new(Mutex::new(
+            Arc::new(Vec::new()),
+        ))))
+        .manage(LatestTypicalWeek(Arc::new(Mutex::new(None))))
+        .manage(TileArchive(tile_archive))
+        .manage(MaxAccessibilityCells(max_accessibility_cells))
+        .mount(
+            "/",
+            routes![
+                index,
+                stops,
+                search_stops,
+                stops_within,
+                stop_details,
+                departures,
+                routes,
+                route_details,
+                trip_details,
+                validation,
+                tiles,
+                difference_tiles,
+                prerendered_tiles,
+                raster,
+                vector_tiles,
+                dijkstras,
+                pareto,
+                fares,
+                fare_search,
+                fare_tiles,
+                typical_week,
+                typical_week_tiles,
+                frequency_tiles,
+                itinerary,
+                accessibility,
+                list_scenarios,
+                create_scenario,
+                realtime,
+                delete_scenario,
+                scenario_dijkstras,
+                scenario_tiles,
+                reload
+            ],
+        )
 }
@@ -0,0 +1,408 @@
+use std::path::Path;
+use std::thread;
+use std::time::Duration as StdDuration;
+
+use gtfs_heatmap_lib::gtfs_graph::TripStop;
+use rocket::http::ContentType;
+use rocket::local::blocking::{Client, LocalResponse};
+
+use super::*;
+
+///Stops A and B, trip t1 of route r1 leaving A at 08:00 and reaching B at 08:10 every day
+fn test_graph() -> GtfsGraph {
+    let mut graph = GtfsGraph::new();
+
+    for (id, latitude) in [("A", 60.0), ("B", 60.1)] {
+        graph
+            .insert_stop(gtfs_structures::Stop {
+                id: id.to_string(),
+                latitude: Some(latitude),
+                longitude: Some(25.0),
+                ..Default::default()
+            })
+            .unwrap();
+    }
+
+    graph
+        .connect_stops(
+            "A",
+            8 * 3600,
+            "B",
+            8 * 3600 + 600,
+            [true; 7],
+            TripStop {
+                trip_id: &"t1".into(),
+                route_id: &"r1".into(),
+                stop_sequence: 1,
+            },
+        )
+        .unwrap();
+
+    graph
+}
+
+fn test_client(graph: GtfsGraph, feeds: Vec<FeedConfig>, admin_token: Option<&str>) -> Client {
+    let sources = FeedSources {
+        feeds,
+        transfer_distance: DEFAULT_TRANSFER_DISTANCE,
+        admin_token: admin_token.map(str::to_string),
+        reloading: Arc::new(AtomicBool::new(false)),
+    };
+
+    Client::tracked(server(rocket::build(), graph, sources, None)).unwrap()
+}
+
+///Feed with stops X and Y and trip t2 from X at 09:00 to Y at 09:10 during 2024
+fn write_test_feed(dir: &Path) {
+    std::fs::create_dir_all(dir).unwrap();
+
+    for (file, contents) in [
+        (
+            "agency.txt",
+            "agency_id,agency_name,agency_url,agency_timezone\nhsl,HSL,https://hsl.fi,UTC\n",
+        ),
+        (
+            "stops.txt",
+            "stop_id,stop_name,stop_lat,stop_lon\nX,Ex,60.0,25.0\nY,Why,60.1,25.0\n",
+        ),
+        (
+            "routes.txt",
+            "route_id,agency_id,route_short_name,route_type\nr2,hsl,2,3\n",
+        ),
+        ("trips.txt", "route_id,service_id,trip_id\nr2,daily,t2\n"),
+        (
+            "stop_times.txt",
+            "trip_id,arrival_time,departure_time,stop_id,stop_sequence\nt2,09:00:00,09:00:00,X,1\nt2,09:10:00,09:10:00,Y,2\n",
+        ),
+        (
+            "calendar.txt",
+            "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\ndaily,1,1,1,1,1,1,1,20240101,20241231\n",
+        ),
+    ] {
+        std::fs::write(dir.join(file), contents).unwrap();
+    }
+}
+
+///Status and the code of the JSON error body
+fn error(response: LocalResponse) -> (Status, String) {
+    let status = response.status();
+    let body: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+
+    (status, body["error"]["code"].as_str().unwrap().to_string())
+}
+
+#[test]
+fn tiles_outside_the_zoom_level_are_rejected() {
+    let client = test_client(test_graph(), Vec::new(), None);
+    let invalid_tile = (Status::BadRequest, "invalid_tile".to_string());
+
+    assert_eq!(
+        client.get("/api/vtiles/22/0/0.mvt").dispatch().status(),
+        Status::Ok
+    );
+    assert_eq!(
+        client.get("/api/vtiles/2/3/3.mvt").dispatch().status(),
+        Status::Ok
+    );
+    assert_eq!(
+        error(client.get("/api/vtiles/23/0/0.mvt").dispatch()),
+        invalid_tile
+    );
+    assert_eq!(
+        error(client.get("/api/vtiles/2/4/0.mvt").dispatch()),
+        invalid_tile
+    );
+    assert_eq!(
+        error(client.get("/api/vtiles/2/0/4.mvt").dispatch()),
+        invalid_tile
+    );
+    assert_eq!(
+        error(
+            client
+                .get("/api/tiles/A/8/monday/23/0/0/tile.webp")
+                .dispatch()
+        ),
+        invalid_tile
+    );
+}
+
+#[test]
+fn errors_have_a_status_and_a_json_code() {
+    let client = test_client(test_graph(), Vec::new(), None);
+
+    assert_eq!(
+        client
+            .get("/api/stops/A/dijkstras/2024-10-18T07:55")
+            .dispatch()
+            .status(),
+        Status::Ok
+    );
+    assert_eq!(
+        error(client.get("/api/stops/A/dijkstras/yesterday").dispatch()),
+        (Status::BadRequest, "invalid_timestamp".to_string())
+    );
+    assert_eq!(
+        error(client.get("/api/stops/C").dispatch()),
+        (Status::NotFound, "stop_not_found".to_string())
+    );
+    assert_eq!(
+        error(client.get("/api/itinerary/B?saved=missing").dispatch()),
+        (Status::NotFound, "search_not_found".to_string())
+    );
+    //Nothing leaves B, so A is never reached
+    client
+        .get("/api/stops/B/dijkstras/2024-10-18T07:55")
+        .dispatch();
+    assert_eq!(
+        error(client.get("/api/itinerary/A").dispatch()),
+        (Status::UnprocessableEntity, "unprocessable".to_string())
+    );
+    assert_eq!(
+        error(
+            client
+                .post("/api/scenarios")
+                .header(ContentType::JSON)
+                .body("{\"name\": ")
+                .dispatch()
+        ),
+        (Status::UnprocessableEntity, "invalid_scenario".to_string())
+    );
+    assert_eq!(
+        error(
+            client
+                .post(
+                    "/api/accessibility?timestamp=2024-10-18T07:55&min_latitude=0&min_longitude=0\
+                     &max_latitude=80&max_longitude=170"
+                )
+                .body("lat,lon\n60.1,25.0\n")
+                .dispatch()
+        ),
+        (
+            Status::BadRequest,
+            "invalid_accessibility_request".to_string()
+        )
+    );
+
+    //Errors raised by Rocket go through the default catcher
+    assert_eq!(
+        error(client.get("/api/unknown").dispatch()),
+        (Status::NotFound, "not_found".to_string())
+    );
+    assert_eq!(
+        error(client.get("/api/vtiles/high/0/0.mvt").dispatch()),
+        (
+            Status::UnprocessableEntity,
+            "unprocessable_entity".to_string()
+        )
+    );
+}
+
+#[test]
+fn reload_swaps_in_the_rebuilt_graph() {
+    let dir = std::env::temp_dir().join(format!("gtfs-heatmap-reload-test-{}", std::process::id()));
+    write_test_feed(&dir);
+    let feeds = vec![FeedConfig {
+        path: dir.to_str().unwrap().to_string(),
+        namespace: None,
+    }];
+    let client = test_client(test_graph(), feeds, Some("secret"));
+
+    client
+        .get("/api/stops/A/dijkstras/2024-10-18T07:55?save_as=before")
+        .dispatch();
+
+    assert_eq!(
+        error(client.post("/api/admin/reload").dispatch()),
+        (Status::Unauthorized, "unauthorized".to_string())
+    );
+    let reload = client
+        .post("/api/admin/reload")
+        .header(Header::new("X-Admin-Token", "secret"))
+        .dispatch();
+    assert_eq!(reload.status(), Status::Accepted);
+
+    //The graph is rebuilt in the background, the old one is served until it is ready
+    let mut attempts = 0;
+    while client.get("/api/stops/X").dispatch().status() != Status::Ok {
+        attempts += 1;
+        assert!(attempts < 100, "reload did not finish");
+        thread::sleep(StdDuration::from_millis(50));
+    }
+
+    assert_eq!(
+        error(client.get("/api/stops/A").dispatch()),
+        (Status::NotFound, "stop_not_found".to_string())
+    );
+    assert_eq!(
+        error(client.get("/api/itinerary/B?saved=before").dispatch()),
+        (Status::NotFound, "search_not_found".to_string())
+    );
+    assert_eq!(
+        client
+            .get("/api/stops/X/dijkstras/2024-10-18T08:55")
+            .dispatch()
+            .status(),
+        Status::Ok
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn searches_on_a_replaced_graph_are_not_stored() {
+    let old = Arc::new(test_graph());
+    let loaded = Arc::new(RwLock::new(old.clone()));
+    let graph = Graph {
+        graph: old,
+        loaded: loaded.clone(),
+    };
+
+    let mut stored = 0;
+    graph.store(|| stored += 1);
+    *loaded.write().unwrap() = Arc::new(test_graph());
+    graph.store(|| stored += 1);
+
+    assert_eq!(stored, 1);
+}
+
+#[test]
+fn reload_is_forbidden_without_an_admin_token() {
+    let client = test_client(test_graph(), Vec::new(), None);
+
+    assert_eq!(
+        error(client.post("/api/admin/reload").dispatch()),
+        (Status::Forbidden, "forbidden".to_string())
+    );
+}
+
+#[test]
+fn tiles_are_not_found_before_a_search() {
+    let client = test_client(test_graph(), Vec::new(), None);
+    let tile = "/api/tiles/A/8/monday/10/583/294/tile.webp";
+
+    assert_eq!(
+        error(client.get(tile).dispatch()),
+        (Status::NotFound, "search_not_found".to_string())
+    );
+
+    client
+        .get("/api/stops/A/dijkstras/2024-10-18T07:55")
+        .dispatch();
+    assert_eq!(client.get(tile).dispatch().status(), Status::Ok);
+}
+
+#[test]
+fn tiles_come_from_the_archive_until_a_search() {
+    let path = std::env::temp_dir().join(format!(
+        "gtfs-heatmap-backend-test-{}.mbtiles",
+        std::process::id()
+    ));
+    let bounding_box = BoundingBox {
+        min: Coordinates {
+            latitude: 60.0,
+            longitude: 25.0,
+        },
+        max: Coordinates {
+            latitude: 60.1,
+            longitude: 25.0,
+        },
+    };
+    let archived = TileNumbers {
+        zoom: 10,
+        x: 583,
+        y: 294,
+    };
+    MbTiles::create(&path, "test", "test", &bounding_box, 10, 10)
+        .unwrap()
+        .insert_tile(&archived, b"archived")
+        .unwrap();
+
+    let sources = FeedSources {
+        feeds: Vec::new(),
+        transfer_distance: DEFAULT_TRANSFER_DISTANCE,
+        admin_token: None,
+        reloading: Arc::new(AtomicBool::new(false)),
+    };
+    let archive = Mutex::new(MbTiles::open(&path).unwrap());
+    let client = Client::tracked(server(
+        rocket::build(),
+        test_graph(),
+        sources,
+        Some(archive),
+    ))
+    .unwrap();
+    let tile = |x| {
+        client
+            .get(format!("/api/tiles/A/8/monday/10/{}/294/tile.webp", x))
+            .dispatch()
+    };
+
+    assert_eq!(tile(583).into_bytes().unwrap(), b"archived");
+    //Missing from the archive, and nothing to render before a search
+    assert_eq!(
+        error(tile(584)),
+        (Status::NotFound, "search_not_found".to_string())
+    );
+
+    client
+        .get("/api/stops/A/dijkstras/2024-10-18T07:55")
+        .dispatch();
+    let rendered = tile(583);
+    assert_eq!(rendered.status(), Status::Ok);
+    assert_ne!(rendered.into_bytes().unwrap(), b"archived");
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn scenario_searches_do_not_collide_with_saved_searches() {
+    let client = test_client(test_graph(), Vec::new(), None);
+
+    let created = client
+        .post("/api/scenarios")
+        .header(ContentType::JSON)
+        .body(r#"{ "name": "no-r1", "removed_routes": ["r1"] }"#)
+        .dispatch();
+    assert_eq!(created.status(), Status::Ok);
+    client
+        .get("/api/stops/A/dijkstras/2024-10-18T07:55?save_as=no-r1")
+        .dispatch();
+    client
+        .get("/api/scenarios/no-r1/stops/A/dijkstras/2024-10-18T07:55")
+        .dispatch();
+
+    //The search saved as no-r1 reaches B, the scenario search doesn't
+    assert_eq!(
+        client
+            .get("/api/itinerary/B?saved=no-r1")
+            .dispatch()
+            .status(),
+        Status::Ok
+    );
+    assert_eq!(
+        error(
+            client
+                .get("/api/itinerary/B?saved=scenario:no-r1")
+                .dispatch()
+        ),
+        (Status::UnprocessableEntity, "unprocessable".to_string())
+    );
+    assert_eq!(
+        error(
+            client
+                .get("/api/stops/A/dijkstras/2024-10-18T07:55?save_as=scenario:x")
+                .dispatch()
+        ),
+        (Status::BadRequest, "invalid_save_name".to_string())
+    );
+    assert_eq!(
+        error(
+            client
+                .post("/api/scenarios")
+                .header(ContentType::JSON)
+                .body(r#"{ "name": "realtime" }"#)
+                .dispatch()
+        ),
+        (Status::UnprocessableEntity, "invalid_scenario".to_string())
+    );
+}